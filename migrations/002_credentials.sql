@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS credentials (
+    repository_id INTEGER PRIMARY KEY REFERENCES repositories(id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    secret BLOB NOT NULL,
+    updated_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
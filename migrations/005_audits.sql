@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS audits (
+    repository_id INTEGER PRIMARY KEY REFERENCES repositories(id) ON DELETE CASCADE,
+    ok BOOLEAN NOT NULL,
+    report TEXT NOT NULL,
+    audited_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
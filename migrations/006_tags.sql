@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS repository_tags (
+    repository_id INTEGER NOT NULL REFERENCES repositories(id) ON DELETE CASCADE,
+    tag TEXT NOT NULL,
+    PRIMARY KEY (repository_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS idx_repository_tags_tag ON repository_tags(tag);
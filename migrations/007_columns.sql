@@ -0,0 +1,12 @@
+ALTER TABLE repositories ADD COLUMN branch TEXT;
+ALTER TABLE repositories ADD COLUMN ssh_key_path TEXT;
+ALTER TABLE repositories ADD COLUMN last_sync_attempt DATETIME;
+ALTER TABLE repositories ADD COLUMN deleted_at DATETIME;
+ALTER TABLE repositories ADD COLUMN mirror BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE repositories ADD COLUMN recurse_submodules BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE repositories ADD COLUMN size_bytes INTEGER;
+ALTER TABLE repositories ADD COLUMN last_commit_sha TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_author TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_summary TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_at DATETIME;
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
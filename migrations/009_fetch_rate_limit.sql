@@ -0,0 +1 @@
+ALTER TABLE repositories ADD COLUMN fetch_rate_limit_kbps INTEGER;
@@ -0,0 +1,11 @@
+CREATE TABLE sync_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    repository_id INTEGER NOT NULL REFERENCES repositories(id) ON DELETE CASCADE,
+    started_at DATETIME NOT NULL,
+    finished_at DATETIME NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    commits_advanced INTEGER
+);
+
+CREATE INDEX idx_sync_history_repository ON sync_history (repository_id, started_at);
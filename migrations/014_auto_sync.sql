@@ -0,0 +1 @@
+ALTER TABLE repositories ADD COLUMN auto_sync_enabled BOOLEAN NOT NULL DEFAULT 1;
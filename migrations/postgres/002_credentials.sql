@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS credentials (
+    repository_id BIGINT PRIMARY KEY REFERENCES repositories(id) ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    secret BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,12 @@
+ALTER TABLE repositories ADD COLUMN branch TEXT;
+ALTER TABLE repositories ADD COLUMN ssh_key_path TEXT;
+ALTER TABLE repositories ADD COLUMN last_sync_attempt TIMESTAMPTZ;
+ALTER TABLE repositories ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE repositories ADD COLUMN mirror BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE repositories ADD COLUMN recurse_submodules BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE repositories ADD COLUMN size_bytes BIGINT;
+ALTER TABLE repositories ADD COLUMN last_commit_sha TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_author TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_summary TEXT;
+ALTER TABLE repositories ADD COLUMN last_commit_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
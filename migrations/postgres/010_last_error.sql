@@ -0,0 +1 @@
+ALTER TABLE repositories ADD COLUMN last_error TEXT;
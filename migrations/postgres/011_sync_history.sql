@@ -0,0 +1,11 @@
+CREATE TABLE sync_history (
+    id BIGSERIAL PRIMARY KEY,
+    repository_id BIGINT NOT NULL REFERENCES repositories(id) ON DELETE CASCADE,
+    started_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL,
+    status TEXT NOT NULL,
+    error TEXT,
+    commits_advanced BIGINT
+);
+
+CREATE INDEX idx_sync_history_repository ON sync_history (repository_id, started_at);
@@ -0,0 +1 @@
+ALTER TABLE repositories ADD COLUMN syncs_since_gc BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE repositories ADD COLUMN max_size_mb BIGINT;
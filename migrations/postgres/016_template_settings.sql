@@ -0,0 +1,6 @@
+ALTER TABLE templates ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+ALTER TABLE templates ADD COLUMN auto_sync_enabled BOOLEAN;
+ALTER TABLE templates ADD COLUMN mirror BOOLEAN;
+ALTER TABLE templates ADD COLUMN recurse_submodules BOOLEAN;
+ALTER TABLE templates ADD COLUMN fetch_rate_limit_kbps BIGINT;
+ALTER TABLE templates ADD COLUMN max_size_mb BIGINT;
@@ -1,40 +1,527 @@
-use bcrypt::verify;
-use anyhow::Result;
+use bcrypt::{hash, verify};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 use std::sync::Arc;
+use tracing::debug;
+
+use crate::config::Config;
+use crate::models::{Role, SessionInfo};
+
+// HS256 keys shorter than the hash output are easy to brute force
+const MIN_JWT_SECRET_LEN: usize = 32;
+const JWT_HEADER: &str = r#"{"alg":"HS256","typ":"JWT"}"#;
+
+pub const DEFAULT_MIN_PASSWORD_LENGTH: usize = 12;
+pub const DEFAULT_MIN_PASSWORD_CLASSES: usize = 2;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How login tokens are issued and checked, chosen with `AUTH_MODE`.
+#[derive(Clone)]
+pub enum AuthMode {
+    /// Opaque tokens looked up in this process's session map (the default).
+    Session,
+    /// HS256 JWTs signed with `JWT_SECRET`, accepted by every instance sharing the secret.
+    Jwt { secret: Arc<Vec<u8>> },
+}
+
+impl AuthMode {
+    pub fn from_env() -> Result<Self> {
+        match env::var("AUTH_MODE").as_deref().unwrap_or("session") {
+            "session" => Ok(AuthMode::Session),
+            "jwt" => {
+                let Ok(secret) = env::var("JWT_SECRET") else {
+                    bail!("AUTH_MODE=jwt requires JWT_SECRET to be set");
+                };
+                if secret.len() < MIN_JWT_SECRET_LEN {
+                    bail!("JWT_SECRET must be at least {} characters long", MIN_JWT_SECRET_LEN);
+                }
+                Ok(AuthMode::Jwt { secret: Arc::new(secret.into_bytes()) })
+            }
+            other => bail!("Unknown AUTH_MODE '{}', expected 'session' or 'jwt'", other),
+        }
+    }
+}
+
+// Never print the secret
+impl fmt::Debug for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for AuthMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMode::Session => f.write_str("session"),
+            AuthMode::Jwt { .. } => f.write_str("jwt"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    role: Role,
+    iat: u64,
+    exp: u64,
+}
+
+/// The user a request was authenticated as. The role is fixed at login.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub username: String,
+    pub role: Role,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+}
+
+/// Why a token was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// Unknown, malformed or revoked.
+    Invalid,
+    /// Was valid, but its TTL ran out.
+    Expired,
+}
+
+struct Session {
+    user: AuthUser,
+    expires_at: Instant,
+}
 
 #[derive(Clone)]
 pub struct AuthManager {
-    sessions: Arc<RwLock<HashMap<String, String>>>, // token -> username
+    sessions: Arc<RwLock<HashMap<String, Session>>>, // token -> session
+    ttl: Duration,
+    mode: AuthMode,
+    /// Hash of a random password that was thrown away, made at the configured cost, see `verify_login`.
+    dummy_hash: Arc<String>,
+}
+
+impl Default for AuthManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AuthManager {
     pub fn new() -> Self {
         AuthManager {
             sessions: Arc::new(RwLock::new(HashMap::new())),
+            ttl: Config::get().session_ttl,
+            mode: Config::get().auth_mode.clone(),
+            dummy_hash: Arc::new(Self::hash_password(&Uuid::new_v4().to_string()).expect("the bcrypt cost is validated at startup")),
         }
     }
 
+    /// Hashes a password at the cost set with `BCRYPT_COST`.
+    pub fn hash_password(password: &str) -> Result<String> {
+        Ok(hash(password, Config::get().bcrypt_cost)?)
+    }
+
     pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
         Ok(verify(password, hash)?)
     }
 
-    pub async fn create_session(&self, username: &str) -> String {
+    /// Whether a stored hash was made at a lower cost than the configured one.
+    pub fn needs_rehash(hash: &str) -> bool {
+        hashed_below(hash, Config::get().bcrypt_cost)
+    }
+
+    /// Checks the password given at login against the stored hash, `None` if the user doesn't exist.
+    /// Unknown users are checked against a dummy hash instead of failing right away, so that a login
+    /// takes as long for them as for a wrong password and the timing doesn't reveal which usernames exist.
+    pub fn verify_login(&self, password: &str, hash: Option<&str>) -> bool {
+        let matches = Self::verify_password(password, hash.unwrap_or(&self.dummy_hash)).unwrap_or(false);
+        matches && hash.is_some()
+    }
+
+    /// When a session created now would expire.
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        Utc::now() + self.ttl
+    }
+
+    pub async fn create_session(&self, user: AuthUser) -> String {
+        self.create_session_with_ttl(user, self.ttl).await
+    }
+
+    pub async fn create_session_with_ttl(&self, user: AuthUser, ttl: Duration) -> String {
+        if let AuthMode::Jwt { secret } = &self.mode {
+            return sign_jwt(secret, &user, ttl);
+        }
         let token = Uuid::new_v4().to_string();
         let mut sessions = self.sessions.write().await;
-        sessions.insert(token.clone(), username.to_string());
+        sessions.insert(token.clone(), Session {
+            user,
+            expires_at: Instant::now() + ttl,
+        });
         token
     }
 
-    pub async fn validate_session(&self, token: &str) -> Option<String> {
-        let sessions = self.sessions.read().await;
-        sessions.get(token).cloned()
+    /// Expired sessions only report `TokenError::Expired` until the sweeper purges them.
+    pub async fn validate_session(&self, token: &str) -> Result<AuthUser, TokenError> {
+        if let AuthMode::Jwt { secret } = &self.mode {
+            return verify_jwt(secret, token);
+        }
+        {
+            let sessions = self.sessions.read().await;
+            match sessions.get(token) {
+                Some(session) if session.expires_at > Instant::now() => return Ok(session.user.clone()),
+                Some(_) => {}
+                None => return Err(TokenError::Invalid),
+            }
+        }
+
+        // Expired: drop it now instead of waiting for the sweeper
+        self.remove_session(token).await;
+        Err(TokenError::Expired)
     }
 
+    /// Swaps a session for a new one with a fresh TTL, for `user` as currently stored. The old token stops
+    /// working, except for JWTs, which can't be revoked and stay valid until they expire.
+    pub async fn refresh_session(&self, token: &str, user: AuthUser) -> Result<String, TokenError> {
+        if let AuthMode::Jwt { secret } = &self.mode {
+            return Ok(sign_jwt(secret, &user, self.ttl));
+        }
+        // Removed and replaced under one lock, so a token can only be refreshed once
+        let mut sessions = self.sessions.write().await;
+        match sessions.remove(token) {
+            Some(session) if session.expires_at > Instant::now() => {}
+            Some(_) => return Err(TokenError::Expired),
+            None => return Err(TokenError::Invalid),
+        }
+        let token = Uuid::new_v4().to_string();
+        sessions.insert(token.clone(), Session {
+            user,
+            expires_at: Instant::now() + self.ttl,
+        });
+        Ok(token)
+    }
+
+    /// JWTs can't be revoked and stay valid until they expire.
     pub async fn remove_session(&self, token: &str) {
         let mut sessions = self.sessions.write().await;
         sessions.remove(token);
     }
+
+    /// Whether sessions are kept in this process and can be listed and revoked, which JWTs can't.
+    pub fn tracks_sessions(&self) -> bool {
+        matches!(self.mode, AuthMode::Session)
+    }
+
+    /// Sessions that haven't expired yet, by username.
+    pub async fn list_sessions(&self) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let sessions = self.sessions.read().await;
+        let mut list: Vec<SessionInfo> = sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at > now)
+            .map(|(token, session)| SessionInfo {
+                id: session_id(token),
+                username: session.user.username.clone(),
+                role: session.user.role,
+                expires_at: Utc::now() + (session.expires_at - now),
+            })
+            .collect();
+        list.sort_by(|a, b| a.username.cmp(&b.username).then(a.expires_at.cmp(&b.expires_at)));
+        list
+    }
+
+    /// Revokes the session with the given `SessionInfo::id`, returning the user it belonged to.
+    pub async fn remove_session_by_id(&self, id: &str) -> Option<AuthUser> {
+        let mut sessions = self.sessions.write().await;
+        let token = sessions.keys().find(|token| session_id(token) == id)?.clone();
+        sessions.remove(&token).map(|session| session.user)
+    }
+
+    /// Logs a user out everywhere, returning how many sessions were revoked.
+    pub async fn remove_user_sessions(&self, username: &str) -> usize {
+        let mut sessions = self.sessions.write().await;
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user.username != username);
+        before - sessions.len()
+    }
+
+    /// Removes all expired sessions, returning how many were purged.
+    pub async fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut sessions = self.sessions.write().await;
+        let before = sessions.len();
+        sessions.retain(|_, session| session.expires_at > now);
+        before - sessions.len()
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let auth_manager = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let purged = auth_manager.purge_expired().await;
+                if purged > 0 {
+                    debug!("Purged {} expired sessions", purged);
+                }
+            }
+        });
+    }
+}
+
+// Short stand-in for a token in listings, which can't be turned back into the token
+fn session_id(token: &str) -> String {
+    hex::encode(&Sha256::digest(token.as_bytes())[..8])
+}
+
+/// Why a new password is too weak: too short, too uniform or guessable from the username.
+pub fn password_weakness(username: &str, password: &str) -> Option<String> {
+    let min_length = Config::get().min_password_length;
+    let min_classes = Config::get().min_password_classes;
+    if password.chars().count() < min_length {
+        return Some(format!("it must be at least {} characters long", min_length));
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < min_classes {
+        return Some(format!(
+            "it must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+            min_classes
+        ));
+    }
+    if password.to_lowercase().contains(&username.to_lowercase()) {
+        return Some("it must not contain the username".to_string());
+    }
+    None
+}
+
+// `$2b$<cost>$<salt and hash>`
+fn hashed_below(hash: &str, cost: u32) -> bool {
+    let hash_cost = hash.split('$').nth(2).and_then(|cost| cost.parse::<u32>().ok());
+    hash_cost.is_some_and(|hash_cost| hash_cost < cost)
+}
+
+fn sign_jwt(secret: &[u8], user: &AuthUser, ttl: Duration) -> String {
+    let now = unix_now();
+    let claims = Claims {
+        sub: user.username.clone(),
+        role: user.role,
+        iat: now,
+        exp: now + ttl.as_secs(),
+    };
+    let claims = serde_json::to_vec(&claims).expect("claims always serialize");
+    let signing_input = format!("{}.{}", URL_SAFE_NO_PAD.encode(JWT_HEADER), URL_SAFE_NO_PAD.encode(claims));
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(signing_input.as_bytes());
+    let signature = mac.finalize().into_bytes();
+    format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature))
+}
+
+// Returns the user of a token with a valid signature that hasn't expired yet
+fn verify_jwt(secret: &[u8], token: &str) -> Result<AuthUser, TokenError> {
+    let claims = verified_claims(secret, token).ok_or(TokenError::Invalid)?;
+    if claims.exp <= unix_now() {
+        debug!("Rejected expired token of {}", claims.sub);
+        return Err(TokenError::Expired);
+    }
+    Ok(AuthUser {
+        username: claims.sub,
+        role: claims.role,
+    })
+}
+
+// Claims of a token with a valid signature, whether or not it expired
+fn verified_claims(secret: &[u8], token: &str) -> Option<Claims> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = HmacSha256::new_from_slice(secret).ok()?;
+    mac.update(signing_input.as_bytes());
+    // Constant-time comparison
+    mac.verify_slice(&signature).ok()?;
+
+    // Only HS256 is issued, so reject anything else (in particular `none`)
+    let header: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.get("alg")?.as_str()? != "HS256" {
+        return None;
+    }
+
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn user() -> AuthUser {
+        AuthUser { username: "alice".to_string(), role: Role::User }
+    }
+
+    // A session manager with a cheap dummy hash, so tests don't pay for the configured bcrypt cost
+    fn manager(mode: AuthMode, ttl: Duration) -> AuthManager {
+        AuthManager {
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            ttl,
+            mode,
+            dummy_hash: Arc::new(hash("thrown away", 4).unwrap()),
+        }
+    }
+
+    fn decode_part(part: &str) -> serde_json::Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+    }
+
+    fn encode_part(value: &serde_json::Value) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).unwrap())
+    }
+
+    fn sign(signing_input: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET).unwrap();
+        mac.update(signing_input.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn signed_token_verifies() {
+        let token = sign_jwt(SECRET, &user(), Duration::from_secs(60));
+        let verified = verify_jwt(SECRET, &token).unwrap();
+        assert_eq!((verified.username.as_str(), verified.role), ("alice", Role::User));
+        assert_eq!(verify_jwt(b"another secret of at least 32 bytes", &token).unwrap_err(), TokenError::Invalid);
+    }
+
+    #[test]
+    fn modified_payload_is_invalid() {
+        let token = sign_jwt(SECRET, &user(), Duration::from_secs(60));
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut claims = decode_part(parts[1]);
+        claims["role"] = serde_json::json!("admin");
+        let forged = format!("{}.{}.{}", parts[0], encode_part(&claims), parts[2]);
+        assert_eq!(verify_jwt(SECRET, &forged).unwrap_err(), TokenError::Invalid);
+    }
+
+    #[test]
+    fn modified_signature_is_invalid() {
+        let token = sign_jwt(SECRET, &user(), Duration::from_secs(60));
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let mut signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+        let forged = format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature));
+        assert_eq!(verify_jwt(SECRET, &forged).unwrap_err(), TokenError::Invalid);
+        assert_eq!(verify_jwt(SECRET, signing_input).unwrap_err(), TokenError::Invalid);
+    }
+
+    #[test]
+    fn alg_none_is_rejected() {
+        let token = sign_jwt(SECRET, &user(), Duration::from_secs(60));
+        let claims = token.split('.').nth(1).unwrap();
+        let header = encode_part(&serde_json::json!({"alg": "none", "typ": "JWT"}));
+        let unsigned = format!("{}.{}.", header, claims);
+        assert_eq!(verify_jwt(SECRET, &unsigned).unwrap_err(), TokenError::Invalid);
+        // Even with a valid HMAC, only HS256 headers are accepted
+        let signing_input = format!("{}.{}", header, claims);
+        let signed = format!("{}.{}", signing_input, sign(&signing_input));
+        assert_eq!(verify_jwt(SECRET, &signed).unwrap_err(), TokenError::Invalid);
+    }
+
+    #[test]
+    fn past_expiry_is_expired() {
+        let token = sign_jwt(SECRET, &user(), Duration::ZERO);
+        assert_eq!(verify_jwt(SECRET, &token).unwrap_err(), TokenError::Expired);
+
+        let mut claims = decode_part(token.split('.').nth(1).unwrap());
+        claims["exp"] = serde_json::json!(unix_now() - 3600);
+        let signing_input = format!("{}.{}", URL_SAFE_NO_PAD.encode(JWT_HEADER), encode_part(&claims));
+        let expired = format!("{}.{}", signing_input, sign(&signing_input));
+        assert_eq!(verify_jwt(SECRET, &expired).unwrap_err(), TokenError::Expired);
+    }
+
+    #[tokio::test]
+    async fn refreshed_session_replaces_the_old_token() {
+        let auth = manager(AuthMode::Session, Duration::from_secs(60));
+        let token = auth.create_session(user()).await;
+        let refreshed = auth.refresh_session(&token, user()).await.unwrap();
+
+        assert_ne!(refreshed, token);
+        assert_eq!(auth.validate_session(&refreshed).await.unwrap().username, "alice");
+        assert_eq!(auth.validate_session(&token).await.unwrap_err(), TokenError::Invalid);
+        // A token can only be refreshed once
+        assert_eq!(auth.refresh_session(&token, user()).await.unwrap_err(), TokenError::Invalid);
+    }
+
+    #[tokio::test]
+    async fn expired_session_is_not_refreshed() {
+        let auth = manager(AuthMode::Session, Duration::ZERO);
+        let token = auth.create_session(user()).await;
+        assert_eq!(auth.refresh_session(&token, user()).await.unwrap_err(), TokenError::Expired);
+    }
+
+    #[tokio::test]
+    async fn refreshed_jwt_verifies() {
+        let auth = manager(AuthMode::Jwt { secret: Arc::new(SECRET.to_vec()) }, Duration::from_secs(60));
+        let token = auth.create_session(user()).await;
+        let refreshed = auth.refresh_session(&token, user()).await.unwrap();
+        assert_eq!(auth.validate_session(&refreshed).await.unwrap().username, "alice");
+    }
+
+    #[test]
+    fn login_checks_the_stored_hash() {
+        let auth = manager(AuthMode::Session, Duration::from_secs(60));
+        let stored = hash("correct horse", 4).unwrap();
+        assert!(auth.verify_login("correct horse", Some(&stored)));
+        assert!(!auth.verify_login("wrong horse", Some(&stored)));
+    }
+
+    #[test]
+    fn unknown_user_is_checked_against_the_dummy_hash() {
+        let mut auth = manager(AuthMode::Session, Duration::from_secs(60));
+        auth.dummy_hash = Arc::new(hash("thrown away", 8).unwrap());
+        let stored = hash("correct horse", 8).unwrap();
+
+        // Even the dummy's own password never logs in an unknown user
+        assert!(!auth.verify_login("thrown away", None));
+
+        // Skipping bcrypt would take microseconds rather than the milliseconds a cost-8 check takes
+        let started = Instant::now();
+        assert!(!auth.verify_login("wrong horse", Some(&stored)));
+        let known = started.elapsed();
+        let started = Instant::now();
+        assert!(!auth.verify_login("wrong horse", None));
+        let unknown = started.elapsed();
+        assert!(unknown * 10 >= known, "unknown user took {:?}, wrong password {:?}", unknown, known);
+    }
+
+    #[test]
+    fn only_cheaper_hashes_need_rehashing() {
+        assert!(hashed_below(&hash("correct horse", 4).unwrap(), 5));
+        assert!(!hashed_below(&hash("correct horse", 5).unwrap(), 5));
+        assert!(!hashed_below(&hash("correct horse", 6).unwrap(), 5));
+        assert!(!hashed_below("not a bcrypt hash", 5));
+    }
 }
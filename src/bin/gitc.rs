@@ -1,8 +1,13 @@
 use std::env;
+use std::io::{self, Write};
 use std::process;
-use bcrypt::{hash, DEFAULT_COST};
-use sqlx::{SqlitePool, Row};
-use tokio;
+use gitcloner::auth::{self, AuthManager};
+use gitcloner::config::Config;
+use gitcloner::database::Database;
+use gitcloner::git_manager::{GitError, GitManager};
+use gitcloner::handlers::add_repository_entry;
+use gitcloner::models::{AddRepositoryRequest, RepoStatus};
+use gitcloner::sync::{sync_all_repositories, sync_one_repository, SyncQueue};
 
 #[tokio::main]
 async fn main() {
@@ -13,40 +18,49 @@ async fn main() {
         process::exit(1);
     }
 
-    let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:gitcloner.db".to_string());
-    
-    let pool = match SqlitePool::connect(&database_url).await {
-        Ok(pool) => pool,
+    let config = match Config::load() {
+        Ok(config) => config,
         Err(e) => {
-            eprintln!("Failed to connect to database: {}", e);
+            eprintln!("Invalid configuration: {:#}", e);
             process::exit(1);
         }
     };
+    
+    if args[1] == "repo" {
+        run_repo_command(&args, config).await;
+        return;
+    }
+    
+    let db = open_database(config).await;
 
     match args[1].as_str() {
         "add" => {
-            if args.len() != 4 {
-                eprintln!("Usage: {} add <username> <password>", args[0]);
+            let user_args = parse_user_args(&args[2..]);
+            if user_args.positional.is_empty() || user_args.positional.len() > 2 {
+                eprintln!("Usage: {} add <username> [<password> | --stdin] [--role admin|user] [--force]", args[0]);
                 process::exit(1);
             }
-            add_user(&pool, &args[2], &args[3]).await;
+            let password = read_password(&user_args);
+            add_user(&db, &user_args.positional[0], &password, user_args.role.as_deref()).await;
         }
         "remove" => {
             if args.len() != 3 {
                 eprintln!("Usage: {} remove <username>", args[0]);
                 process::exit(1);
             }
-            remove_user(&pool, &args[2]).await;
+            remove_user(&db, &args[2]).await;
         }
         "list" => {
-            list_users(&pool).await;
+            list_users(&db).await;
         }
         "update" => {
-            if args.len() != 4 {
-                eprintln!("Usage: {} update <username> <new_password>", args[0]);
+            let user_args = parse_user_args(&args[2..]);
+            if user_args.positional.is_empty() || user_args.positional.len() > 2 || user_args.role.is_some() {
+                eprintln!("Usage: {} update <username> [<new_password> | --stdin] [--force]", args[0]);
                 process::exit(1);
             }
-            update_user_password(&pool, &args[2], &args[3]).await;
+            let password = read_password(&user_args);
+            update_user_password(&db, &user_args.positional[0], &password).await;
         }
         _ => {
             eprintln!("Unknown command: {}", args[1]);
@@ -60,23 +74,192 @@ fn print_usage() {
     println!("GitCloner Admin Tool");
     println!();
     println!("USAGE:");
-    println!("    gitc add <username> <password>     - Add or update user");
+    println!("    gitc add <username> [--role admin|user]");
+    println!("                                       - Add or update user (new users default to 'user')");
     println!("    gitc remove <username>             - Remove user");
-    println!("    gitc update <username> <password>  - Update user password");
+    println!("    gitc update <username>             - Update user password");
     println!("    gitc list                          - List all users");
+    println!("    gitc repo add <url>                - Clone and track a repository");
+    println!("    gitc repo list                     - List tracked repositories");
+    println!("    gitc repo remove <url>             - Move a repository to the trash");
+    println!("    gitc repo sync <url>               - Sync a repository now");
+    println!("    gitc repo sync --all               - Sync every repository now, like the daily sync");
     println!();
     println!("EXAMPLES:");
-    println!("    gitc add admin admin123");
-    println!("    gitc add john secret456");
+    println!("    gitc add admin --role admin");
+    println!("    gitc update john");
     println!("    gitc remove admin");
-    println!("    gitc update john newpassword789");
+    println!("    echo \"$PASSWORD\" | gitc add ci --stdin");
+    println!("    gitc repo add https://github.com/rust-lang/rust.git");
     println!();
     println!("ENVIRONMENT:");
-    println!("    DATABASE_URL - Database connection string (default: sqlite:gitcloner.db)");
+    println!("    DATABASE_URL         - sqlite: or postgres:// connection string (default: sqlite:gitcloner.db)");
+    println!("    REPOS_DIR            - Directory holding the repositories (default: ./repos)");
+    println!("    MIN_PASSWORD_LENGTH  - Shortest password accepted (default: {})", auth::DEFAULT_MIN_PASSWORD_LENGTH);
+    println!("    MIN_PASSWORD_CLASSES - Lowercase, uppercase, digits and symbols a password must mix (default: {})", auth::DEFAULT_MIN_PASSWORD_CLASSES);
+    println!();
+    println!("Passwords are prompted for without echo. Use --stdin to read one from standard input, or pass it");
+    println!("as an argument in scripts (it then ends up in the shell history). Weak passwords are rejected");
+    println!("unless --force is given.");
+}
+
+struct UserArgs {
+    positional: Vec<String>,
+    role: Option<String>,
+    stdin: bool,
+    /// Accept passwords that fail the strength check, for throwaway test setups.
+    force: bool,
+}
+
+// Pulls `--role <role>`, `--stdin` and `--force` out of the arguments, exiting on an invalid role
+fn parse_user_args(args: &[String]) -> UserArgs {
+    let mut user_args = UserArgs {
+        positional: Vec::new(),
+        role: None,
+        stdin: false,
+        force: false,
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--role" => match iter.next().map(String::as_str) {
+                Some(value @ ("admin" | "user")) => user_args.role = Some(value.to_string()),
+                _ => {
+                    eprintln!("--role must be 'admin' or 'user'");
+                    process::exit(1);
+                }
+            },
+            "--stdin" => user_args.stdin = true,
+            "--force" => user_args.force = true,
+            _ => user_args.positional.push(arg.clone()),
+        }
+    }
+    user_args
+}
+
+// Takes the password from the arguments, standard input or an interactive prompt, exiting on failure
+fn read_password(user_args: &UserArgs) -> String {
+    let password = match (user_args.positional.get(1), user_args.stdin) {
+        (Some(_), true) => {
+            eprintln!("Pass the password either as an argument or with --stdin, not both");
+            process::exit(1);
+        }
+        (Some(password), false) => Ok(password.clone()),
+        (None, true) => read_line(),
+        (None, false) => prompt_new_password(),
+    };
+    let password = match password {
+        Ok(password) if !password.is_empty() => password,
+        Ok(_) => {
+            eprintln!("Password must not be empty");
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to read password: {}", e);
+            process::exit(1);
+        }
+    };
+    if !user_args.force {
+        if let Some(problem) = auth::password_weakness(&user_args.positional[0], &password) {
+            eprintln!("Password is too weak: {}. Pass --force to use it anyway", problem);
+            process::exit(1);
+        }
+    }
+    password
+}
+
+
+fn prompt_new_password() -> io::Result<String> {
+    let password = prompt_hidden("Password: ")?;
+    let confirmation = prompt_hidden("Confirm password: ")?;
+    if password != confirmation {
+        eprintln!("Passwords do not match");
+        process::exit(1);
+    }
+    Ok(password)
+}
+
+fn prompt_hidden(prompt: &str) -> io::Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let password = read_line_without_echo();
+    // The newline typed by the user wasn't echoed either
+    eprintln!();
+    password
+}
+
+// The terminal settings from before echo was turned off, for the signal handler to put back
+#[cfg(unix)]
+static ECHOING_TERMINAL: std::sync::OnceLock<libc::termios> = std::sync::OnceLock::new();
+
+#[cfg(unix)]
+fn read_line_without_echo() -> io::Result<String> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = io::stdin().as_raw_fd();
+    let mut original = std::mem::MaybeUninit::<libc::termios>::uninit();
+    // SAFETY: `original` is valid for writes of a termios struct, tcgetattr only writes to it
+    if unsafe { libc::tcgetattr(fd, original.as_mut_ptr()) } != 0 {
+        return Err(io::Error::other(
+            "standard input is not a terminal, pass --stdin to read the password from it",
+        ));
+    }
+    // SAFETY: tcgetattr succeeded, so it initialized the struct
+    let original = unsafe { original.assume_init() };
+    let _ = ECHOING_TERMINAL.set(original);
+    let mut silent = original;
+    silent.c_lflag &= !libc::ECHO;
+
+    // Interrupting the prompt must not leave the shell without echo
+    let handler = restore_echo_and_reraise as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only makes async-signal-safe calls, and the previous handlers are put back below
+    let previous = unsafe { [libc::signal(libc::SIGINT, handler), libc::signal(libc::SIGTERM, handler)] };
+    // SAFETY: `silent` is a valid termios struct obtained from tcgetattr for this descriptor
+    let line = if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        read_line()
+    };
+    // Restore echo even if reading failed
+    // SAFETY: `original` is the struct tcgetattr filled in for this descriptor
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    // SAFETY: these are the handlers `signal` returned when ours were installed
+    unsafe {
+        libc::signal(libc::SIGINT, previous[0]);
+        libc::signal(libc::SIGTERM, previous[1]);
+    }
+    line
+}
+
+#[cfg(unix)]
+extern "C" fn restore_echo_and_reraise(signal: libc::c_int) {
+    if let Some(original) = ECHOING_TERMINAL.get() {
+        // SAFETY: tcsetattr is async-signal-safe and `original` was filled in by tcgetattr on standard input
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original) };
+    }
+    // SAFETY: signal and raise are async-signal-safe. With the default action back, re-raising ends the
+    // process the way the signal would have without this handler.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
 }
 
-async fn add_user(pool: &SqlitePool, username: &str, password: &str) {
-    let password_hash = match hash(password, DEFAULT_COST) {
+#[cfg(not(unix))]
+fn read_line_without_echo() -> io::Result<String> {
+    Err(io::Error::other(
+        "password prompts are not supported on this platform, pass --stdin instead",
+    ))
+}
+
+fn read_line() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn add_user(db: &Database, username: &str, password: &str, role: Option<&str>) {
+    let password_hash = match AuthManager::hash_password(password) {
         Ok(hash) => hash,
         Err(e) => {
             eprintln!("Failed to hash password: {}", e);
@@ -84,19 +267,22 @@ async fn add_user(pool: &SqlitePool, username: &str, password: &str) {
         }
     };
 
-    let result = sqlx::query(
-        "INSERT OR REPLACE INTO users (username, password_hash) VALUES (?, ?)"
-    )
-    .bind(username)
-    .bind(&password_hash)
-    .execute(pool)
-    .await;
+    // Existing users keep their role unless --role is given
+    let result = match db.save_user(username, &password_hash, role).await {
+        Ok(()) => db.get_user_by_username(username).await,
+        Err(e) => Err(e),
+    };
 
     match result {
-        Ok(_) => {
+        Ok(Some(user)) => {
+            record_audit(db, "user.add", username, Some(user.role.as_str())).await;
             println!("✓ User '{}' created/updated successfully", username);
             println!("  Username: {}", username);
-            println!("  Password: {}", password);
+            println!("  Role: {}", user.role.as_str());
+        }
+        Ok(None) => {
+            eprintln!("Failed to create user '{}': it disappeared right after being saved", username);
+            process::exit(1);
         }
         Err(e) => {
             eprintln!("Failed to create user '{}': {}", username, e);
@@ -105,15 +291,11 @@ async fn add_user(pool: &SqlitePool, username: &str, password: &str) {
     }
 }
 
-async fn remove_user(pool: &SqlitePool, username: &str) {
-    let result = sqlx::query("DELETE FROM users WHERE username = ?")
-        .bind(username)
-        .execute(pool)
-        .await;
-
-    match result {
-        Ok(result) => {
-            if result.rows_affected() > 0 {
+async fn remove_user(db: &Database, username: &str) {
+    match db.remove_user(username).await {
+        Ok(removed) => {
+            if removed {
+                record_audit(db, "user.remove", username, None).await;
                 println!("✓ User '{}' removed successfully", username);
             } else {
                 println!("! User '{}' not found", username);
@@ -126,8 +308,8 @@ async fn remove_user(pool: &SqlitePool, username: &str) {
     }
 }
 
-async fn update_user_password(pool: &SqlitePool, username: &str, new_password: &str) {
-    let password_hash = match hash(new_password, DEFAULT_COST) {
+async fn update_user_password(db: &Database, username: &str, new_password: &str) {
+    let password_hash = match AuthManager::hash_password(new_password) {
         Ok(hash) => hash,
         Err(e) => {
             eprintln!("Failed to hash password: {}", e);
@@ -135,15 +317,10 @@ async fn update_user_password(pool: &SqlitePool, username: &str, new_password: &
         }
     };
 
-    let result = sqlx::query("UPDATE users SET password_hash = ? WHERE username = ?")
-        .bind(&password_hash)
-        .bind(username)
-        .execute(pool)
-        .await;
-
-    match result {
-        Ok(result) => {
-            if result.rows_affected() > 0 {
+    match db.update_user_password(username, &password_hash).await {
+        Ok(updated) => {
+            if updated {
+                record_audit(db, "user.update", username, Some("password")).await;
                 println!("✓ Password for user '{}' updated successfully", username);
             } else {
                 eprintln!("! User '{}' not found. Use 'add {} <password>' to create one.", username, username);
@@ -157,21 +334,20 @@ async fn update_user_password(pool: &SqlitePool, username: &str, new_password: &
     }
 }
 
-async fn list_users(pool: &SqlitePool) {
-    let result = sqlx::query("SELECT username, created_at FROM users ORDER BY created_at")
-        .fetch_all(pool)
-        .await;
-
-    match result {
-        Ok(rows) => {
-            if rows.is_empty() {
+async fn list_users(db: &Database) {
+    match db.get_users().await {
+        Ok(users) => {
+            if users.is_empty() {
                 println!("No users found");
             } else {
                 println!("Users:");
-                for row in rows {
-                    let username: String = row.get("username");
-                    let created_at: chrono::DateTime<chrono::Utc> = row.get("created_at");
-                    println!("  {} (created: {})", username, created_at.format("%Y-%m-%d %H:%M:%S UTC"));
+                for user in users {
+                    println!(
+                        "  {} [{}] (created: {})",
+                        user.username,
+                        user.role.as_str(),
+                        user.created_at.format("%Y-%m-%d %H:%M:%S UTC")
+                    );
                 }
             }
         }
@@ -181,3 +357,218 @@ async fn list_users(pool: &SqlitePool) {
         }
     }
 }
+
+// Audit log entries name the local account that ran gitc, as it has no login of its own
+async fn record_audit(db: &Database, action: &str, target: &str, detail: Option<&str>) {
+    let account = env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    if let Err(e) = db.record_audit(&format!("gitc:{}", account), action, target, detail).await {
+        eprintln!("Warning: failed to record {} in the audit log: {}", action, e);
+    }
+}
+
+// Connects and creates any missing tables, so gitc also works before the server first ran
+async fn open_database(config: &Config) -> Database {
+    let db = match Database::new(&config.database_url).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to connect to database: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = db.migrate().await {
+        eprintln!("Failed to migrate database: {}", e);
+        process::exit(1);
+    }
+    db
+}
+
+async fn run_repo_command(args: &[String], config: &Config) {
+    let command = args.get(2).map(String::as_str).unwrap_or_default();
+    match (command, args.len()) {
+        ("list", 3) | ("add" | "remove" | "sync", 4) => {}
+        _ => {
+            eprintln!("Usage: {} repo <add|remove|sync> <url> | {} repo sync --all | {} repo list", args[0], args[0], args[0]);
+            process::exit(1);
+        }
+    }
+
+    let db = open_database(config).await;
+    let git_manager = match GitManager::new(config).await {
+        Ok(git_manager) => git_manager,
+        Err(e) => {
+            eprintln!("Failed to open the repositories directory: {}", e);
+            process::exit(1);
+        }
+    };
+
+    match command {
+        "add" => add_repository(&db, &git_manager, &args[3]).await,
+        "list" => list_repositories(&db).await,
+        "remove" => remove_repository(&db, &git_manager, &args[3]).await,
+        "sync" if args[3] == "--all" => sync_all(&db, &git_manager, config.sync_concurrency).await,
+        "sync" => sync_repository(&db, &git_manager, &args[3]).await,
+        _ => unreachable!(),
+    }
+}
+
+async fn add_repository(db: &Database, git_manager: &GitManager, url: &str) {
+    let request = AddRepositoryRequest {
+        url: url.to_string(),
+        branch: None,
+        credentials: None,
+        ssh_key_path: None,
+        template: None,
+        tags: Vec::new(),
+        mirror: None,
+        recurse_submodules: None,
+        overwrite: false,
+        adopt: false,
+        fetch_rate_limit_kbps: None,
+        max_size_mb: None,
+        auto_sync_enabled: None,
+    };
+    println!("Adding {}...", url);
+    match add_repository_entry(db, git_manager, request).await {
+        Ok(added) => {
+            record_audit(db, "repository.add", url, None).await;
+            println!("✓ Repository '{}' added successfully", url);
+            println!("  Name: {}", added["name"].as_str().unwrap_or_default());
+            println!("  Path: {}", added["local_path"].as_str().unwrap_or_default());
+            if added["status"] == RepoStatus::LfsPointersOnly.as_str() {
+                println!("  Warning: uses Git LFS, only pointer files were checked out");
+            }
+        }
+        Err(failure) => {
+            eprintln!("Failed to add repository '{}': {}", url, failure.message);
+            process::exit(1);
+        }
+    }
+}
+
+async fn list_repositories(db: &Database) {
+    match db.get_all_repositories().await {
+        Ok(repositories) => {
+            if repositories.is_empty() {
+                println!("No repositories found");
+            } else {
+                println!("Repositories:");
+                for repo in repositories {
+                    let last_synced = repo
+                        .last_synced
+                        .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                        .unwrap_or_else(|| "never".to_string());
+                    println!("  {} [{}] (last synced: {})", repo.url, repo.status, last_synced);
+                }
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to list repositories: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn remove_repository(db: &Database, git_manager: &GitManager, url: &str) {
+    let repo = find_repository(db, url).await;
+    if let Err(e) = git_manager.move_to_trash(&repo).await {
+        eprintln!("Failed to move repository files to the trash: {}", e);
+        process::exit(1);
+    }
+    match db.remove_repository_by_id(repo.id).await {
+        Ok(_) => {
+            record_audit(db, "repository.remove", &repo.url, None).await;
+            println!("✓ Repository '{}' moved to the trash", repo.url);
+        }
+        Err(e) => {
+            eprintln!("Failed to remove repository '{}': {}", repo.url, e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn sync_repository(db: &Database, git_manager: &GitManager, url: &str) {
+    let repo = find_repository(db, url).await;
+    println!("Syncing {}...", repo.url);
+    match sync_one_repository(db, git_manager, &repo).await {
+        Ok(Ok(outcome)) => {
+            record_audit(db, "repository.sync", &repo.url, Some(outcome.status().as_str())).await;
+            println!("✓ Repository '{}' synced successfully", repo.url);
+            if let Some(new_url) = outcome.moved_to {
+                println!("  Moved to: {}", new_url);
+            }
+            if outcome.lfs_pointers_only {
+                println!("  Warning: uses Git LFS, only pointer files were checked out");
+            }
+        }
+        Ok(Err(e)) => {
+            record_audit(db, "repository.sync", &repo.url, Some(&format!("failed: {}", e))).await;
+            eprintln!("Failed to sync repository '{}': {}", repo.url, e);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to record sync result for '{}': {}", repo.url, e);
+            process::exit(1);
+        }
+    }
+}
+
+async fn sync_all(db: &Database, git_manager: &GitManager, concurrency: usize) {
+    match db.sync_paused().await {
+        Ok(false) => {}
+        Ok(true) => {
+            println!("- Automatic syncing is paused, resume it with POST /api/admin/sync/resume or sync repositories one by one");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to read the sync settings: {}", e);
+            process::exit(1);
+        }
+    }
+    // The server can't reach this process's queue, so nothing is prioritized in it
+    let results = match sync_all_repositories(db, git_manager, &SyncQueue::default(), concurrency).await {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Failed to list repositories: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut failed = 0;
+    for (repo, result) in &results {
+        match result {
+            Ok(Ok(outcome)) => println!("✓ {} ({})", repo.url, outcome.status()),
+            Ok(Err(e)) if matches!(e.downcast_ref::<GitError>(), Some(GitError::Busy)) => {
+                println!("- {}: skipped, {}", repo.url, e);
+            }
+            Ok(Err(e)) => {
+                failed += 1;
+                eprintln!("✗ {}: {}", repo.url, e);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("✗ {}: failed to record the result: {}", repo.url, e);
+            }
+        }
+    }
+    let summary = format!("{} repositories, {} failed", results.len(), failed);
+    record_audit(db, "repository.sync_all", "*", Some(&summary)).await;
+    println!("Synced {}", summary);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+async fn find_repository(db: &Database, url: &str) -> gitcloner::models::Repository {
+    match db.get_repository_by_url(url).await {
+        Ok(Some(repo)) => repo,
+        Ok(None) => {
+            eprintln!("! Repository '{}' not found", url);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Failed to look up repository '{}': {}", url, e);
+            process::exit(1);
+        }
+    }
+}
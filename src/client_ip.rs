@@ -0,0 +1,218 @@
+//! The address of the client behind a request, which is the proxy's when the server runs behind one.
+//!
+//! With `TRUST_PROXY` set, requests from `TRUSTED_PROXIES` are attributed to the address those
+//! proxies put in `X-Forwarded-For`. Each proxy appends the address it got the request from, so the
+//! header is read from the right, skipping trusted proxies: entries left of the first untrusted one
+//! come from the client and may be made up.
+
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use warp::http::HeaderMap;
+
+use crate::config::Config;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// An address or CIDR range, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' is not an IP address or CIDR range", value);
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network = IpAddr::from_str(address).map_err(|_| invalid())?.to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().ok().filter(|len| *len <= max_len).ok_or_else(invalid)?,
+            None => max_len,
+        };
+        Ok(IpRange { network, prefix_len })
+    }
+}
+
+/// Proxies whose `X-Forwarded-For` headers are believed, as a comma-separated list of ranges.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TrustedProxies(Vec<IpRange>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(ip))
+    }
+}
+
+impl Default for TrustedProxies {
+    /// A proxy on the same machine.
+    fn default() -> Self {
+        TrustedProxies(vec![
+            IpRange { network: IpAddr::from([127, 0, 0, 0]), prefix_len: 8 },
+            IpRange { network: IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]), prefix_len: 128 },
+        ])
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(IpRange::from_str)
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+impl TryFrom<String> for TrustedProxies {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The client's address according to the configuration, `None` when the connection has no address.
+pub fn client_ip(remote: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    let peer = remote?.ip().to_canonical();
+    match Config::load().ok().and_then(|config| config.trusted_proxies.as_ref()) {
+        Some(proxies) => Some(resolve(peer, headers, proxies)),
+        None => Some(peer),
+    }
+}
+
+/// Walks `X-Forwarded-For` back from `peer` for as long as the hops are trusted proxies. Stops at
+/// an entry that isn't an address, keeping the last proxy, since anything beyond it is unreliable.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, proxies: &TrustedProxies) -> IpAddr {
+    if !proxies.contains(peer) {
+        return peer;
+    }
+    let hops: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = ip;
+        if !proxies.contains(ip) {
+            break;
+        }
+    }
+    client
+}
+
+// Entries are bare addresses, though some proxies add the port
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(X_FORWARDED_FOR, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_addresses_are_resolved_through_trusted_proxies() {
+        let proxies: TrustedProxies = "10.0.0.0/8, ::1".parse().unwrap();
+        let cases = [
+            // The header of an untrusted peer is ignored
+            ("203.0.113.9", vec!["198.51.100.1"], "203.0.113.9"),
+            // Whatever the client put left of the address the proxy saw is ignored
+            ("10.0.0.1", vec!["1.2.3.4, 198.51.100.1"], "198.51.100.1"),
+            // Several trusted hops, also across repeated headers
+            ("10.0.0.1", vec!["198.51.100.1, 10.0.0.3, 10.0.0.2"], "198.51.100.1"),
+            ("10.0.0.1", vec!["198.51.100.1", "10.0.0.2"], "198.51.100.1"),
+            ("::1", vec!["2001:db8::7"], "2001:db8::7"),
+            // An entry that isn't an address stops the walk at the last proxy
+            ("10.0.0.1", vec!["198.51.100.1, garbage, 10.0.0.2"], "10.0.0.2"),
+            ("10.0.0.1", vec!["unknown"], "10.0.0.1"),
+            // Entries with a port
+            ("10.0.0.1", vec!["198.51.100.1:51234"], "198.51.100.1"),
+            ("10.0.0.1", vec!["[2001:db8::7]:443"], "2001:db8::7"),
+            // Only proxies in the header
+            ("10.0.0.1", vec!["10.0.0.3"], "10.0.0.3"),
+            ("10.0.0.1", vec![], "10.0.0.1"),
+        ];
+        for (peer, header, expected) in cases {
+            assert_eq!(resolve(ip(peer), &forwarded(&header), &proxies), ip(expected), "{} via {:?}", peer, header);
+        }
+    }
+
+    #[test]
+    fn ranges_parse_addresses_and_prefixes() {
+        assert_eq!("10.0.0.0/8".parse::<IpRange>().unwrap(), IpRange { network: ip("10.0.0.0"), prefix_len: 8 });
+        assert_eq!("192.0.2.1".parse::<IpRange>().unwrap(), IpRange { network: ip("192.0.2.1"), prefix_len: 32 });
+        assert_eq!("2001:db8::/32".parse::<IpRange>().unwrap(), IpRange { network: ip("2001:db8::"), prefix_len: 32 });
+        assert_eq!("::ffff:10.0.0.0/8".parse::<IpRange>().unwrap(), IpRange { network: ip("10.0.0.0"), prefix_len: 8 });
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "10.0.0.0/", "10.0.0/8", "example.com", ""] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges_contain_their_addresses() {
+        let v4: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(ip("10.255.0.1")));
+        assert!(!v4.contains(ip("11.0.0.1")));
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::1")));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:1::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        assert!("192.0.2.1".parse::<IpRange>().unwrap().contains(ip("192.0.2.1")));
+        assert!(!"192.0.2.1".parse::<IpRange>().unwrap().contains(ip("192.0.2.2")));
+    }
+}
@@ -0,0 +1,133 @@
+//! Gzip or deflate compression of API responses for clients that accept it.
+//!
+//! Only JSON and plain text bodies are compressed. Those are built in memory anyway, while the
+//! progress event stream and archive downloads are streamed and have to stay as they are.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use std::io::Write;
+use tracing::warn;
+use warp::http::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use warp::http::StatusCode;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+// Smaller bodies fit in a packet either way
+const MIN_COMPRESS_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Wraps `filter` so that its replies are compressed according to the `Accept-Encoding` header.
+pub fn compress<F, R>(filter: F) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::optional::<String>("accept-encoding")
+        .and(filter)
+        .and_then(|accept_encoding: Option<String>, reply: R| async move {
+            Ok::<_, Rejection>(compress_response(reply.into_response(), accept_encoding.as_deref()).await)
+        })
+}
+
+async fn compress_response(response: Response, accept_encoding: Option<&str>) -> Response {
+    if !compressible(&response) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = accept_encoding.and_then(preferred_encoding) else {
+        return Response::from_parts(parts, body);
+    };
+
+    let bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Failed to read response body for compression: {}", e);
+            parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    if bytes.len() < MIN_COMPRESS_SIZE {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    match encode(&bytes, encoding) {
+        Ok(compressed) => {
+            parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+            parts.headers.remove(CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            warn!("Failed to compress response: {}", e);
+            Response::from_parts(parts, Body::from(bytes))
+        }
+    }
+}
+
+fn compressible(response: &Response) -> bool {
+    if response.headers().contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    content_type.starts_with("application/json") || content_type.starts_with("text/plain")
+}
+
+// Gzip unless the client only takes deflate. `q=0` rules an encoding out, `*` stands for any.
+fn preferred_encoding(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut any = None;
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = params
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "deflate" => deflate = Some(quality),
+            "*" => any = Some(quality),
+            _ => {}
+        }
+    }
+    let gzip = gzip.or(any).unwrap_or(0.0);
+    let deflate = deflate.or(any).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+fn encode(bytes: &[u8], encoding: Encoding) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        // HTTP's deflate is the zlib format, not a raw deflate stream
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+    }
+}
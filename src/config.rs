@@ -0,0 +1,431 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::auth::{AuthMode, DEFAULT_MIN_PASSWORD_CLASSES, DEFAULT_MIN_PASSWORD_LENGTH};
+use crate::client_ip::TrustedProxies;
+use crate::url_utils::HostList;
+use crate::git_manager::LockMode;
+use crate::webhooks::WebhookEvents;
+
+const DEFAULT_CONFIG_PATH: &str = "gitcloner.toml";
+const DEFAULT_REPOS_DIR: &str = "./repos";
+const DEFAULT_SYNC_CRON: &str = "0 0 2 * * *";
+// The range bcrypt accepts; every step doubles the time a hash takes
+const MIN_BCRYPT_COST: u32 = 4;
+const MAX_BCRYPT_COST: u32 = 31;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Server settings, from the defaults overridden by `gitcloner.toml` overridden by the environment.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    /// Directory the repositories are cloned into.
+    pub repos_dir: PathBuf,
+    pub bind_addr: SocketAddr,
+    /// Cron expression (with seconds) of the daily sync of all repositories.
+    pub sync_cron: String,
+    pub session_ttl: Duration,
+    pub sync_concurrency: usize,
+    /// Clones, syncs and bundle uploads requested through the API that may run at once.
+    pub max_concurrent_operations: usize,
+    /// Whether a clone or sync waits for another operation on the same repository or fails right away.
+    pub repo_lock_mode: LockMode,
+    pub shutdown_grace: Duration,
+    /// How long removed repositories stay restorable before they are purged.
+    pub trash_retention: Duration,
+    /// How long finished syncs stay in the history, `None` to keep them forever.
+    pub sync_history_retention: Option<Duration>,
+    /// Serve HTTPS instead of plain HTTP when set.
+    pub tls: Option<TlsConfig>,
+    pub auth_mode: AuthMode,
+    /// Work factor of new password hashes; lower ones are upgraded at login.
+    pub bcrypt_cost: u32,
+    pub min_password_length: usize,
+    /// How many of lowercase letters, uppercase letters, digits and symbols a password must mix.
+    pub min_password_classes: usize,
+    /// Download cap of clones and syncs in KiB/s for repositories without their own.
+    pub fetch_rate_limit_kbps: Option<u64>,
+    /// Size cap in MiB of repositories without their own, beyond which clones and syncs are stopped.
+    pub max_repo_size_mb: Option<u64>,
+    /// Run `git gc` after a scheduled sync once a repository has been synced this many times since its last one.
+    pub gc_after_syncs: Option<u64>,
+    /// Include requests for the web interface's files in the access log.
+    pub log_static_requests: bool,
+    /// Other origins browsers may call the API from.
+    pub cors_origins: CorsOrigins,
+    /// Proxies whose `X-Forwarded-For` is believed, `None` unless `TRUST_PROXY` is set.
+    pub trusted_proxies: Option<TrustedProxies>,
+    /// Hosts repositories may be cloned from, any public one when empty.
+    pub allowed_hosts: HostList,
+    /// Hosts repositories may never be cloned from, even when allowed.
+    pub denied_hosts: HostList,
+    /// Shortest time between two manual syncs of a repository, `None` for no limit.
+    pub manual_sync_min_interval: Option<Duration>,
+    /// Longest a clone or sync may run, `None` for no limit.
+    pub git_op_timeout: Option<Duration>,
+    /// Attempts per clone or fetch when the connection fails.
+    pub git_retry_attempts: u32,
+    /// Pause before the first retry, doubled for each further one.
+    pub git_retry_backoff: Duration,
+    /// Free space in MiB that must be left on the repositories volume, 0 to skip the check.
+    pub min_free_disk_mb: u64,
+    pub max_bundle_size_bytes: u64,
+    /// git executable for what libgit2 can't do, such as bundles and `gc`.
+    pub git_binary: String,
+    /// Update a repository's URL when its host redirects it elsewhere.
+    pub follow_repo_moves: bool,
+    /// Download Git LFS objects with `git-lfs` after clones and syncs.
+    pub git_lfs_fetch: bool,
+    /// Key for SSH remotes of repositories without their own.
+    pub git_ssh_key: Option<PathBuf>,
+    pub git_ssh_passphrase: Option<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_events: WebhookEvents,
+    pub github_webhook_secret: Option<String>,
+    /// Bearer token required by `/api/metrics`, open without one.
+    pub metrics_token: Option<String>,
+    /// Failed logins within `login_failure_window` before a lockout, 0 to never lock out.
+    pub login_max_failures: u32,
+    pub login_failure_window: Duration,
+    pub login_lockout: Duration,
+    /// API requests a client may make per minute, 0 for no limit.
+    pub api_rate_limit_per_min: u64,
+    /// Clones and syncs a client may request per minute, 0 for no limit.
+    pub git_op_rate_limit_per_min: u64,
+    pub health_fresh_hours: f64,
+    pub health_stale_hours: f64,
+    pub health_weight_recency: f64,
+    pub health_weight_outcome: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Origins allowed to make cross-origin requests, besides the server's own which always is.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CorsOrigins {
+    /// Any origin, `*`. Meant for development.
+    Any,
+    /// Only these, normalized to `scheme://host[:port]`. Empty by default, for same-origin only.
+    Only(Vec<String>),
+}
+
+impl Default for CorsOrigins {
+    fn default() -> Self {
+        CorsOrigins::Only(Vec::new())
+    }
+}
+
+impl FromStr for CorsOrigins {
+    type Err = String;
+
+    /// Comma-separated origins, or `*` for any.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        let mut origins = Vec::new();
+        for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            if entry == "*" {
+                return Ok(CorsOrigins::Any);
+            }
+            let invalid = || format!("'{}' is not an origin like https://example.com", entry);
+            let url = url::Url::parse(entry).map_err(|_| invalid())?;
+            if !matches!(url.scheme(), "http" | "https")
+                || url.host().is_none()
+                || url.path() != "/"
+                || url.query().is_some()
+                || !url.username().is_empty()
+            {
+                return Err(invalid());
+            }
+            origins.push(url.origin().ascii_serialization());
+        }
+        Ok(CorsOrigins::Only(origins))
+    }
+}
+
+impl TryFrom<String> for CorsOrigins {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Contents of the configuration file, every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    database_url: Option<String>,
+    repos_dir: Option<PathBuf>,
+    bind_addr: Option<IpAddr>,
+    port: Option<u16>,
+    sync_cron: Option<String>,
+    session_ttl_secs: Option<u64>,
+    sync_concurrency: Option<usize>,
+    max_concurrent_operations: Option<usize>,
+    repo_lock_mode: Option<LockMode>,
+    shutdown_grace_secs: Option<u64>,
+    trash_retention_days: Option<u64>,
+    sync_history_retention_days: Option<u64>,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    bcrypt_cost: Option<u32>,
+    min_password_length: Option<usize>,
+    min_password_classes: Option<usize>,
+    fetch_rate_limit_kbps: Option<u64>,
+    max_repo_size_mb: Option<u64>,
+    gc_after_syncs: Option<u64>,
+    log_static_requests: Option<bool>,
+    cors_origins: Option<CorsOrigins>,
+    trust_proxy: Option<bool>,
+    trusted_proxies: Option<TrustedProxies>,
+    allowed_hosts: Option<HostList>,
+    denied_hosts: Option<HostList>,
+    manual_sync_min_interval_secs: Option<u64>,
+    git_op_timeout_secs: Option<u64>,
+    git_retry_attempts: Option<u32>,
+    git_retry_backoff_ms: Option<u64>,
+    min_free_disk_mb: Option<u64>,
+    max_bundle_size_bytes: Option<u64>,
+    git_binary: Option<String>,
+    follow_repo_moves: Option<bool>,
+    git_lfs_fetch: Option<bool>,
+    git_ssh_key: Option<PathBuf>,
+    webhook_url: Option<String>,
+    webhook_events: Option<WebhookEvents>,
+    login_max_failures: Option<u32>,
+    login_failure_window_secs: Option<u64>,
+    login_lockout_secs: Option<u64>,
+    api_rate_limit_per_min: Option<u64>,
+    git_op_rate_limit_per_min: Option<u64>,
+    health_fresh_hours: Option<f64>,
+    health_stale_hours: Option<f64>,
+    health_weight_recency: Option<f64>,
+    health_weight_outcome: Option<f64>,
+}
+
+impl Config {
+    /// Settings for code that isn't handed a `Config`. They are loaded and validated at startup, before this is used.
+    pub fn get() -> &'static Config {
+        Config::load().expect("the configuration is validated at startup")
+    }
+
+    /// Reads the configuration on first use; later calls return the same settings.
+    pub fn load() -> Result<&'static Config> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+        let config = Self::from_sources(read_file()?)?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    fn from_sources(file: FileConfig) -> Result<Self> {
+        let database_url = setting("DATABASE_URL", file.database_url)?
+            .unwrap_or_else(|| "sqlite:gitcloner.db".to_string());
+        let repos_dir = setting("REPOS_DIR", file.repos_dir)?
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_REPOS_DIR));
+
+        let ip = setting("BIND_ADDR", file.bind_addr)?.unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let port = setting("PORT", file.port)?.unwrap_or(3030);
+
+        let sync_cron = setting("SYNC_CRON", file.sync_cron)?.unwrap_or_else(|| DEFAULT_SYNC_CRON.to_string());
+        // The scheduler only reports that parsing failed, so check it here to say why
+        if let Err(e) = cron::Schedule::from_str(&sync_cron) {
+            bail!("SYNC_CRON '{}' is not a valid cron expression (sec min hour day month weekday): {}", sync_cron, e);
+        }
+        let session_ttl_secs = setting("SESSION_TTL_SECS", file.session_ttl_secs)?.unwrap_or(24 * 60 * 60);
+
+        let sync_concurrency = setting("SYNC_CONCURRENCY", file.sync_concurrency)?
+            .filter(|value| *value > 0)
+            .unwrap_or(4);
+        let max_concurrent_operations = setting("MAX_CONCURRENT_OPERATIONS", file.max_concurrent_operations)?
+            .filter(|value| *value > 0)
+            .unwrap_or(8);
+        let repo_lock_mode = setting("REPO_LOCK_MODE", file.repo_lock_mode)?.unwrap_or(LockMode::Wait);
+
+        let shutdown_grace = setting("SHUTDOWN_GRACE_SECS", file.shutdown_grace_secs)?.unwrap_or(30);
+        let trash_retention_days = setting("TRASH_RETENTION_DAYS", file.trash_retention_days)?.unwrap_or(7);
+        let sync_history_retention_days = setting("SYNC_HISTORY_RETENTION_DAYS", file.sync_history_retention_days)?.unwrap_or(90);
+
+        let cert_path = setting("TLS_CERT_PATH", file.tls_cert_path)?;
+        let key_path = setting("TLS_KEY_PATH", file.tls_key_path)?;
+        let tls = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => {
+                let tls = TlsConfig { cert_path, key_path };
+                // warp panics on unreadable files when binding, so check them up front
+                for path in [&tls.cert_path, &tls.key_path] {
+                    std::fs::metadata(path)
+                        .with_context(|| format!("Cannot read TLS file {}", path.display()))?;
+                }
+                Some(tls)
+            }
+            (None, None) => None,
+            _ => bail!("TLS_CERT_PATH and TLS_KEY_PATH must be set together"),
+        };
+
+        let auth_mode = AuthMode::from_env()?;
+
+        let bcrypt_cost = setting("BCRYPT_COST", file.bcrypt_cost)?.unwrap_or(bcrypt::DEFAULT_COST);
+        if !(MIN_BCRYPT_COST..=MAX_BCRYPT_COST).contains(&bcrypt_cost) {
+            bail!("BCRYPT_COST must be between {} and {}", MIN_BCRYPT_COST, MAX_BCRYPT_COST);
+        }
+        let min_password_length = setting("MIN_PASSWORD_LENGTH", file.min_password_length)?.unwrap_or(DEFAULT_MIN_PASSWORD_LENGTH);
+        let min_password_classes = setting("MIN_PASSWORD_CLASSES", file.min_password_classes)?.unwrap_or(DEFAULT_MIN_PASSWORD_CLASSES);
+        // No password could mix more than the four kinds of characters
+        if min_password_classes > 4 {
+            bail!("MIN_PASSWORD_CLASSES must be between 0 and 4");
+        }
+
+        let fetch_rate_limit_kbps = setting("FETCH_RATE_LIMIT_KBPS", file.fetch_rate_limit_kbps)?.filter(|kbps| *kbps > 0);
+        let max_repo_size_mb = setting("MAX_REPO_SIZE_MB", file.max_repo_size_mb)?.filter(|mb| *mb > 0);
+        let gc_after_syncs = setting("GC_AFTER_SYNCS", file.gc_after_syncs)?.filter(|syncs| *syncs > 0);
+        let log_static_requests = setting("LOG_STATIC_REQUESTS", file.log_static_requests)?.unwrap_or(true);
+        let cors_origins = setting("CORS_ORIGINS", file.cors_origins)?.unwrap_or_default();
+        let trusted_proxies = setting("TRUSTED_PROXIES", file.trusted_proxies)?.unwrap_or_default();
+        let trust_proxy = flag("TRUST_PROXY", file.trust_proxy)?.unwrap_or(false);
+        let allowed_hosts = setting("ALLOWED_HOSTS", file.allowed_hosts)?.unwrap_or_default();
+        let denied_hosts = setting("DENIED_HOSTS", file.denied_hosts)?.unwrap_or_default();
+
+        let manual_sync_min_interval_secs =
+            setting("MANUAL_SYNC_MIN_INTERVAL_SECONDS", file.manual_sync_min_interval_secs)?.unwrap_or(0);
+        let git_op_timeout_secs = setting("GIT_OP_TIMEOUT_SECS", file.git_op_timeout_secs)?.unwrap_or(3600);
+        let git_retry_attempts = setting("GIT_RETRY_ATTEMPTS", file.git_retry_attempts)?
+            .filter(|attempts| *attempts > 0)
+            .unwrap_or(3);
+        let git_retry_backoff_ms = setting("GIT_RETRY_BACKOFF_MS", file.git_retry_backoff_ms)?.unwrap_or(1000);
+        let min_free_disk_mb = setting("MIN_FREE_DISK_MB", file.min_free_disk_mb)?.unwrap_or(1024);
+        let max_bundle_size_bytes = setting("MAX_BUNDLE_SIZE_BYTES", file.max_bundle_size_bytes)?.unwrap_or(1024 * 1024 * 1024);
+        let git_binary = setting("GIT_BINARY", file.git_binary)?.unwrap_or_else(|| "git".to_string());
+        let follow_repo_moves = flag("FOLLOW_REPO_MOVES", file.follow_repo_moves)?.unwrap_or(false);
+        let git_lfs_fetch = flag("GIT_LFS_FETCH", file.git_lfs_fetch)?.unwrap_or(false);
+        let git_ssh_key = setting("GIT_SSH_KEY", file.git_ssh_key)?;
+        let webhook_url = setting("WEBHOOK_URL", file.webhook_url)?;
+        let webhook_events = setting("WEBHOOK_EVENTS", file.webhook_events)?.unwrap_or_default();
+
+        let login_max_failures = setting("LOGIN_MAX_FAILURES", file.login_max_failures)?.unwrap_or(5);
+        let login_failure_window_secs = setting("LOGIN_FAILURE_WINDOW_SECS", file.login_failure_window_secs)?.unwrap_or(15 * 60);
+        let login_lockout_secs = setting("LOGIN_LOCKOUT_SECS", file.login_lockout_secs)?.unwrap_or(15 * 60);
+        let api_rate_limit_per_min = setting("API_RATE_LIMIT_PER_MIN", file.api_rate_limit_per_min)?.unwrap_or(600);
+        let git_op_rate_limit_per_min = setting("GIT_OP_RATE_LIMIT_PER_MIN", file.git_op_rate_limit_per_min)?.unwrap_or(30);
+
+        let health_fresh_hours = health_setting("HEALTH_FRESH_HOURS", file.health_fresh_hours, 26.0)?;
+        let health_stale_hours = health_setting("HEALTH_STALE_HOURS", file.health_stale_hours, 72.0)?;
+        let health_weight_recency = health_setting("HEALTH_WEIGHT_RECENCY", file.health_weight_recency, 60.0)?;
+        let health_weight_outcome = health_setting("HEALTH_WEIGHT_OUTCOME", file.health_weight_outcome, 40.0)?;
+
+        Ok(Config {
+            database_url,
+            repos_dir,
+            bind_addr: SocketAddr::new(ip, port),
+            sync_cron,
+            session_ttl: Duration::from_secs(session_ttl_secs),
+            sync_concurrency,
+            max_concurrent_operations,
+            repo_lock_mode,
+            shutdown_grace: Duration::from_secs(shutdown_grace),
+            trash_retention: Duration::from_secs(trash_retention_days * 24 * 60 * 60),
+            sync_history_retention: (sync_history_retention_days > 0)
+                .then(|| Duration::from_secs(sync_history_retention_days * 24 * 60 * 60)),
+            tls,
+            auth_mode,
+            bcrypt_cost,
+            min_password_length,
+            min_password_classes,
+            fetch_rate_limit_kbps,
+            max_repo_size_mb,
+            gc_after_syncs,
+            log_static_requests,
+            cors_origins,
+            trusted_proxies: trust_proxy.then_some(trusted_proxies),
+            allowed_hosts,
+            denied_hosts,
+            manual_sync_min_interval: (manual_sync_min_interval_secs > 0)
+                .then(|| Duration::from_secs(manual_sync_min_interval_secs)),
+            git_op_timeout: (git_op_timeout_secs > 0).then(|| Duration::from_secs(git_op_timeout_secs)),
+            git_retry_attempts,
+            git_retry_backoff: Duration::from_millis(git_retry_backoff_ms),
+            min_free_disk_mb,
+            max_bundle_size_bytes,
+            git_binary,
+            follow_repo_moves,
+            git_lfs_fetch,
+            git_ssh_key,
+            // Secrets are never read from the file
+            git_ssh_passphrase: setting("GIT_SSH_PASSPHRASE", None)?,
+            webhook_url,
+            webhook_events,
+            github_webhook_secret: setting("GITHUB_WEBHOOK_SECRET", None)?,
+            metrics_token: setting("METRICS_TOKEN", None)?,
+            login_max_failures,
+            login_failure_window: Duration::from_secs(login_failure_window_secs),
+            login_lockout: Duration::from_secs(login_lockout_secs),
+            api_rate_limit_per_min,
+            git_op_rate_limit_per_min,
+            health_fresh_hours,
+            health_stale_hours,
+            health_weight_recency,
+            health_weight_outcome,
+        })
+    }
+}
+
+// `GITCLONER_CONFIG` must point at an existing file, the default path is optional
+fn read_file() -> Result<FileConfig> {
+    let (path, required) = match env::var("GITCLONER_CONFIG") {
+        Ok(path) if !path.is_empty() => (PathBuf::from(path), true),
+        _ => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+    };
+    if !required && !path.exists() {
+        return Ok(FileConfig::default());
+    }
+    parse_file(&path)
+}
+
+fn parse_file(path: &Path) -> Result<FileConfig> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read configuration file {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Invalid configuration file {}", path.display()))
+}
+
+// A non-empty environment variable wins over the value from the file
+fn setting<T>(name: &str, file_value: Option<T>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("{} '{}' is invalid: {}", name, value, e)),
+        _ => Ok(file_value),
+    }
+}
+
+// Like `setting`, also taking the `1` and `0` that switches were documented with
+fn flag(name: &str, file_value: Option<bool>) -> Result<Option<bool>> {
+    match env::var(name).as_deref() {
+        Ok("1") => Ok(Some(true)),
+        Ok("0") => Ok(Some(false)),
+        _ => setting(name, file_value),
+    }
+}
+
+fn health_setting(name: &str, file_value: Option<f64>, default: f64) -> Result<f64> {
+    let value = setting(name, file_value)?.unwrap_or(default);
+    if value.is_nan() || value < 0.0 {
+        bail!("{} must not be negative", name);
+    }
+    Ok(value)
+}
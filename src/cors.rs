@@ -0,0 +1,58 @@
+//! Cross-origin access to the API, restricted to the configured origins.
+
+use std::convert::Infallible;
+use warp::http::HeaderMap;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::CorsOrigins;
+use crate::request_log::REQUEST_ID_HEADER;
+
+/// Applies the CORS policy to `filter`. Requests from the server's own origin, such as those of the
+/// web interface, skip it: browsers send an `Origin` header with those too, which a strict allowlist
+/// would otherwise turn away. `filter` must have recovered from its rejections, so that a request
+/// taken by the same-origin branch never falls through to the other.
+pub fn wrap<F, R>(filter: F, origins: &CorsOrigins) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let cors = warp::cors()
+        .allow_headers(vec!["content-type", "authorization", REQUEST_ID_HEADER])
+        .expose_headers(vec![REQUEST_ID_HEADER])
+        .allow_methods(vec!["GET", "POST", "PUT", "DELETE", "OPTIONS"]);
+    // Browsers refuse credentials with a wildcard, and no site should get them by default anyway
+    let cors = match origins {
+        CorsOrigins::Any => cors.allow_any_origin(),
+        CorsOrigins::Only(origins) => cors.allow_origins(origins.iter().map(String::as_str)).allow_credentials(true),
+    };
+
+    same_origin().and(filter.clone()).or(filter.with(cors))
+}
+
+// Passes requests without an `Origin` header or with the one of the `Host` they were sent to
+fn same_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and_then(|headers: HeaderMap| async move {
+            if is_same_origin(&headers) {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+fn is_same_origin(headers: &HeaderMap) -> bool {
+    let Some(origin) = headers.get("origin") else {
+        return true;
+    };
+    let host = headers.get("host").and_then(|host| host.to_str().ok());
+    let origin_host = origin.to_str().ok().and_then(|origin| url::Url::parse(origin).ok()).map(|url| {
+        match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => String::new(),
+        }
+    });
+    matches!((host, origin_host), (Some(host), Some(origin_host)) if !origin_host.is_empty() && host.eq_ignore_ascii_case(&origin_host))
+}
@@ -0,0 +1,49 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+use std::env;
+
+const NONCE_LEN: usize = 12;
+
+lazy_static::lazy_static! {
+    // 256-bit key derived from CREDENTIALS_KEY; None when the variable is unset
+    static ref CIPHER_KEY: Option<[u8; 32]> = env::var("CREDENTIALS_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(|key| Sha256::digest(key.as_bytes()).into());
+}
+
+pub fn is_configured() -> bool {
+    CIPHER_KEY.is_some()
+}
+
+fn cipher() -> Result<Aes256Gcm> {
+    let key = CIPHER_KEY
+        .as_ref()
+        .ok_or_else(|| anyhow!("CREDENTIALS_KEY is not set, cannot store or read repository credentials"))?;
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key)))
+}
+
+/// Encrypts `plaintext`, returning the nonce followed by the ciphertext.
+pub fn encrypt(plaintext: &str) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher()?
+        .encrypt(&nonce, plaintext.as_bytes())
+        .map_err(|_| anyhow!("Failed to encrypt secret"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+pub fn decrypt(sealed: &[u8]) -> Result<String> {
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow!("Stored secret is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let plaintext = cipher()?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt secret, was CREDENTIALS_KEY changed?"))?;
+    Ok(String::from_utf8(plaintext)?)
+}
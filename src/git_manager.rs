@@ -1,195 +1,2809 @@
 use anyhow::{Result, anyhow};
-use git2::{Repository, FetchOptions, RemoteCallbacks};
-use std::path::PathBuf;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use git2::{BranchType, Cred, CredentialType, Delta, ObjectType, Oid, Repository, Tree, FetchOptions, FetchPrune, RemoteCallbacks, RemoteRedirect, SubmoduleUpdateOptions, WorktreeAddOptions, WorktreePruneOptions};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::sync::{watch, OwnedMutexGuard};
+use tokio::io::AsyncReadExt;
 use tokio::task;
-use tracing::{info, warn};
-use crate::models::Repository as RepoModel;
+use uuid::Uuid;
+use tracing::{error, info, info_span, warn};
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::url_utils::{check_host, extract_repo_name, RepoUrl};
+use crate::webhooks::{WebhookEvent, WEBHOOKS};
+use crate::models::{ArchiveFormat, AuditReport, BlobContent, CommitInfo, FileChange, FileChangeStatus, GarbageCollection, Operation, RefDifference, RepoStatus, Repository as RepoModel, RepositoryCredentials, RepositoryDiff, RepositoryStats, SyncPreview, TransferProgress, TreeEntry, TreeEntryKind, TreeListing, VerifyReport, Worktree};
+
+
+// Commit counts stop here so that huge histories don't hold up detail requests
+const COMMIT_COUNT_LIMIT: usize = 100_000;
+
+// `git fsck` reports every bad object, only this many are kept in a verification report
+const MAX_FSCK_PROBLEMS: usize = 50;
+
+// Read from `git archive` at a time while streaming a download
+const ARCHIVE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Files larger than this are described by the blob endpoint without their content
+const MAX_INLINE_BLOB_SIZE: usize = 1024 * 1024;
+
+// Diffs include the patch of further files only while it stays under this size
+const MAX_PATCH_SIZE: usize = 5 * 1024 * 1024;
+
+// Same refspec as `git clone --mirror`: every remote ref is copied under its own name
+const MIRROR_REFSPEC: &str = "+refs/*:refs/*";
+
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    pub branch: Option<String>,
+    pub credentials: Option<RepositoryCredentials>,
+    pub ssh_key_path: Option<String>,
+    /// Create a bare mirror clone instead of a checkout.
+    pub mirror: bool,
+    /// Check out submodules, including nested ones, after cloning.
+    pub recurse_submodules: bool,
+    /// Delete an existing directory at the clone's location instead of failing.
+    pub overwrite: bool,
+    /// Download cap in KiB/s, overriding `FETCH_RATE_LIMIT_KBPS`; 0 for none.
+    pub rate_limit_kbps: Option<u64>,
+    /// Size cap in MiB, overriding `MAX_REPO_SIZE_MB`; 0 for none.
+    pub max_size_mb: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub credentials: Option<RepositoryCredentials>,
+    pub worktrees: Vec<Worktree>,
+    /// Hard reset the branch to upstream even when it diverged or has local changes.
+    pub reset: bool,
+    /// Download cap in KiB/s, overriding `FETCH_RATE_LIMIT_KBPS`; 0 for none.
+    pub rate_limit_kbps: Option<u64>,
+    /// Size cap in MiB, overriding `MAX_REPO_SIZE_MB`; 0 for none.
+    pub max_size_mb: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GitError {
+    #[error("another operation is already running for this repository")]
+    Busy,
+    #[error("invalid bundle: {0}")]
+    InvalidBundle(String),
+    #[error("git operation timed out after {} seconds", .0.as_secs())]
+    Timeout(Duration),
+    #[error("not enough disk space: {} MB free, at least {} MB required", .available / MB, .required / MB)]
+    InsufficientSpace { available: u64, required: u64 },
+    #[error("operation was cancelled")]
+    Cancelled,
+    #[error("repository is larger than its size limit of {} MB", .limit / MB)]
+    TooLarge { limit: u64 },
+    #[error("no tree found for '{0}'")]
+    RefNotFound(String),
+    #[error("invalid path '{0}'")]
+    InvalidPath(String),
+    #[error("nothing found at '{0}'")]
+    PathNotFound(String),
+    #[error("'{path}' is not a {expected}")]
+    WrongEntryType { path: String, expected: &'static str },
+}
+
+const MB: u64 = 1024 * 1024;
+
+/// Repository status to record after a failed clone or sync.
+pub fn failure_status(error: &anyhow::Error) -> RepoStatus {
+    match error.downcast_ref::<GitError>() {
+        Some(GitError::Timeout(_)) => RepoStatus::Timeout,
+        Some(GitError::Cancelled) => RepoStatus::Cancelled,
+        Some(GitError::TooLarge { .. }) => RepoStatus::TooLarge,
+        _ => RepoStatus::Error,
+    }
+}
+
+/// Where a repository was cloned to.
+#[derive(Debug)]
+pub struct CloneOutcome {
+    /// Name derived from the URL, which is also the clone's directory relative to the base path.
+    pub name: String,
+    pub local_path: String,
+    /// The repository uses Git LFS but only pointer files were checked out.
+    pub lfs_pointers_only: bool,
+    /// An existing clone was taken over instead of cloning.
+    pub adopted: bool,
+}
+
+/// What a successful sync changed beyond the fetched refs.
+#[derive(Debug, Default)]
+pub struct SyncOutcome {
+    /// New remote URL when the repository was found to have moved and `FOLLOW_REPO_MOVES` is enabled. It has
+    /// been fetched from, but origin still points at the old one until the caller follows the move.
+    pub moved_to: Option<String>,
+    /// The repository uses Git LFS but only pointer files were checked out.
+    pub lfs_pointers_only: bool,
+    /// Upstream commits the branch gained, 0 for mirrors.
+    pub commits_advanced: usize,
+}
+
+/// Context of the error of a sync that fetched from the URL its repository moved to before a later step
+/// failed. The caller still follows the move.
+#[derive(Debug)]
+pub struct Moved(pub String);
+
+impl std::fmt::Display for Moved {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the repository moved to {}", self.0)
+    }
+}
+
+/// New URL of a repository whose sync failed after it found the repository had moved.
+pub fn moved_to(error: &anyhow::Error) -> Option<&str> {
+    error.downcast_ref::<Moved>().map(|moved| moved.0.as_str())
+}
+
+impl SyncOutcome {
+    /// Repository status to record after the sync.
+    pub fn status(&self) -> RepoStatus {
+        if self.lfs_pointers_only {
+            RepoStatus::LfsPointersOnly
+        } else {
+            RepoStatus::Synced
+        }
+    }
+}
+
+/// What a clone or sync does when another operation is already running for the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    /// Wait for the running operation to finish (the default).
+    Wait,
+    /// Fail right away with `GitError::Busy`.
+    Reject,
+}
+
+impl std::str::FromStr for LockMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "wait" => Ok(LockMode::Wait),
+            "reject" => Ok(LockMode::Reject),
+            _ => Err("expected 'wait' or 'reject'".to_string()),
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct GitManager {
     base_path: PathBuf,
+    operations: Arc<Mutex<HashMap<String, RunningOperation>>>, // url -> running operation
+    // One lock per clone directory, held for the whole operation so two never touch it at once
+    locks: Arc<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
+    lock_mode: LockMode,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: usize,
+    op_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    min_free_space: u64,
+    default_rate_limit_kbps: Option<u64>,
+    default_max_size_mb: Option<u64>,
+    gc_after_syncs: Option<u64>,
+    follow_repo_moves: bool,
+    tools: Arc<GitTools>,
+}
+
+// How git itself is run and authenticated, copied into the blocking tasks that need it
+#[derive(Debug, Default)]
+struct GitTools {
+    /// For what libgit2 can't do, such as bundles and `gc`.
+    binary: String,
+    /// Key for SSH remotes of repositories without their own.
+    ssh_key: Option<PathBuf>,
+    ssh_passphrase: Option<String>,
+    lfs_fetch: bool,
+}
+
+/// How often network operations are retried after connection-level failures.
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    attempts: u32,
+    initial_backoff: Duration,
+}
+
+/// A reserved slot for a request-driven git operation, released when dropped.
+pub struct OperationSlot {
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for OperationSlot {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct RunningOperation {
+    operation: Operation,
+    progress: Arc<watch::Sender<TransferProgress>>,
+    /// Set to abort a clone or sync at its next transfer callback.
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Marks an operation as running and holds the repository's lock for as long as it is alive.
+/// Dropping it also closes the progress channel, which ends any progress streams subscribed to the operation.
+struct OperationGuard {
+    operations: Arc<Mutex<HashMap<String, RunningOperation>>>,
+    url: String,
+    progress: Arc<watch::Sender<TransferProgress>>,
+    cancelled: Arc<AtomicBool>,
+    /// Entered by the blocking task so every log line of the operation names the repository and operation.
+    span: tracing::Span,
+    // Released after `drop` has removed the operation, so the next one can register its own
+    _lock: OwnedMutexGuard<()>,
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        self.operations.lock().unwrap().remove(&self.url);
+    }
 }
 
 impl GitManager {
-    pub async fn new(base_path: String) -> Result<Self> {
-        let path = PathBuf::from(base_path);
-        if !path.exists() {
-            fs::create_dir_all(&path)?;
+    pub async fn new(config: &Config) -> Result<Self> {
+        let path = &config.repos_dir;
+        fs::create_dir_all(path).map_err(|e| anyhow!("Cannot create repositories directory {}: {}", path.display(), e))?;
+        // Local paths are built from the canonical form, so they can be checked against it before deleting
+        let base_path = fs::canonicalize(path).map_err(|e| anyhow!("Cannot resolve repositories directory {}: {}", path.display(), e))?;
+        ensure_writable(&base_path)?;
+        info!("Repositories directory: {}", base_path.display());
+        Ok(GitManager {
+            base_path,
+            operations: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            lock_mode: config.repo_lock_mode,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: config.max_concurrent_operations,
+            op_timeout: config.git_op_timeout,
+            retry: RetryPolicy {
+                attempts: config.git_retry_attempts,
+                initial_backoff: config.git_retry_backoff,
+            },
+            min_free_space: config.min_free_disk_mb.saturating_mul(MB),
+            default_rate_limit_kbps: config.fetch_rate_limit_kbps,
+            default_max_size_mb: config.max_repo_size_mb,
+            gc_after_syncs: config.gc_after_syncs,
+            follow_repo_moves: config.follow_repo_moves,
+            tools: Arc::new(GitTools {
+                binary: config.git_binary.clone(),
+                ssh_key: config.git_ssh_key.clone(),
+                ssh_passphrase: config.git_ssh_passphrase.clone(),
+                lfs_fetch: config.git_lfs_fetch,
+            }),
+        })
+    }
+
+    /// Logs a warning whenever free space on the repositories volume is below `MIN_FREE_DISK_MB`.
+    pub fn spawn_disk_monitor(&self, interval: Duration) {
+        if self.min_free_space == 0 {
+            return;
+        }
+        let base_path = self.base_path.clone();
+        let min_free_space = self.min_free_space;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = check_free_space(&base_path, min_free_space) {
+                    warn!("Repositories directory {}: {}", base_path.display(), e);
+                }
+            }
+        });
+    }
+
+    /// Reserves one of the `MAX_CONCURRENT_OPERATIONS` slots for an expensive request, or returns
+    /// `None` when all of them are taken so the caller can fail fast instead of queueing.
+    pub fn try_acquire_slot(&self) -> Option<OperationSlot> {
+        self.in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                (current < self.max_in_flight).then_some(current + 1)
+            })
+            .ok()
+            .map(|_| OperationSlot { in_flight: self.in_flight.clone() })
+    }
+
+    /// Waits until no git operation is running or `deadline` passes, returning how many are still running.
+    pub async fn wait_idle(&self, deadline: tokio::time::Instant) -> usize {
+        loop {
+            let running = self.operations.lock().unwrap().len();
+            if running == 0 || tokio::time::Instant::now() >= deadline {
+                return running;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Current load as (request-driven operations in flight, their ceiling, all running git operations).
+    pub fn load(&self) -> (usize, usize, usize) {
+        (
+            self.in_flight.load(Ordering::SeqCst),
+            self.max_in_flight,
+            self.operations.lock().unwrap().len(),
+        )
+    }
+
+    /// Returns the operation currently running for a repository, if any.
+    pub fn current_operation(&self, url: &str) -> Option<Operation> {
+        self.operations.lock().unwrap().get(url).map(|running| running.operation)
+    }
+
+    /// Subscribes to the transfer progress of the operation running for a repository, if any.
+    /// The receiver reports a closed channel once the operation finishes or fails.
+    pub fn subscribe_progress(&self, url: &str) -> Option<watch::Receiver<TransferProgress>> {
+        self.operations.lock().unwrap().get(url).map(|running| running.progress.subscribe())
+    }
+
+    /// Asks the clone or sync running for a repository to stop, returning the running operation.
+    /// Other operations can't be cancelled and keep running; `None` if nothing is running.
+    pub fn cancel_operation(&self, url: &str) -> Option<Operation> {
+        let operations = self.operations.lock().unwrap();
+        let running = operations.get(url)?;
+        if matches!(running.operation, Operation::Clone | Operation::Sync) {
+            info!("Cancelling {:?} of {}", running.operation, url);
+            running.cancelled.store(true, Ordering::SeqCst);
         }
-        Ok(GitManager { base_path: path })
+        Some(running.operation)
+    }
+
+    // Takes the lock of the repository at `local_path`, then registers the operation under its URL.
+    // With `LockMode::Reject`, fails with `GitError::Busy` while another operation holds the lock.
+    async fn begin_operation(
+        &self,
+        url: &str,
+        local_path: &Path,
+        operation: Operation,
+        mode: LockMode,
+    ) -> Result<OperationGuard, GitError> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(local_path.to_path_buf())
+            .or_default()
+            .clone();
+        let lock = match mode {
+            LockMode::Wait => lock.lock_owned().await,
+            LockMode::Reject => lock.try_lock_owned().map_err(|_| GitError::Busy)?,
+        };
+        
+        let (sender, _) = watch::channel(TransferProgress {
+            operation,
+            received_objects: 0,
+            total_objects: 0,
+            indexed_objects: 0,
+            received_bytes: 0,
+        });
+        let progress = Arc::new(sender);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.operations.lock().unwrap().insert(url.to_string(), RunningOperation {
+            operation,
+            progress: progress.clone(),
+            cancelled: cancelled.clone(),
+        });
+        Ok(OperationGuard {
+            operations: self.operations.clone(),
+            url: url.to_string(),
+            progress,
+            cancelled,
+            span: info_span!("git_op", url = %url, op = operation.as_str()),
+            _lock: lock,
+        })
     }
 
-    pub async fn clone_repository(&self, url: &str) -> Result<String> {
+    pub async fn clone_repository(&self, url: &str, options: &CloneOptions) -> Result<CloneOutcome> {
+        let tools = self.tools.clone();
         let url = url.to_string();
+        let options = options.clone();
         let base_path = self.base_path.clone();
+        let (name, local_path) = self.clone_location(&url)?;
+        // Moved into the blocking task so it outlives a dropped request future
+        let guard = self.begin_operation(&url, &local_path, Operation::Clone, self.lock_mode).await?;
+        let progress = guard.progress.clone();
+        let cancelled = guard.cancelled.clone();
+        let op_timeout = self.op_timeout;
+        let deadline = op_timeout.map(|limit| std::time::Instant::now() + limit);
+        let retry = self.retry;
+        let webhook_url = url.clone();
+        let min_free_space = self.min_free_space;
+        let rate_limit = self.rate_limit(options.rate_limit_kbps);
+        let size_limit = self.max_size(options.max_size_mb).map(|max_bytes| SizeLimit { max_bytes, existing: 0 });
         
-        task::spawn_blocking(move || {
-            let repo_name = extract_repo_name(&url)?;
-            let local_path = base_path.join(&repo_name);
-            
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            check_free_space(&base_path, min_free_space)?;
             if local_path.exists() {
-                return Err(anyhow!("Repository already exists at {}", local_path.display()));
+                if !options.overwrite {
+                    return Err(if Repository::open(&local_path).is_ok() {
+                        anyhow!("A clone already exists at {}, sync it instead or add it with overwrite to clone it again", local_path.display())
+                    } else {
+                        anyhow!("{} exists but is not a git repository, add it with overwrite to replace it", local_path.display())
+                    });
+                }
+                remove_stale_clone(&base_path, &local_path)?;
+            }
+            if let Some(enclosing) = enclosing_clone(&base_path, &local_path) {
+                return Err(anyhow!("{} would be inside the clone at {}", local_path.display(), enclosing.display()));
             }
 
+            // Cloned next to its final place and renamed there once complete, so an interrupted clone never
+            // leaves a half-populated directory where the next attempt would clone
+            let temp_path = temp_clone_path(&local_path);
             info!("Cloning repository {} to {}", url, local_path.display());
             
             let mut callbacks = RemoteCallbacks::new();
-            callbacks.update_tips(|refname, a, b| {
-                if a.is_zero() {
-                    info!("Creating reference {}", refname);
-                } else {
-                    info!("Updating reference {} from {} to {}", refname, a, b);
-                }
-                true
-            });
-            
-            callbacks.pack_progress(|_stage: git2::PackBuilderStage, _transferred: usize, _total: usize| {
-                // Progress tracking callback
-            });
+            register_update_tips(&mut callbacks, cancelled.clone());
+            register_progress(&mut callbacks, progress.clone(), deadline, cancelled.clone(), rate_limit, size_limit);
+            register_credentials(&mut callbacks, options.credentials.clone(), options.ssh_key_path.as_deref(), &tools);
 
             let mut fetch_options = FetchOptions::new();
             fetch_options.remote_callbacks(callbacks);
 
             let mut builder = git2::build::RepoBuilder::new();
             builder.fetch_options(fetch_options);
+            if let Some(branch) = &options.branch {
+                builder.branch(branch);
+            }
+            if options.mirror {
+                builder.bare(true);
+                builder.remote_create(|repo, name, url| {
+                    let remote = repo.remote_with_fetch(name, url, MIRROR_REFSPEC)?;
+                    repo.config()?.set_bool(&format!("remote.{}.mirror", name), true)?;
+                    Ok(remote)
+                });
+            }
             
-            builder.clone(&url, &local_path)?;
+            let cloned = retry.run("clone", &url, deadline, || {
+                let result = builder.clone(&url, &temp_path);
+                // Don't leave a half-initialized directory behind (e.g. unknown branch), the next
+                // attempt starts over
+                if result.is_err() && temp_path.exists() {
+                    discard_clone(&base_path, &temp_path);
+                }
+                result
+            });
+            let cloned = match cloned {
+                Ok(cloned) => cloned,
+                Err(e) => {
+                    return Err(abort_error(&cancelled, deadline, op_timeout)
+                        .or_else(|| size_error(&progress, size_limit))
+                        .unwrap_or_else(|| e.into()))
+                }
+            };
+            let finished = if cancelled.load(Ordering::SeqCst) {
+                // Cancelled too late for the transfer callbacks to notice
+                Err(GitError::Cancelled.into())
+            } else if options.mirror {
+                finish_mirror_clone(&cloned, &options, &tools)
+            } else if options.recurse_submodules {
+                update_submodules(&cloned, options.credentials.as_ref(), options.ssh_key_path.as_deref(), &tools)
+            } else {
+                Ok(())
+            };
+            let lfs_pointers_only = finished.map(|_| fetch_lfs_objects(&cloned, &url, &tools));
+            drop(cloned);
+            let moved = lfs_pointers_only.and_then(|lfs_pointers_only| {
+                // Submodules and LFS objects can outlast the deadline, and a cancelled clone must not appear in place
+                if let Some(aborted) = abort_error(&cancelled, deadline, op_timeout) {
+                    return Err(aborted);
+                }
+                fs::rename(&temp_path, &local_path)?;
+                Ok(lfs_pointers_only)
+            });
+            let lfs_pointers_only = match moved {
+                Ok(lfs_pointers_only) => lfs_pointers_only,
+                Err(e) => {
+                    // The clone is not recorded when this fails, so don't leave it behind either
+                    discard_clone(&base_path, &temp_path);
+                    return Err(e);
+                }
+            };
             
-            Ok(local_path.to_string_lossy().to_string())
-        }).await?
+            Ok(CloneOutcome {
+                name,
+                local_path: local_path.to_string_lossy().to_string(),
+                lfs_pointers_only,
+                adopted: false,
+            })
+        });
+        // A clone stuck before any data arrives never reaches the progress callback; its thread
+        // removes the partial directory itself once libgit2 finally gives up
+        let result = join_with_timeout(task, op_timeout).await;
+        METRICS.record_clone(result.is_ok());
+        match &result {
+            Ok(outcome) => {
+                let status = if outcome.lfs_pointers_only { RepoStatus::LfsPointersOnly.as_str() } else { "cloned" };
+                WEBHOOKS.notify(WebhookEvent::CloneSucceeded, &webhook_url, status, None)
+            }
+            Err(e) => WEBHOOKS.notify(WebhookEvent::CloneFailed, &webhook_url, failure_status(e).as_str(), Some(e)),
+        }
+        result
+    }
+
+    /// Name of the repository at `url` and the path it is cloned to.
+    pub fn clone_location(&self, url: &str) -> Result<(String, PathBuf)> {
+        let name = extract_repo_name(url)?;
+        let local_path = self.base_path.join(&name);
+        Ok((name, local_path))
+    }
+
+    /// Takes over the clone already at the location `url` would be cloned to, e.g. after the database was restored
+    /// from a backup. `None` when there is nothing there; fails when the directory isn't a clone of `url`, or is a
+    /// checkout when a mirror was asked for or the other way around.
+    pub async fn adopt_repository(&self, url: &str, options: &CloneOptions) -> Result<Option<CloneOutcome>> {
+        let (name, local_path) = self.clone_location(url)?;
+        let guard = self.begin_operation(url, &local_path, Operation::Clone, self.lock_mode).await?;
+        let url = url.to_string();
+        let mirror = options.mirror;
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            if !local_path.exists() {
+                return Ok(None);
+            }
+            let repo = Repository::open(&local_path)
+                .map_err(|_| anyhow!("{} exists but is not a git repository, add it with overwrite to replace it", local_path.display()))?;
+            let origin = repo
+                .find_remote("origin")
+                .ok()
+                .and_then(|remote| remote.url().map(str::to_string))
+                .ok_or_else(|| anyhow!("The repository at {} has no origin to compare with {}", local_path.display(), url))?;
+            // Same remote however it is spelled, e.g. with credentials or without `.git`
+            if RepoUrl::parse(&origin)?.canonical_key() != RepoUrl::parse(&url)?.canonical_key() {
+                return Err(anyhow!("The repository at {} is a clone of {}, not {}", local_path.display(), origin, url));
+            }
+            if repo.is_bare() != mirror {
+                let (found, wanted) = if mirror { ("a checkout", "a mirror") } else { ("a bare repository", "a checkout") };
+                return Err(anyhow!("The repository at {} is {}, not {}", local_path.display(), found, wanted));
+            }
+
+            info!("Adopting the existing clone of {} at {}", url, local_path.display());
+            Ok(Some(CloneOutcome {
+                name,
+                local_path: local_path.to_string_lossy().to_string(),
+                lfs_pointers_only: false,
+                adopted: true,
+            }))
+        });
+        join_with_timeout(task, self.op_timeout).await
     }
 
-    pub async fn sync_repository(&self, repo: &RepoModel) -> Result<()> {
+    pub async fn sync_repository(&self, repo: &RepoModel, options: &SyncOptions) -> Result<SyncOutcome> {
+        let tools = self.tools.clone();
         let url = repo.url.clone();
+        let options = options.clone();
         let local_path = PathBuf::from(&repo.local_path);
+        let tracked_branch = repo.branch.clone();
+        let ssh_key_path = repo.ssh_key_path.clone();
+        let mirror = repo.mirror;
+        let recurse_submodules = repo.recurse_submodules;
+        let guard = self.begin_operation(&url, &local_path, Operation::Sync, self.lock_mode).await?;
+        let started = std::time::Instant::now();
+        let progress = guard.progress.clone();
+        let cancelled = guard.cancelled.clone();
+        let op_timeout = self.op_timeout;
+        let deadline = op_timeout.map(|limit| std::time::Instant::now() + limit);
+        let follow_repo_moves = self.follow_repo_moves;
+        let retry = self.retry;
+        let rate_limit = self.rate_limit(options.rate_limit_kbps);
+        let max_size = self.max_size(options.max_size_mb);
         
-        task::spawn_blocking(move || {
-            if !local_path.exists() {
-                return Err(anyhow!("Repository path does not exist: {}", local_path.display()));
-            }
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let mut moved_to = None;
+            let mut sync = || -> Result<SyncOutcome> {
+                if !local_path.exists() {
+                    return Err(anyhow!("Repository path does not exist: {}", local_path.display()));
+                }
+                let size_limit = measure_size_limit(&local_path, max_size)?;
 
-            info!("Syncing repository {} at {}", url, local_path.display());
+                info!("Syncing repository {} at {}", url, local_path.display());
             
-            let repo = Repository::open(&local_path)?;
+                let repo = Repository::open(&local_path)?;
             
-            // Get the remote (usually 'origin')
-            let mut remote = repo.find_remote("origin")?;
+                // Get the remote (usually 'origin')
+                let mut remote = repo.find_remote("origin")?;
             
-            // Create callbacks for progress tracking
-            let mut callbacks = RemoteCallbacks::new();
-            callbacks.update_tips(|refname, a, b| {
-                if a.is_zero() {
-                    info!("Creating reference {}", refname);
-                } else {
-                    info!("Updating reference {} from {} to {}", refname, a, b);
+                let refspecs = sync_refspecs(tracked_branch.as_deref(), &options.worktrees, mirror);
+            
+                // Fetch from remote without merging/overriding local changes
+                let mut outcome = SyncOutcome::default();
+                let mut fetch_options = sync_fetch_options(&options, ssh_key_path.as_deref(), &progress, deadline, &cancelled, rate_limit, size_limit, &tools);
+                let fetched = retry.run("fetch", &url, deadline, || remote.fetch(&refspecs, Some(&mut fetch_options), None));
+                if let Err(e) = fetched {
+                    if let Some(aborted) = abort_error(&cancelled, deadline, op_timeout).or_else(|| size_error(&progress, size_limit)) {
+                        return Err(aborted);
+                    }
+                    let Some(new_url) = detect_moved_url(&url) else {
+                        return Err(e.into());
+                    };
+                    if !follow_repo_moves {
+                        return Err(anyhow!(
+                            "{}; the repository appears to have moved to {}, update its URL or set FOLLOW_REPO_MOVES=1",
+                            e, new_url
+                        ));
+                    }
+                    check_move_target(&new_url).map_err(|denied| {
+                        anyhow!("{}; the repository appears to have moved to {}: {}", e, new_url, denied)
+                    })?;
+                    // Origin is only pointed at the new location by the caller, once it has been fetched from and
+                    // isn't tracked as another repository
+                    let mut moved = repo.remote_anonymous(&new_url)?;
+                    let mut fetch_options = sync_fetch_options(&options, ssh_key_path.as_deref(), &progress, deadline, &cancelled, rate_limit, size_limit, &tools);
+                    let fetched = retry.run("fetch", &new_url, deadline, || moved.fetch(&refspecs, Some(&mut fetch_options), None));
+                    if let Err(e) = fetched {
+                        return Err(abort_error(&cancelled, deadline, op_timeout)
+                            .or_else(|| size_error(&progress, size_limit))
+                            .unwrap_or_else(|| e.into()));
+                    }
+                    warn!("Repository {} has moved to {}", url, new_url);
+                    moved_to = Some(new_url.clone());
+                    outcome.moved_to = Some(new_url);
+                }
+                // Fetches that bring nothing new never call back, so check once more before touching the branches
+                if cancelled.load(Ordering::SeqCst) {
+                    return Err(GitError::Cancelled.into());
                 }
-                true
-            });
             
-            // Fetch from remote without merging/overriding local changes
-            let mut fetch_options = FetchOptions::new();
-            fetch_options.remote_callbacks(callbacks);
+                // A mirror has no working tree, the pruning fetch already made it an exact copy
+                if mirror {
+                    outcome.lfs_pointers_only = fetch_lfs_objects(&repo, &url, &tools);
+                    return Ok(outcome);
+                }
             
-            remote.fetch(&["refs/heads/*:refs/remotes/origin/*"], Some(&mut fetch_options), None)?;
+                if options.reset {
+                    // A detached HEAD is put back on the tracked branch, or else upstream's default branch
+                    let detached = repo.head_detached()?;
+                    let head_branch = if detached { None } else { repo.head()?.shorthand().map(|name| name.to_string()) };
+                    let default_branch = || {
+                        repo.find_reference("refs/remotes/origin/HEAD").ok()
+                            .and_then(|reference| reference.symbolic_target().map(|target| target.to_string()))
+                            .and_then(|target| target.strip_prefix("refs/remotes/origin/").map(|branch| branch.to_string()))
+                    };
+                    match tracked_branch.clone().or(head_branch.clone()).or_else(default_branch) {
+                        Some(branch_name) => {
+                            let checkout = detached || head_branch.as_deref() == Some(branch_name.as_str());
+                            outcome.commits_advanced = reset_branch(&repo, &branch_name, checkout, &url)?;
+                        }
+                        None => warn!("HEAD of {} is detached and no branch is known, nothing to reset", url),
+                    }
+                    if recurse_submodules {
+                        update_submodules(&repo, options.credentials.as_ref(), ssh_key_path.as_deref(), &tools)?;
+                    }
+                    outcome.lfs_pointers_only = fetch_lfs_objects(&repo, &url, &tools);
+                } else if !repo.statuses(None)?.is_empty() {
+                    warn!("Repository {} has local changes, skipping merge to preserve local history", url);
+                } else {
+                    // Get the current branch, or the tracked branch when one is configured
+                    let head_branch = repo.head()?.shorthand().map(|name| name.to_string());
+                    if let Some(branch_name) = tracked_branch.clone().or(head_branch.clone()) {
+                        let checkout = head_branch.as_deref() == Some(branch_name.as_str());
+                        outcome.commits_advanced = fast_forward_branch(&repo, &branch_name, checkout, &url)?;
+                    }
+                    if recurse_submodules {
+                        update_submodules(&repo, options.credentials.as_ref(), ssh_key_path.as_deref(), &tools)?;
+                    }
+                    outcome.lfs_pointers_only = fetch_lfs_objects(&repo, &url, &tools);
+                }
+            
+                for worktree in &options.worktrees {
+                    if let Err(e) = sync_worktree(worktree, &url) {
+                        warn!("Failed to update worktree {} of {}: {}", worktree.path, url, e);
+                    }
+                }
             
-            // Check if there are local changes
-            let statuses = repo.statuses(None)?;
-            if !statuses.is_empty() {
+                Ok(outcome)
+            };
+            let result = sync();
+            // The new location was fetched from, so the move stands even though a later step failed
+            match moved_to {
+                Some(new_url) => result.map_err(|e| e.context(Moved(new_url))),
+                None => result,
+            }
+        });
+        let result = join_with_timeout(task, op_timeout).await;
+        METRICS.record_sync(result.is_ok(), started.elapsed());
+        match &result {
+            Ok(outcome) => WEBHOOKS.notify(WebhookEvent::SyncSucceeded, &repo.url, outcome.status().as_str(), None),
+            Err(e) => WEBHOOKS.notify(WebhookEvent::SyncFailed, &repo.url, failure_status(e).as_str(), Some(e)),
+        }
+        result
+    }
+
+    // The repository's own limit wins over the default, 0 means none
+    fn rate_limit(&self, kbps: Option<u64>) -> Option<Throttle> {
+        kbps.or(self.default_rate_limit_kbps)
+            .filter(|kbps| *kbps > 0)
+            .map(|kbps| Throttle { bytes_per_sec: kbps * 1024 })
+    }
+
+    // Same precedence as the rate limit, in bytes
+    fn max_size(&self, mb: Option<u64>) -> Option<u64> {
+        mb.or(self.default_max_size_mb)
+            .filter(|mb| *mb > 0)
+            .map(|mb| mb.saturating_mul(MB))
+    }
+
+    /// Fetches like a sync, but only updates the remote-tracking refs, and reports what the sync would then do to the
+    /// branch. Mirrors are not supported since their fetch overwrites every ref.
+    pub async fn preview_sync(&self, repo: &RepoModel, options: &SyncOptions) -> Result<SyncPreview> {
+        let tools = self.tools.clone();
+        if repo.mirror {
+            return Err(anyhow!("Mirrors can't be previewed, audit them instead"));
+        }
+        let url = repo.url.clone();
+        let options = options.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let tracked_branch = repo.branch.clone();
+        let ssh_key_path = repo.ssh_key_path.clone();
+        let guard = self.begin_operation(&url, &local_path, Operation::SyncPreview, LockMode::Reject).await?;
+        let progress = guard.progress.clone();
+        let cancelled = guard.cancelled.clone();
+        let op_timeout = self.op_timeout;
+        let deadline = op_timeout.map(|limit| std::time::Instant::now() + limit);
+        let retry = self.retry;
+        let rate_limit = self.rate_limit(options.rate_limit_kbps);
+        let max_size = self.max_size(options.max_size_mb);
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let size_limit = measure_size_limit(&local_path, max_size)?;
+            let repo = Repository::open(&local_path)?;
+            let known_branches = remote_branches(&repo)?;
+
+            info!("Fetching {} to preview a sync", url);
+            let mut remote = repo.find_remote("origin")?;
+            let refspecs = sync_refspecs(tracked_branch.as_deref(), &options.worktrees, false);
+            let mut fetch_options = sync_fetch_options(&options, ssh_key_path.as_deref(), &progress, deadline, &cancelled, rate_limit, size_limit, &tools);
+            let fetched = retry.run("fetch", &url, deadline, || remote.fetch(&refspecs, Some(&mut fetch_options), None));
+            if let Err(e) = fetched {
+                return Err(abort_error(&cancelled, deadline, op_timeout)
+                    .or_else(|| size_error(&progress, size_limit))
+                    .unwrap_or_else(|| e.into()));
+            }
+
+            let mut preview = SyncPreview {
+                new_branches: remote_branches(&repo)?.difference(&known_branches).cloned().collect(),
+                local_changes: !repo.statuses(None)?.is_empty(),
+                ..SyncPreview::default()
+            };
+            let head_branch = repo.head()?.shorthand().map(|name| name.to_string());
+            preview.branch = tracked_branch.or(head_branch);
+            if let Some(branch_name) = &preview.branch {
+                if let Some(comparison) = compare_branch(&repo, branch_name)? {
+                    preview.ahead = comparison.ahead;
+                    preview.behind = comparison.behind;
+                    preview.would_fast_forward = comparison.can_fast_forward() && !preview.local_changes;
+                    preview.diverged = comparison.diverged();
+                }
+            }
+            Ok(preview)
+        });
+        join_with_timeout(task, op_timeout).await
+    }
+
+    /// Lists the refs of a remote to make sure it can be reached with the given credentials.
+    pub async fn check_remote(&self, url: &str, credentials: Option<RepositoryCredentials>, ssh_key_path: Option<&str>) -> Result<()> {
+        let tools = self.tools.clone();
+        let url = url.to_string();
+        let ssh_key_path = ssh_key_path.map(str::to_string);
+        let task = task::spawn_blocking(move || {
+            let mut remote = git2::Remote::create_detached(url.as_str())?;
+            let mut callbacks = RemoteCallbacks::new();
+            register_credentials(&mut callbacks, credentials, ssh_key_path.as_deref(), &tools);
+            let connection = remote.connect_auth(git2::Direction::Fetch, Some(callbacks), None)?;
+            connection.list()?;
+            Ok(())
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Points `origin` of a clone at `new_url`. Clones missing on disk are skipped, the next
+    /// sync reports them.
+    pub async fn set_remote_url(&self, repo: &RepoModel, new_url: &str) -> Result<()> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let new_url = new_url.to_string();
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::ChangeUrl, LockMode::Reject).await?;
+
+        task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            if !local_path.exists() {
+                warn!("{} does not exist, only the recorded URL changes", local_path.display());
+                return Ok(());
+            }
+            Repository::open(&local_path)?.remote_set_url("origin", &new_url)?;
+            info!("Pointed origin of {} at {}", local_path.display(), new_url);
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Fresh path for staging an uploaded bundle before it is applied.
+    pub async fn bundle_upload_path(&self) -> Result<PathBuf> {
+        let uploads = self.base_path.join(".uploads");
+        tokio::fs::create_dir_all(&uploads).await?;
+        Ok(uploads.join(format!("{}.bundle", uuid::Uuid::new_v4())))
+    }
+
+    /// Verifies a git bundle against a local clone and fetches its branches and tags into it,
+    /// fast-forwarding the checked-out branch like a regular sync.
+    pub async fn apply_bundle(&self, repo: &RepoModel, bundle_path: &Path) -> Result<()> {
+        let tools = self.tools.clone();
+        let url = repo.url.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let mirror = repo.mirror;
+        let bundle_path = fs::canonicalize(bundle_path)?;
+        let guard = self.begin_operation(&url, &local_path, Operation::Bundle, LockMode::Reject).await?;
+
+        task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            if !local_path.exists() {
+                return Err(anyhow!("Repository path does not exist: {}", local_path.display()));
+            }
+
+            // libgit2 cannot read bundles, so verification and fetch go through the git binary
+            let verify = Command::new(&tools.binary)
+                .current_dir(&local_path)
+                .args(["bundle", "verify"])
+                .arg(&bundle_path)
+                .output()?;
+            if !verify.status.success() {
+                return Err(GitError::InvalidBundle(String::from_utf8_lossy(&verify.stderr).trim().to_string()).into());
+            }
+
+            info!("Fetching bundle into {} at {}", url, local_path.display());
+            let branches = if mirror { "+refs/heads/*:refs/heads/*" } else { "+refs/heads/*:refs/remotes/origin/*" };
+            let fetch = Command::new(&tools.binary)
+                .current_dir(&local_path)
+                .arg("fetch")
+                .arg(&bundle_path)
+                .args([branches, "+refs/tags/*:refs/tags/*"])
+                .output()?;
+            if !fetch.status.success() {
+                return Err(anyhow!("git fetch from bundle failed: {}", String::from_utf8_lossy(&fetch.stderr).trim()));
+            }
+            if mirror {
+                return Ok(());
+            }
+
+            let repo = Repository::open(&local_path)?;
+            if !repo.statuses(None)?.is_empty() {
                 warn!("Repository {} has local changes, skipping merge to preserve local history", url);
                 return Ok(());
             }
-            
-            // Get the current branch
-            let head = repo.head()?;
-            if let Some(branch_name) = head.shorthand() {
-                // Try to fast-forward merge if possible
-                let remote_branch_name = format!("origin/{}", branch_name);
-                if let Ok(remote_ref) = repo.find_reference(&format!("refs/remotes/{}", remote_branch_name)) {
-                    let remote_commit = remote_ref.peel_to_commit()?;
-                    let local_commit = head.peel_to_commit()?;
-                    
-                    // Check if we can fast-forward
-                    let (ahead, behind) = repo.graph_ahead_behind(local_commit.id(), remote_commit.id())?;
-                    
-                    if ahead == 0 && behind > 0 {
-                        // We can fast-forward
-                        info!("Fast-forwarding {} commits in {}", behind, url);
-                        let mut reference = repo.find_reference(&format!("refs/heads/{}", branch_name))?;
-                        reference.set_target(remote_commit.id(), "Fast-forward merge")?;
-                        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
-                    } else if ahead > 0 && behind > 0 {
-                        warn!("Repository {} has diverged from remote, skipping merge to preserve local history", url);
-                    } else {
-                        info!("Repository {} is up to date", url);
-                    }
-                }
+            if let Some(branch_name) = repo.head()?.shorthand().map(|name| name.to_string()) {
+                fast_forward_branch(&repo, &branch_name, true, &url)?;
             }
-            
             Ok(())
         }).await?
     }
-}
 
-fn extract_repo_name(url: &str) -> Result<String> {
-    let url = url.trim_end_matches('/');
-    
-    // Parse the URL to extract host, org, and repo name
-    let parsed_url = if url.starts_with("http://") || url.starts_with("https://") {
-        // HTTP/HTTPS URL
-        let without_protocol = url.split("://").nth(1)
-            .ok_or_else(|| anyhow!("Invalid URL format"))?;
-        
-        let parts: Vec<&str> = without_protocol.split('/').collect();
-        if parts.len() < 3 {
-            return Err(anyhow!("Invalid repository URL format"));
-        }
-        
-        let host = parts[0];
-        let org = parts[1];
-        let repo = parts[2].trim_end_matches(".git");
-        
-        // Create fully qualified name: host/org/repo
-        format!("{}/{}/{}", host, org, repo)
-    } else if url.contains("@") {
-        // SSH URL like git@github.com:user/repo.git
-        let parts: Vec<&str> = url.split('@').collect();
-        if parts.len() != 2 {
-            return Err(anyhow!("Invalid SSH URL format"));
-        }
-        
-        let host_and_path = parts[1];
-        let colon_split: Vec<&str> = host_and_path.split(':').collect();
-        if colon_split.len() != 2 {
-            return Err(anyhow!("Invalid SSH URL format"));
-        }
-        
-        let host = colon_split[0];
-        let path = colon_split[1];
-        let path_parts: Vec<&str> = path.split('/').collect();
-        
-        if path_parts.len() == 2 {
-            // org/repo format
-            let org = path_parts[0];
-            let repo = path_parts[1].trim_end_matches(".git");
-            format!("{}/{}/{}", host, org, repo)
-        } else if path_parts.len() == 1 {
-            // just repo format
-            let repo = path_parts[0].trim_end_matches(".git");
-            format!("{}/{}", host, repo)
-        } else {
-            return Err(anyhow!("Invalid repository path format"));
-        }
-    } else {
-        return Err(anyhow!("Unsupported URL format"));
-    };
-    
-    if parsed_url.is_empty() {
-        return Err(anyhow!("Could not extract repository name from URL"));
+    /// Compares the branches and tags advertised upstream (like `git ls-remote`) with the local mirror.
+    /// When the repository tracks a single branch, only that branch and worktree branches are expected.
+    pub async fn audit_repository(&self, repo: &RepoModel, options: &SyncOptions) -> Result<AuditReport> {
+        let tools = self.tools.clone();
+        let url = repo.url.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let ssh_key_path = repo.ssh_key_path.clone();
+        let tracked_branches: Option<Vec<String>> = repo.branch.clone().map(|branch| {
+            std::iter::once(branch)
+                .chain(options.worktrees.iter().map(|worktree| worktree.branch.clone()))
+                .collect()
+        });
+        let credentials = options.credentials.clone();
+        // Mirrors keep upstream branches under their own names instead of as remote-tracking refs
+        let branch_prefix = if repo.mirror { "refs/heads/" } else { "refs/remotes/origin/" };
+        let guard = self.begin_operation(&url, &local_path, Operation::Audit, LockMode::Reject).await?;
+        let cancelled = guard.cancelled.clone();
+        let op_timeout = self.op_timeout;
+        let deadline = op_timeout.map(|limit| std::time::Instant::now() + limit);
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let repo = Repository::open(&local_path)?;
+            let expected = |name: &str| -> Option<String> {
+                if let Some(branch) = name.strip_prefix("refs/heads/") {
+                    let tracked = tracked_branches.as_ref().is_none_or(|branches| branches.iter().any(|b| b == branch));
+                    tracked.then(|| format!("{}{}", branch_prefix, branch))
+                } else if name.starts_with("refs/tags/") && !name.ends_with("^{}") {
+                    Some(name.to_string())
+                } else {
+                    None
+                }
+            };
+
+            info!("Auditing {} against {}", local_path.display(), url);
+            // local ref name -> (upstream ref name, upstream id)
+            let mut upstream = BTreeMap::new();
+            {
+                let mut remote = repo.find_remote("origin")?;
+                let mut callbacks = RemoteCallbacks::new();
+                register_connect_check(&mut callbacks, deadline, cancelled.clone());
+                register_credentials(&mut callbacks, credentials, ssh_key_path.as_deref(), &tools);
+                let connection = remote
+                    .connect_auth(git2::Direction::Fetch, Some(callbacks), None)
+                    .map_err(|e| abort_error(&cancelled, deadline, op_timeout).unwrap_or_else(|| e.into()))?;
+                for head in connection.list()? {
+                    if let Some(local_name) = expected(head.name()) {
+                        upstream.insert(local_name, (head.name().to_string(), head.oid()));
+                    }
+                }
+            }
+
+            let mut local = BTreeMap::new();
+            for glob in [format!("{}*", branch_prefix), "refs/tags/*".to_string()] {
+                for reference in repo.references_glob(&glob)? {
+                    let reference = reference?;
+                    let Some(name) = reference.name().map(|name| name.to_string()) else { continue };
+                    let upstream_name = match name.strip_prefix(branch_prefix) {
+                        Some("HEAD") => continue,
+                        Some(branch) => format!("refs/heads/{}", branch),
+                        None => name.clone(),
+                    };
+                    if expected(&upstream_name).is_none() {
+                        continue;
+                    }
+                    if let Some(id) = reference.resolve().ok().and_then(|resolved| resolved.target()) {
+                        local.insert(name, (upstream_name, id));
+                    }
+                }
+            }
+
+            let mut report = AuditReport::default();
+            for (local_name, (name, upstream_id)) in &upstream {
+                match local.get(local_name) {
+                    None => report.missing.push(RefDifference {
+                        name: name.clone(),
+                        upstream: Some(upstream_id.to_string()),
+                        local: None,
+                    }),
+                    Some((_, local_id)) if local_id != upstream_id => report.mismatched.push(RefDifference {
+                        name: name.clone(),
+                        upstream: Some(upstream_id.to_string()),
+                        local: Some(local_id.to_string()),
+                    }),
+                    Some(_) => {}
+                }
+            }
+            for (local_name, (name, local_id)) in &local {
+                if !upstream.contains_key(local_name) {
+                    report.extra.push(RefDifference {
+                        name: name.clone(),
+                        upstream: None,
+                        local: Some(local_id.to_string()),
+                    });
+                }
+            }
+            report.ok = report.missing.is_empty() && report.extra.is_empty() && report.mismatched.is_empty();
+            Ok(report)
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Checks that HEAD and the branches of a clone resolve to commits whose objects can be read,
+    /// and with `fsck` also runs `git fsck` over every object. Problems are reported rather than
+    /// returned as errors.
+    pub async fn verify_repository(&self, repo: &RepoModel, fsck: bool) -> Result<VerifyReport> {
+        let tools = self.tools.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::Verify, LockMode::Reject).await?;
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let mut problems = Vec::new();
+            let repo = match Repository::open(&local_path) {
+                Ok(repo) => repo,
+                Err(e) => {
+                    problems.push(format!("The clone can't be opened: {}", e.message()));
+                    return Ok(VerifyReport { ok: false, problems });
+                }
+            };
+
+            if !repo.is_empty().unwrap_or(false) {
+                match repo.head().and_then(|head| head.peel_to_commit()) {
+                    Ok(commit) => {
+                        if let Err(e) = commit.tree() {
+                            problems.push(format!("HEAD tree {} can't be read: {}", commit.tree_id(), e.message()));
+                        }
+                    }
+                    Err(e) => problems.push(format!("HEAD doesn't resolve to a commit: {}", e.message())),
+                }
+            }
+
+            for glob in ["refs/heads/*", "refs/remotes/*"] {
+                for reference in repo.references_glob(glob)? {
+                    let reference = match reference {
+                        Ok(reference) => reference,
+                        Err(e) => {
+                            problems.push(format!("Unreadable ref: {}", e.message()));
+                            continue;
+                        }
+                    };
+                    let name = reference.name().unwrap_or("(invalid name)").to_string();
+                    // Peeling reads the commit, so a missing one fails here
+                    if let Err(e) = reference.resolve().and_then(|resolved| resolved.peel_to_commit()) {
+                        problems.push(format!("{} doesn't resolve to a commit: {}", name, e.message()));
+                    }
+                }
+            }
+
+            if fsck {
+                info!("Running git fsck on {}", local_path.display());
+                let output = Command::new(&tools.binary)
+                    .current_dir(&local_path)
+                    .args(["fsck", "--no-progress", "--no-dangling"])
+                    .output()?;
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let complaints: Vec<&str> = stderr.lines().chain(stdout.lines()).map(str::trim).filter(|line| !line.is_empty()).collect();
+                    if complaints.is_empty() {
+                        problems.push(format!("git fsck failed with {}", output.status));
+                    }
+                    let more = complaints.len().saturating_sub(MAX_FSCK_PROBLEMS);
+                    problems.extend(complaints.into_iter().take(MAX_FSCK_PROBLEMS).map(|line| format!("git fsck: {}", line)));
+                    if more > 0 {
+                        problems.push(format!("git fsck: … and {} more", more));
+                    }
+                }
+            }
+
+            Ok(VerifyReport { ok: problems.is_empty(), problems })
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Returns the checked-out branch of a local clone, when available.
+    pub async fn current_branch(&self, local_path: &str) -> Option<String> {
+        let local_path = PathBuf::from(local_path);
+        task::spawn_blocking(move || {
+            Repository::open(&local_path)
+                .ok()
+                .and_then(|repo| repo.head().ok().and_then(|head| head.shorthand().map(|name| name.to_string())))
+        })
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Reads the commit HEAD points to, or `None` when the repository has no commits yet.
+    pub async fn head_commit_info(&self, local_path: &str) -> Option<CommitInfo> {
+        let local_path = PathBuf::from(local_path);
+        task::spawn_blocking(move || {
+            let repo = Repository::open(&local_path).ok()?;
+            let commit = repo.head().ok()?.peel_to_commit().ok()?;
+            let author = commit.author();
+            Some(CommitInfo {
+                sha: commit.id().to_string(),
+                author: author.name().unwrap_or_default().to_string(),
+                summary: commit.summary().unwrap_or_default().to_string(),
+                committed_at: chrono::DateTime::from_timestamp(commit.time().seconds(), 0).unwrap_or_default(),
+            })
+        })
+        .await
+        .unwrap_or(None)
+    }
+
+    /// Counts the branches, tags and commits (up to `COMMIT_COUNT_LIMIT`) of a local clone.
+    pub async fn repo_stats(&self, local_path: &str) -> Option<RepositoryStats> {
+        let local_path = PathBuf::from(local_path);
+        task::spawn_blocking(move || -> Result<RepositoryStats> {
+            let repo = Repository::open(&local_path)?;
+            // Mirrors keep upstream branches as local ones, checkouts as remote-tracking branches
+            let branch_type = if repo.is_bare() { BranchType::Local } else { BranchType::Remote };
+            let mut branches = Vec::new();
+            for branch in repo.branches(Some(branch_type))? {
+                let (branch, _) = branch?;
+                if let Some(name) = branch.name()? {
+                    let name = name.strip_prefix("origin/").unwrap_or(name);
+                    if name != "HEAD" {
+                        branches.push(name.to_string());
+                    }
+                }
+            }
+            branches.sort();
+
+            let tag_count = repo.tag_names(None)?.len();
+
+            let mut commit_count = 0;
+            let mut commit_count_capped = false;
+            if let Ok(head) = repo.head().and_then(|head| head.peel_to_commit()) {
+                let mut walk = repo.revwalk()?;
+                walk.push(head.id())?;
+                for commit in walk {
+                    commit?;
+                    if commit_count == COMMIT_COUNT_LIMIT {
+                        commit_count_capped = true;
+                        break;
+                    }
+                    commit_count += 1;
+                }
+            }
+
+            Ok(RepositoryStats {
+                branch_count: branches.len(),
+                tag_count,
+                commit_count,
+                commit_count_capped,
+                branches,
+            })
+        })
+        .await
+        .ok()
+        .and_then(Result::ok)
+    }
+
+    /// Runs `git gc` on a clone to pack loose objects and drop unreachable ones, measuring its size
+    /// before and after.
+    pub async fn gc_repository(&self, repo: &RepoModel) -> Result<GarbageCollection> {
+        let tools = self.tools.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::Gc, LockMode::Reject).await?;
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let size_before = dir_size(&local_path)?;
+            let gc = Command::new(&tools.binary)
+                .current_dir(&local_path)
+                .args(["gc", "--quiet"])
+                .output()?;
+            if !gc.status.success() {
+                return Err(anyhow!("git gc failed: {}", String::from_utf8_lossy(&gc.stderr).trim()));
+            }
+            let size_after = dir_size(&local_path)?;
+            info!("Collected garbage in {}, {} bytes reclaimed", local_path.display(), size_before.saturating_sub(size_after));
+            Ok(GarbageCollection {
+                size_before,
+                size_after,
+                reclaimed_bytes: size_before.saturating_sub(size_after),
+            })
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Streams an archive of the tree `reference` points at, as produced by `git archive`, without
+    /// holding the whole of it in memory. Fails with `GitError::RefNotFound` when the reference doesn't
+    /// resolve to a tree. Dropping the stream stops git.
+    pub async fn archive(&self, repo: &RepoModel, reference: &str, format: ArchiveFormat) -> Result<impl futures::Stream<Item = std::io::Result<Vec<u8>>>> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let reference = reference.to_string();
+        let resolve_path = local_path.clone();
+        let id = task::spawn_blocking(move || -> Result<Oid> {
+            let repo = Repository::open(&resolve_path)?;
+            // Archives of commits carry the commit time as the modification time of their files
+            let (id, _) = resolve_tree(&repo, &reference)?;
+            Ok(id)
+        })
+        .await??;
+
+        let mut child = tokio::process::Command::new(&self.tools.binary)
+            .current_dir(&local_path)
+            .arg("archive")
+            .arg(format!("--format={}", format.extension()))
+            .arg(id.to_string())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("git archive has no output"))?;
+
+        // Ends with an error instead of a truncated archive when git fails midway
+        Ok(futures::stream::unfold(Some((child, stdout)), |state| async move {
+            let (mut child, mut stdout) = state?;
+            let mut chunk = vec![0; ARCHIVE_CHUNK_SIZE];
+            match stdout.read(&mut chunk).await {
+                Ok(0) => match child.wait().await {
+                    Ok(status) if status.success() => None,
+                    Ok(status) => Some((Err(std::io::Error::other(format!("git archive failed with {}", status))), None)),
+                    Err(e) => Some((Err(e), None)),
+                },
+                Ok(read) => {
+                    chunk.truncate(read);
+                    Some((Ok(chunk), Some((child, stdout))))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }))
+    }
+
+    /// Lists the directory at `path` of the tree `reference` points at.
+    pub async fn list_tree(&self, repo: &RepoModel, reference: &str, path: &str) -> Result<TreeListing> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let reference = reference.to_string();
+        let path = tree_path(path)?;
+
+        let task = task::spawn_blocking(move || {
+            let repo = Repository::open(&local_path)?;
+            let (revision, root) = resolve_tree(&repo, &reference)?;
+            let tree = if path.is_empty() {
+                root
+            } else {
+                let entry = root.get_path(Path::new(&path)).map_err(|_| GitError::PathNotFound(path.clone()))?;
+                if entry.kind() != Some(ObjectType::Tree) {
+                    return Err(GitError::WrongEntryType { path, expected: "directory" }.into());
+                }
+                repo.find_tree(entry.id())?
+            };
+
+            let odb = repo.odb()?;
+            let mut entries: Vec<TreeEntry> = tree
+                .iter()
+                .map(|entry| {
+                    let kind = match entry.filemode() {
+                        0o040000 => TreeEntryKind::Directory,
+                        0o120000 => TreeEntryKind::Symlink,
+                        0o160000 => TreeEntryKind::Submodule,
+                        _ => TreeEntryKind::File,
+                    };
+                    // Read from the object header, without inflating the whole file
+                    let size = match kind {
+                        TreeEntryKind::File | TreeEntryKind::Symlink => odb.read_header(entry.id()).ok().map(|(size, _)| size as u64),
+                        _ => None,
+                    };
+                    TreeEntry {
+                        name: String::from_utf8_lossy(entry.name_bytes()).into_owned(),
+                        kind,
+                        size,
+                        mode: format!("{:06o}", entry.filemode()),
+                    }
+                })
+                .collect();
+            entries.sort_by(|a, b| {
+                (a.kind != TreeEntryKind::Directory)
+                    .cmp(&(b.kind != TreeEntryKind::Directory))
+                    .then_with(|| a.name.cmp(&b.name))
+            });
+
+            Ok(TreeListing { revision: revision.to_string(), path, entries })
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Reads the file at `path` of the tree `reference` points at. Files over `MAX_INLINE_BLOB_SIZE`
+    /// come back without their content.
+    pub async fn read_blob(&self, repo: &RepoModel, reference: &str, path: &str) -> Result<BlobContent> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let reference = reference.to_string();
+        let path = tree_path(path)?;
+        if path.is_empty() {
+            return Err(GitError::WrongEntryType { path: "/".to_string(), expected: "file" }.into());
+        }
+
+        let task = task::spawn_blocking(move || {
+            let repo = Repository::open(&local_path)?;
+            let (revision, root) = resolve_tree(&repo, &reference)?;
+            let entry = root.get_path(Path::new(&path)).map_err(|_| GitError::PathNotFound(path.clone()))?;
+            if entry.kind() != Some(ObjectType::Blob) {
+                return Err(GitError::WrongEntryType { path, expected: "file" }.into());
+            }
+
+            let (size, _) = repo.odb()?.read_header(entry.id())?;
+            let (binary, content) = if size > MAX_INLINE_BLOB_SIZE {
+                (None, None)
+            } else {
+                let blob = repo.find_blob(entry.id())?;
+                match std::str::from_utf8(blob.content()) {
+                    Ok(text) if !blob.is_binary() => (Some(false), Some(text.to_string())),
+                    _ => (Some(true), Some(BASE64.encode(blob.content()))),
+                }
+            };
+
+            Ok(BlobContent { revision: revision.to_string(), path, size: size as u64, binary, content })
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Compares the trees `from` and `to` point at, file by file, detecting renames. The unified
+    /// patch is only built when `with_patch` is set, and stops growing at `MAX_PATCH_SIZE`.
+    pub async fn diff(&self, repo: &RepoModel, from: &str, to: &str, with_patch: bool) -> Result<RepositoryDiff> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let from = from.to_string();
+        let to = to.to_string();
+
+        let task = task::spawn_blocking(move || {
+            let repo = Repository::open(&local_path)?;
+            let (from_id, from_tree) = resolve_tree(&repo, &from)?;
+            let (to_id, to_tree) = resolve_tree(&repo, &to)?;
+            let fast_forward = from_id == to_id || repo.graph_descendant_of(to_id, from_id).unwrap_or(false);
+            let mut result = RepositoryDiff {
+                from: from_id.to_string(),
+                to: to_id.to_string(),
+                fast_forward,
+                additions: 0,
+                deletions: 0,
+                files: Vec::new(),
+                patch: with_patch.then(String::new),
+                patch_truncated: false,
+            };
+            // Also covers the same ref given twice
+            if from_tree.id() == to_tree.id() {
+                return Ok(result);
+            }
+
+            let mut diff = repo.diff_tree_to_tree(Some(&from_tree), Some(&to_tree), None)?;
+            diff.find_similar(None)?;
+            for index in 0..diff.deltas().len() {
+                let file_patch = git2::Patch::from_diff(&diff, index)?;
+                let Some(delta) = file_patch.as_ref().map(|patch| patch.delta()).or_else(|| diff.get_delta(index)) else {
+                    continue;
+                };
+                let status = match delta.status() {
+                    Delta::Added => FileChangeStatus::Added,
+                    Delta::Deleted => FileChangeStatus::Deleted,
+                    Delta::Renamed => FileChangeStatus::Renamed,
+                    Delta::Copied => FileChangeStatus::Copied,
+                    Delta::Typechange => FileChangeStatus::TypeChanged,
+                    _ => FileChangeStatus::Modified,
+                };
+                let path_of = |file: git2::DiffFile| file.path().map(|path| path.to_string_lossy().into_owned()).unwrap_or_default();
+                let path = path_of(if status == FileChangeStatus::Deleted { delta.old_file() } else { delta.new_file() });
+                let old_path = matches!(status, FileChangeStatus::Renamed | FileChangeStatus::Copied).then(|| path_of(delta.old_file()));
+                let binary = delta.flags().is_binary();
+
+                let (additions, deletions) = match &file_patch {
+                    Some(patch) => {
+                        let (_, additions, deletions) = patch.line_stats()?;
+                        (additions, deletions)
+                    }
+                    None => (0, 0),
+                };
+                result.additions += additions;
+                result.deletions += deletions;
+                result.files.push(FileChange { path, old_path, status, additions, deletions, binary });
+
+                if let (Some(text), Some(mut patch)) = (result.patch.as_mut(), file_patch) {
+                    if !result.patch_truncated {
+                        let buf = patch.to_buf()?;
+                        if text.len() + buf.len() > MAX_PATCH_SIZE {
+                            result.patch_truncated = true;
+                        } else {
+                            text.push_str(&String::from_utf8_lossy(&buf));
+                        }
+                    }
+                }
+            }
+            Ok(result)
+        });
+        join_with_timeout(task, self.op_timeout).await
+    }
+
+    /// Whether a repository synced this many times since its last `git gc` is due for another, per `GC_AFTER_SYNCS`.
+    pub fn gc_due(&self, syncs_since_gc: i64) -> bool {
+        self.gc_after_syncs.is_some_and(|syncs| syncs_since_gc >= syncs as i64)
+    }
+
+    /// Whether a clone can still be opened, e.g. after the server stopped in the middle of a sync.
+    pub async fn clone_is_intact(&self, local_path: &str) -> bool {
+        let local_path = PathBuf::from(local_path);
+        task::spawn_blocking(move || {
+            let Ok(repo) = Repository::open(&local_path) else {
+                return false;
+            };
+            repo.is_empty().unwrap_or(false) || repo.head().and_then(|head| head.peel_to_commit()).is_ok()
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Deletes the temporary directories of clones that never completed because the server stopped
+    /// during them. Only safe before any clone has started.
+    pub async fn remove_abandoned_clones(&self) -> Result<Vec<PathBuf>> {
+        let base_path = self.base_path.clone();
+        task::spawn_blocking(move || {
+            let mut removed = Vec::new();
+            find_temp_clones(&base_path, &mut removed)?;
+            for path in &removed {
+                discard_clone(&base_path, path);
+            }
+            Ok(removed)
+        }).await?
+    }
+
+    /// Walks a local clone to add up its on-disk size, which can take a while for large repositories.
+    pub async fn disk_usage(&self, local_path: &str) -> Option<u64> {
+        let local_path = PathBuf::from(local_path);
+        task::spawn_blocking(move || dir_size(&local_path).ok()).await.ok().flatten()
+    }
+
+    /// Directory holding the extra worktrees of a repository, next to its main checkout.
+    pub fn worktrees_dir(local_path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.worktrees", local_path.trim_end_matches('/')))
+    }
+
+    // Removed repositories wait here, with their worktrees, until they are purged
+    fn trash_dir(&self, repo: &RepoModel) -> PathBuf {
+        self.base_path.join(".trash").join(repo.id.to_string())
+    }
+
+    /// Moves the checkout and worktrees of a removed repository into the trash.
+    pub async fn move_to_trash(&self, repo: &RepoModel) -> Result<()> {
+        let base_path = self.base_path.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let trash = self.trash_dir(repo);
+        // A sync still running in the checkout would carry on in the trash
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::Remove, LockMode::Reject).await?;
+
+        // Renames are cheap, but a slow disk shouldn't stall the workers serving other requests
+        task::spawn_blocking(move || {
+            let _guard = guard;
+            let local_path = within_base(&base_path, &local_path)?;
+            fs::create_dir_all(&trash)?;
+            for (from, to) in [
+                (local_path.clone(), trash.join("checkout")),
+                (Self::worktrees_dir(&local_path.to_string_lossy()), trash.join("worktrees")),
+            ] {
+                if from.try_exists()? {
+                    fs::rename(&from, &to)?;
+                }
+            }
+            remove_empty_parents(&base_path, &local_path);
+            info!("Moved {} to {}", local_path.display(), trash.display());
+            Ok(())
+        }).await?
+    }
+
+    /// Moves a removed repository back from the trash to its original location.
+    pub async fn restore_from_trash(&self, repo: &RepoModel) -> Result<()> {
+        let base_path = self.base_path.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let trash = self.trash_dir(repo);
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::Restore, LockMode::Reject).await?;
+
+        task::spawn_blocking(move || {
+            let _guard = guard;
+            let local_path = within_base(&base_path, &local_path)?;
+            if local_path.try_exists()? {
+                return Err(anyhow!("{} is already in use by another repository", local_path.display()));
+            }
+            if let Some(enclosing) = enclosing_clone(&base_path, &local_path) {
+                return Err(anyhow!("{} would be inside the clone at {}", local_path.display(), enclosing.display()));
+            }
+            if let Some(parent) = local_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let worktrees_dir = Self::worktrees_dir(&local_path.to_string_lossy());
+            for (from, to) in [
+                (trash.join("checkout"), local_path),
+                (trash.join("worktrees"), worktrees_dir),
+            ] {
+                if from.try_exists()? {
+                    fs::rename(&from, &to)?;
+                }
+            }
+            fs::remove_dir_all(&trash)?;
+            Ok(())
+        }).await?
+    }
+
+    /// Permanently deletes the trashed files of a removed repository, which can take a while for
+    /// large checkouts.
+    pub async fn purge_trash(&self, repo: &RepoModel) -> Result<()> {
+        let trash = self.trash_dir(repo);
+        task::spawn_blocking(move || {
+            if trash.try_exists()? {
+                fs::remove_dir_all(&trash)?;
+            }
+            Ok(())
+        }).await?
+    }
+
+    pub async fn add_worktree(&self, repo: &RepoModel, branch: &str, subdir: &str, credentials: Option<&RepositoryCredentials>) -> Result<String> {
+        let tools = self.tools.clone();
+        let subdir_path = Path::new(subdir);
+        if subdir.is_empty() || !subdir_path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(anyhow!("Worktree path must be a relative path without '..' components"));
+        }
+        if repo.mirror {
+            return Err(anyhow!("Mirror repositories have no working tree to add worktrees to"));
+        }
+
+        let url = repo.url.clone();
+        let local_path = PathBuf::from(&repo.local_path);
+        let worktree_path = Self::worktrees_dir(&repo.local_path).join(subdir_path);
+        let branch = branch.to_string();
+        let name = subdir.replace('/', "-");
+        let ssh_key_path = repo.ssh_key_path.clone();
+        let options = SyncOptions {
+            credentials: credentials.cloned(),
+            rate_limit_kbps: repo.fetch_rate_limit_kbps.map(|kbps| kbps as u64),
+            max_size_mb: repo.max_size_mb.map(|mb| mb as u64),
+            ..SyncOptions::default()
+        };
+        let guard = self.begin_operation(&url, &local_path, Operation::AddWorktree, LockMode::Reject).await?;
+        let progress = guard.progress.clone();
+        let cancelled = guard.cancelled.clone();
+        let op_timeout = self.op_timeout;
+        let deadline = op_timeout.map(|limit| std::time::Instant::now() + limit);
+        let rate_limit = self.rate_limit(options.rate_limit_kbps);
+        let max_size = self.max_size(options.max_size_mb);
+
+        let task = task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            if worktree_path.exists() {
+                return Err(anyhow!("Worktree path already exists: {}", worktree_path.display()));
+            }
+
+            let repo = Repository::open(&local_path)?;
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+            if repo.find_reference(&remote_ref).is_err() {
+                // Single-branch clones only track one branch, so fetch this one explicitly
+                let size_limit = measure_size_limit(&local_path, max_size)?;
+                let mut fetch_options = sync_fetch_options(&options, ssh_key_path.as_deref(), &progress, deadline, &cancelled, rate_limit, size_limit, &tools);
+                let refspec = format!("+refs/heads/{}:{}", branch, remote_ref);
+                if let Err(e) = repo.find_remote("origin")?.fetch(&[refspec.as_str()], Some(&mut fetch_options), None) {
+                    return Err(abort_error(&cancelled, deadline, op_timeout)
+                        .or_else(|| size_error(&progress, size_limit))
+                        .unwrap_or_else(|| e.into()));
+                }
+            }
+            let remote_commit = repo
+                .find_reference(&remote_ref)
+                .map_err(|_| anyhow!("Branch '{}' does not exist on the remote", branch))?
+                .peel_to_commit()?;
+
+            let local_branch = match repo.find_branch(&branch, BranchType::Local) {
+                Ok(local_branch) => local_branch,
+                Err(_) => repo.branch(&branch, &remote_commit, false)?,
+            };
+
+            if let Some(parent) = worktree_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            info!("Adding worktree for branch {} of {} at {}", branch, url, worktree_path.display());
+            let mut worktree_options = WorktreeAddOptions::new();
+            worktree_options.reference(Some(local_branch.get()));
+            repo.worktree(&name, &worktree_path, Some(&worktree_options))?;
+
+            Ok(worktree_path.to_string_lossy().to_string())
+        });
+        join_with_timeout(task, op_timeout).await
+    }
+
+    pub async fn remove_worktree(&self, repo: &RepoModel, worktree: &Worktree) -> Result<()> {
+        let local_path = PathBuf::from(&repo.local_path);
+        let worktree_path = PathBuf::from(&worktree.path);
+        let base_path = self.base_path.clone();
+        let guard = self.begin_operation(&repo.url, &local_path, Operation::RemoveWorktree, LockMode::Reject).await?;
+
+        task::spawn_blocking(move || {
+            let _guard = guard;
+            let _span = _guard.span.clone().entered();
+            let worktree_path = within_base(&base_path, &worktree_path)?;
+            // Prune the matching worktree, which removes its checkout and the files under .git/worktrees
+            let repo = Repository::open(&local_path)?;
+            let target = fs::canonicalize(&worktree_path).unwrap_or_else(|_| worktree_path.clone());
+            for name in repo.worktrees()?.iter().flatten() {
+                let candidate = repo.find_worktree(name)?;
+                let candidate_path = fs::canonicalize(candidate.path()).unwrap_or_else(|_| candidate.path().to_path_buf());
+                if candidate_path == target {
+                    candidate.prune(Some(WorktreePruneOptions::new().valid(true).working_tree(true)))?;
+                }
+            }
+
+            if worktree_path.exists() {
+                fs::remove_dir_all(&worktree_path)?;
+            }
+            Ok(())
+        }).await?
+    }
+}
+
+// Fails at startup rather than on the first clone when the directory is read-only
+fn ensure_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".write-test-{}", Uuid::new_v4()));
+    fs::write(&probe, b"").map_err(|e| anyhow!("Repositories directory {} is not writable: {}", dir.display(), e))?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+// Canonicalizes as much of a path as exists, so that paths stored relative to the working
+// directory compare against the canonical base path
+fn resolve_path(path: &Path) -> PathBuf {
+    for ancestor in path.ancestors() {
+        if let Ok(resolved) = fs::canonicalize(ancestor) {
+            return match path.strip_prefix(ancestor) {
+                Ok(rest) => resolved.join(rest),
+                Err(_) => resolved,
+            };
+        }
+    }
+    path.to_path_buf()
+}
+
+// Stored paths are only acted on once they resolve to somewhere below the repositories
+// directory, so a corrupted row can't move or delete anything else
+fn within_base(base_path: &Path, path: &Path) -> Result<PathBuf> {
+    let resolved = resolve_path(path);
+    let escapes = resolved.components().any(|component| component == Component::ParentDir);
+    if escapes || resolved == base_path || !resolved.starts_with(base_path) {
+        error!("Refusing to touch {}: it is outside of {}", path.display(), base_path.display());
+        return Err(anyhow!("{} is outside of the repository directory", path.display()));
+    }
+    Ok(resolved)
+}
+
+// A sibling of the clone's final directory, so renaming it into place never crosses volumes
+fn temp_clone_path(local_path: &Path) -> PathBuf {
+    let name = local_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    local_path.with_file_name(format!("{}.tmp-{}", name, Uuid::new_v4()))
+}
+
+// Clones are never nested and the trash and uploads hold no temporary clones, so those aren't searched
+fn find_temp_clones(dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_temp_clone = name.rsplit_once(".tmp-").is_some_and(|(_, id)| Uuid::parse_str(id).is_ok());
+        if is_temp_clone {
+            found.push(path);
+        } else if name != ".trash" && name != ".uploads" && !name.ends_with(".worktrees") && Repository::open(&path).is_err() {
+            find_temp_clones(&path, found)?;
+        }
+    }
+    Ok(())
+}
+
+// Deletes a clone that didn't complete, along with the directories only created for it
+fn discard_clone(base_path: &Path, path: &Path) {
+    if let Err(e) = fs::remove_dir_all(path) {
+        warn!("Failed to clean up {}: {}", path.display(), e);
+    }
+    remove_empty_parents(base_path, path);
+}
+
+// Subgroups nest (`group/sub` and `group/sub/project` can both be repositories), but a clone
+// must never end up inside the working tree of another one
+fn enclosing_clone(base_path: &Path, local_path: &Path) -> Option<PathBuf> {
+    local_path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| *dir != base_path && dir.starts_with(base_path))
+        .find(|dir| Repository::open(dir).is_ok())
+        .map(Path::to_path_buf)
+}
+
+// Only ever deletes below the base path, and never the clones of subgroup repositories that
+// live inside a group directory
+fn remove_stale_clone(base_path: &Path, local_path: &Path) -> Result<()> {
+    let target = within_base(base_path, local_path)?;
+    let is_clone = target.join(".git").exists() || Repository::open(&target).is_ok();
+    if !is_clone && contains_clone(&target) {
+        return Err(anyhow!("{} holds the clones of other repositories", local_path.display()));
+    }
+    warn!("Removing {} to clone it again", local_path.display());
+    fs::remove_dir_all(&target)?;
+    Ok(())
+}
+
+fn contains_clone(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        entry.file_type().is_ok_and(|file_type| file_type.is_dir()) && (Repository::open(&path).is_ok() || contains_clone(&path))
+    })
+}
+
+// Drops the host and group directories left empty once a clone is gone, so they can't block
+// a repository of the same name later
+fn remove_empty_parents(base_path: &Path, local_path: &Path) {
+    for dir in local_path.ancestors().skip(1).take_while(|dir| *dir != base_path && dir.starts_with(base_path)) {
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += dir_size(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+// Only fetch the tracked branch (plus any worktree branches) when one is configured
+fn sync_refspecs(tracked_branch: Option<&str>, worktrees: &[Worktree], mirror: bool) -> Vec<String> {
+    match tracked_branch {
+        _ if mirror => vec![MIRROR_REFSPEC.to_string()],
+        Some(branch) => std::iter::once(branch)
+            .chain(worktrees.iter().map(|worktree| worktree.branch.as_str()))
+            .map(|branch| format!("+refs/heads/{}:refs/remotes/origin/{}", branch, branch))
+            .collect(),
+        None => vec!["refs/heads/*:refs/remotes/origin/*".to_string()],
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sync_fetch_options(
+    options: &SyncOptions,
+    ssh_key_path: Option<&str>,
+    progress: &Arc<watch::Sender<TransferProgress>>,
+    deadline: Option<std::time::Instant>,
+    cancelled: &Arc<AtomicBool>,
+    rate_limit: Option<Throttle>,
+    size_limit: Option<SizeLimit>,
+    tools: &GitTools,
+) -> FetchOptions<'static> {
+    // Create callbacks for progress tracking
+    let mut callbacks = RemoteCallbacks::new();
+    register_update_tips(&mut callbacks, cancelled.clone());
+    register_progress(&mut callbacks, progress.clone(), deadline, cancelled.clone(), rate_limit, size_limit);
+    register_credentials(&mut callbacks, options.credentials.clone(), ssh_key_path, tools);
+    
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    // Redirects are handled explicitly by `detect_moved_url` so a moved repository is noticed
+    fetch_options.follow_redirects(RemoteRedirect::None);
+    // Drop refs of branches deleted upstream, which would otherwise linger forever (and keeps mirrors exact)
+    fetch_options.prune(FetchPrune::On);
+    fetch_options
+}
+
+// Also enforces the operation deadline, cancellation and size limit: returning false from the callback aborts the
+// transfer. Pausing the callback stops libgit2 from reading the connection, which is what keeps the download under a
+// rate limit
+fn register_progress(
+    callbacks: &mut RemoteCallbacks<'static>,
+    progress: Arc<watch::Sender<TransferProgress>>,
+    deadline: Option<std::time::Instant>,
+    cancelled: Arc<AtomicBool>,
+    rate_limit: Option<Throttle>,
+    size_limit: Option<SizeLimit>,
+) {
+    let started = std::time::Instant::now();
+    callbacks.transfer_progress(move |stats| {
+        if let Some(throttle) = &rate_limit {
+            let pause = throttle.pause(stats.received_bytes() as u64, started.elapsed());
+            if !pause.is_zero() {
+                std::thread::sleep(pause);
+            }
+        }
+        progress.send_modify(|current| {
+            current.received_objects = stats.received_objects();
+            current.total_objects = stats.total_objects();
+            current.indexed_objects = stats.indexed_objects();
+            current.received_bytes = stats.received_bytes();
+        });
+        !cancelled.load(Ordering::SeqCst)
+            && deadline.is_none_or(|deadline| std::time::Instant::now() < deadline)
+            && !size_limit.is_some_and(|limit| limit.exceeded(stats.received_bytes() as u64))
+    });
+}
+
+/// Keeps the average download rate of a transfer under a limit.
+#[derive(Debug, Clone, Copy)]
+struct Throttle {
+    bytes_per_sec: u64,
+}
+
+// Pauses are capped so cancellation and the deadline are still checked regularly
+const MAX_THROTTLE_PAUSE: Duration = Duration::from_secs(1);
+
+impl Throttle {
+    /// How long to wait after receiving `received` bytes in `elapsed` so the transfer is no faster than the limit.
+    fn pause(&self, received: u64, elapsed: Duration) -> Duration {
+        let allowed = Duration::from_secs_f64(received as f64 / self.bytes_per_sec as f64);
+        allowed.saturating_sub(elapsed).min(MAX_THROTTLE_PAUSE)
+    }
+}
+
+/// Stops a transfer that would take a repository past its size limit.
+#[derive(Debug, Clone, Copy)]
+struct SizeLimit {
+    max_bytes: u64,
+    /// Size of the clone on disk before the transfer started.
+    existing: u64,
+}
+
+impl SizeLimit {
+    /// Whether the repository outgrows the limit once `received` more bytes have been downloaded.
+    fn exceeded(&self, received: u64) -> bool {
+        self.existing.saturating_add(received) > self.max_bytes
+    }
+}
+
+// Fails right away when the clone at `local_path` has already outgrown `max_bytes`, e.g. after the limit was lowered
+fn measure_size_limit(local_path: &Path, max_bytes: Option<u64>) -> Result<Option<SizeLimit>> {
+    let Some(max_bytes) = max_bytes else {
+        return Ok(None);
+    };
+    let limit = SizeLimit { max_bytes, existing: dir_size(local_path)? };
+    if limit.exceeded(0) {
+        return Err(GitError::TooLarge { limit: max_bytes }.into());
+    }
+    Ok(Some(limit))
+}
+
+// The transfer was aborted because it went past the size limit
+fn size_error(progress: &watch::Sender<TransferProgress>, limit: Option<SizeLimit>) -> Option<anyhow::Error> {
+    let received = progress.borrow().received_bytes as u64;
+    limit
+        .filter(|limit| limit.exceeded(received))
+        .map(|limit| GitError::TooLarge { limit: limit.max_bytes }.into())
+}
+
+fn register_update_tips(callbacks: &mut RemoteCallbacks<'static>, cancelled: Arc<AtomicBool>) {
+    callbacks.update_tips(move |refname, a, b| {
+        if a.is_zero() {
+            info!("Creating reference {}", refname);
+        } else {
+            info!("Updating reference {} from {} to {}", refname, a, b);
+        }
+        !cancelled.load(Ordering::SeqCst)
+    });
+}
+
+// Listing refs transfers nothing, so the deadline and cancellation are checked while connecting instead. Passing
+// the certificate through keeps libgit2's own validation
+fn register_connect_check(callbacks: &mut RemoteCallbacks<'static>, deadline: Option<std::time::Instant>, cancelled: Arc<AtomicBool>) {
+    callbacks.certificate_check(move |_cert, _host| {
+        if cancelled.load(Ordering::SeqCst) || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            return Err(git2::Error::from_str("the operation was aborted"));
+        }
+        Ok(git2::CertificateCheckStatus::CertificatePassthrough)
+    });
+}
+
+// Doubling stops here, so that many attempts with a long initial backoff don't wait for hours
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+impl RetryPolicy {
+    /// Pause after the failed attempt `attempt` (from 1): the initial backoff, doubled for each
+    /// further attempt up to `MAX_RETRY_BACKOFF`.
+    fn backoff_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.checked_pow(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff.checked_mul(factor).map_or(MAX_RETRY_BACKOFF, |backoff| backoff.min(MAX_RETRY_BACKOFF))
+    }
+
+    /// Runs `op` until it succeeds, fails with a non-transient error or runs out of attempts,
+    /// pausing for `backoff_delay` between attempts. Never sleeps past `deadline`.
+    fn run<T>(
+        &self,
+        what: &str,
+        url: &str,
+        deadline: Option<std::time::Instant>,
+        mut op: impl FnMut() -> std::result::Result<T, git2::Error>,
+    ) -> std::result::Result<T, git2::Error> {
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    let backoff = self.backoff_delay(attempt);
+                    if deadline.is_some_and(|deadline| std::time::Instant::now() + backoff >= deadline) {
+                        return Err(e);
+                    }
+                    warn!(
+                        "Attempt {}/{} to {} {} failed: {}; retrying in {:?}",
+                        attempt, self.attempts, what, url, e, backoff
+                    );
+                    std::thread::sleep(backoff);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Connection-level failures (DNS, refused or reset connections, TLS handshakes) are worth
+// retrying; authentication errors, HTTP errors, missing or existing paths and aborted transfers are not
+fn is_transient(error: &git2::Error) -> bool {
+    matches!(error.class(), git2::ErrorClass::Net | git2::ErrorClass::Os | git2::ErrorClass::Ssl)
+        && !matches!(
+            error.code(),
+            git2::ErrorCode::Auth
+                | git2::ErrorCode::NotFound
+                | git2::ErrorCode::Exists
+                | git2::ErrorCode::User
+                | git2::ErrorCode::Certificate
+        )
+}
+
+// The tree a revision points at, and the commit it was found through when there is one
+fn resolve_tree<'r>(repo: &'r Repository, reference: &str) -> Result<(Oid, Tree<'r>), GitError> {
+    let object = repo.revparse_single(reference).map_err(|_| GitError::RefNotFound(reference.to_string()))?;
+    let tree = object.peel_to_tree().map_err(|_| GitError::RefNotFound(reference.to_string()))?;
+    let id = object.peel_to_commit().map(|commit| commit.id()).unwrap_or_else(|_| object.id());
+    Ok((id, tree))
+}
+
+// Normalizes a path inside a repository tree given by a client, dropping leading, trailing and
+// repeated slashes. `.` and `..` are refused rather than resolved.
+fn tree_path(path: &str) -> Result<String, GitError> {
+    let mut segments = Vec::new();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains('\0') {
+            return Err(GitError::InvalidPath(path.to_string()));
+        }
+        segments.push(segment);
+    }
+    Ok(segments.join("/"))
+}
+
+// Waits for a blocking git task, giving up once `limit` has passed
+async fn join_with_timeout<T>(task: task::JoinHandle<Result<T>>, limit: Option<Duration>) -> Result<T> {
+    match limit {
+        Some(limit) => match tokio::time::timeout(limit, task).await {
+            Ok(joined) => joined?,
+            Err(_) => Err(GitError::Timeout(limit).into()),
+        },
+        None => task.await?,
+    }
+}
+
+// The error to report instead of libgit2's "user cancelled" after a cancellation or once the deadline has passed
+fn abort_error(cancelled: &AtomicBool, deadline: Option<std::time::Instant>, limit: Option<Duration>) -> Option<anyhow::Error> {
+    if cancelled.load(Ordering::SeqCst) {
+        return Some(GitError::Cancelled.into());
+    }
+    match (deadline, limit) {
+        (Some(deadline), Some(limit)) if std::time::Instant::now() >= deadline => Some(GitError::Timeout(limit).into()),
+        _ => None,
+    }
+}
+
+// A `min_free_space` of 0 disables the check, as do filesystems whose free space can't be read
+fn check_free_space(path: &Path, min_free_space: u64) -> Result<(), GitError> {
+    match available_space(path) {
+        Some(available) if available < min_free_space => Err(GitError::InsufficientSpace {
+            available,
+            required: min_free_space,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid, writable statvfs struct
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+fn uses_lfs(repo: &Repository) -> bool {
+    let Ok(tree) = repo.head().and_then(|head| head.peel_to_tree()) else {
+        return false;
+    };
+    tree.get_path(Path::new(".gitattributes"))
+        .and_then(|entry| entry.to_object(repo))
+        .and_then(|object| object.peel_to_blob())
+        .is_ok_and(|blob| String::from_utf8_lossy(blob.content()).contains("filter=lfs"))
+}
+
+/// libgit2 only checks out the pointer files of Git LFS repositories. When `GIT_LFS_FETCH` is
+/// enabled the objects are downloaded with the `git-lfs` binary instead. Returns whether the
+/// repository is left with pointer files only.
+fn fetch_lfs_objects(repo: &Repository, url: &str, tools: &GitTools) -> bool {
+    if !uses_lfs(repo) {
+        return false;
+    }
+    if !tools.lfs_fetch {
+        warn!("{} uses Git LFS but only pointer files were checked out, set GIT_LFS_FETCH=1 to download them", url);
+        return true;
+    }
+
+    // Mirrors keep the objects of every ref, checkouts only need the files of the current one
+    let (dir, args) = match repo.workdir() {
+        Some(workdir) => (workdir, ["lfs", "pull"].as_slice()),
+        None => (repo.path(), ["lfs", "fetch", "--all"].as_slice()),
+    };
+    match Command::new(&tools.binary).current_dir(dir).args(args).output() {
+        Ok(output) if output.status.success() => {
+            info!("Downloaded Git LFS objects of {}", url);
+            false
+        }
+        Ok(output) => {
+            warn!("git lfs failed for {}: {}", url, String::from_utf8_lossy(&output.stderr).trim());
+            true
+        }
+        Err(e) => {
+            warn!("Failed to run git lfs for {}: {}", url, e);
+            true
+        }
+    }
+}
+
+/// Probes an HTTP(S) remote for a redirect to a new location, as served by hosts
+/// such as GitHub after a repository is renamed or transferred.
+fn detect_moved_url(url: &str) -> Option<String> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return None;
+    }
+
+    let client = reqwest::blocking::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(Duration::from_secs(15))
+        .build()
+        .ok()?;
+    let current = url.trim_end_matches('/');
+    let response = client
+        .get(format!("{}/info/refs?service=git-upload-pack", current))
+        .send()
+        .ok()?;
+    if !response.status().is_redirection() {
+        return None;
+    }
+
+    let location = response.headers().get(reqwest::header::LOCATION)?.to_str().ok()?;
+    let target = response.url().join(location).ok()?;
+    moved_location(current, target.as_str())
+}
+
+// Repository URL behind the redirect target of its `info/refs` request. Anything else, such as a login page,
+// is not the repository answering from somewhere else
+fn moved_location(current: &str, target: &str) -> Option<String> {
+    let new_url = target.strip_suffix("/info/refs?service=git-upload-pack")?.to_string();
+    (new_url != current).then_some(new_url)
+}
+
+// The new location of a moved repository is held to the same host rules as a repository added by hand
+fn check_move_target(new_url: &str) -> Result<()> {
+    check_host(&RepoUrl::parse(new_url)?)
+}
+
+// Where a local branch stands against its remote-tracking branch
+struct BranchComparison {
+    remote: Oid,
+    ahead: usize,
+    behind: usize,
+}
+
+impl BranchComparison {
+    fn can_fast_forward(&self) -> bool {
+        self.ahead == 0 && self.behind > 0
+    }
+
+    fn diverged(&self) -> bool {
+        self.ahead > 0 && self.behind > 0
+    }
+}
+
+// None when the remote has no such branch
+fn compare_branch(repo: &Repository, branch_name: &str) -> Result<Option<BranchComparison>> {
+    let Ok(remote_ref) = repo.find_reference(&format!("refs/remotes/origin/{}", branch_name)) else {
+        return Ok(None);
+    };
+    let remote = remote_ref.peel_to_commit()?.id();
+    let local = repo.find_reference(&format!("refs/heads/{}", branch_name))?.peel_to_commit()?.id();
+    let (ahead, behind) = repo.graph_ahead_behind(local, remote)?;
+    Ok(Some(BranchComparison { remote, ahead, behind }))
+}
+
+// Branch names under refs/remotes/origin
+fn remote_branches(repo: &Repository) -> Result<BTreeSet<String>> {
+    let mut branches = BTreeSet::new();
+    for reference in repo.references_glob("refs/remotes/origin/*")? {
+        let reference = reference?;
+        if let Some(branch) = reference.name().and_then(|name| name.strip_prefix("refs/remotes/origin/")) {
+            if branch != "HEAD" {
+                branches.insert(branch.to_string());
+            }
+        }
+    }
+    Ok(branches)
+}
+
+// Returns how many commits the branch moved forward
+fn fast_forward_branch(repo: &Repository, branch_name: &str, checkout: bool, url: &str) -> Result<usize> {
+    let Some(comparison) = compare_branch(repo, branch_name)? else {
+        return Ok(0);
+    };
+    
+    if comparison.can_fast_forward() {
+        info!("Fast-forwarding {} commits of {} in {}", comparison.behind, branch_name, url);
+        let mut reference = repo.find_reference(&format!("refs/heads/{}", branch_name))?;
+        reference.set_target(comparison.remote, "Fast-forward merge")?;
+        if checkout {
+            repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+        }
+        return Ok(comparison.behind);
+    }
+    if comparison.diverged() {
+        warn!("Branch {} of {} has diverged from remote, skipping merge to preserve local history", branch_name, url);
+    } else {
+        info!("Branch {} of {} is up to date", branch_name, url);
+    }
+    Ok(0)
+}
+
+// Points the branch at the remote commit whatever it held, dropping local commits and, when it is checked
+// out, uncommitted changes to tracked files. Returns how many upstream commits the branch gained
+fn reset_branch(repo: &Repository, branch_name: &str, checkout: bool, url: &str) -> Result<usize> {
+    let Ok(remote_ref) = repo.find_reference(&format!("refs/remotes/origin/{}", branch_name)) else {
+        return Err(anyhow!("Branch {} does not exist upstream, nothing to reset to", branch_name));
+    };
+    let remote_commit = remote_ref.peel_to_commit()?;
+    let gained = compare_branch(repo, branch_name).ok().flatten().map_or(0, |comparison| comparison.behind);
+    let local_name = format!("refs/heads/{}", branch_name);
+    warn!("Hard resetting {} of {} to {}, discarding local commits and changes", branch_name, url, remote_commit.id());
+    repo.reference(&local_name, remote_commit.id(), true, "Reset to remote")?;
+    if checkout {
+        repo.set_head(&local_name)?;
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+    }
+    Ok(gained)
+}
+
+fn sync_worktree(worktree: &Worktree, url: &str) -> Result<()> {
+    let repo = Repository::open(&worktree.path)?;
+    if !repo.statuses(None)?.is_empty() {
+        warn!("Worktree {} of {} has local changes, skipping merge to preserve local history", worktree.path, url);
+        return Ok(());
+    }
+    fast_forward_branch(&repo, &worktree.branch, true, url)?;
+    Ok(())
+}
+
+// libgit2 may record the remote HEAD, which `git clone --mirror` doesn't have, and leaves HEAD at
+// the default branch name instead of the remote's default branch
+fn finish_mirror_clone(repo: &Repository, options: &CloneOptions, tools: &GitTools) -> Result<()> {
+    if let Ok(mut remote_head) = repo.find_reference("refs/remotes/origin/HEAD") {
+        remote_head.delete()?;
+    }
+    let mut remote = repo.find_remote("origin")?;
+    let mut callbacks = RemoteCallbacks::new();
+    register_credentials(&mut callbacks, options.credentials.clone(), options.ssh_key_path.as_deref(), tools);
+    let connection = remote.connect_auth(git2::Direction::Fetch, Some(callbacks), None)?;
+    if let Some(default_branch) = connection.default_branch().ok().and_then(|name| name.as_str().map(str::to_string)) {
+        repo.set_head(&default_branch)?;
+    }
+    Ok(())
+}
+
+/// Initializes and checks out the submodules of a repository at the commits it records, then
+/// their own submodules. Submodules are fetched with the parent repository's credentials.
+fn update_submodules(repo: &Repository, credentials: Option<&RepositoryCredentials>, ssh_key_path: Option<&str>, tools: &GitTools) -> Result<()> {
+    for mut submodule in repo.submodules()? {
+        let name = submodule.name().unwrap_or_default().to_string();
+        let mut callbacks = RemoteCallbacks::new();
+        register_credentials(&mut callbacks, credentials.cloned(), ssh_key_path, tools);
+        let mut fetch_options = FetchOptions::new();
+        fetch_options.remote_callbacks(callbacks);
+        let mut update_options = SubmoduleUpdateOptions::new();
+        update_options.fetch(fetch_options);
+
+        info!("Updating submodule {} in {}", name, repo.path().display());
+        submodule
+            .update(true, Some(&mut update_options))
+            .map_err(|e| anyhow!("Failed to update submodule '{}': {}", name, e))?;
+        let nested = submodule.open().map_err(|e| anyhow!("Failed to open submodule '{}': {}", name, e))?;
+        update_submodules(&nested, credentials, ssh_key_path, tools).map_err(|e| anyhow!("In submodule '{}': {}", name, e))?;
+    }
+    Ok(())
+}
+
+/// Registers the authentication callback: stored username/token for HTTPS remotes and
+/// the per-repository key (or `GIT_SSH_KEY`) for SSH remotes.
+fn register_credentials(callbacks: &mut RemoteCallbacks, credentials: Option<RepositoryCredentials>, ssh_key_path: Option<&str>, tools: &GitTools) {
+    let ssh_key = ssh_key_path
+        .map(PathBuf::from)
+        .or_else(|| tools.ssh_key.clone());
+    if credentials.is_none() && ssh_key.is_none() {
+        return;
+    }
+    let passphrase = tools.ssh_passphrase.clone();
+
+    let mut choice = CredentialChoice::default();
+    callbacks.credentials(move |_url, username_from_url, allowed_types| {
+        match (choice.next(allowed_types, username_from_url, ssh_key.is_some(), credentials.is_some()), &ssh_key, &credentials) {
+            (Some(CredentialKind::SshKey(username)), Some(key), _) => Cred::ssh_key(username, None, key, passphrase.as_deref()),
+            (Some(CredentialKind::UserPass), _, Some(credentials)) => Cred::userpass_plaintext(&credentials.username, &credentials.token),
+            _ => Err(git2::Error::from_str("authentication failed with the configured credentials")),
+        }
+    });
+}
+
+/// Picks the credentials answering a request from libgit2. It keeps asking as long as the callback
+/// returns credentials, so each kind is only offered once.
+#[derive(Debug, Default)]
+struct CredentialChoice {
+    tried_ssh_key: bool,
+    tried_userpass: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum CredentialKind<'a> {
+    /// The SSH key, for the user named in the URL or `git`.
+    SshKey(&'a str),
+    UserPass,
+}
+
+impl CredentialChoice {
+    fn next<'a>(
+        &mut self,
+        allowed_types: CredentialType,
+        username_from_url: Option<&'a str>,
+        has_ssh_key: bool,
+        has_credentials: bool,
+    ) -> Option<CredentialKind<'a>> {
+        if allowed_types.contains(CredentialType::SSH_KEY) && has_ssh_key && !self.tried_ssh_key {
+            self.tried_ssh_key = true;
+            return Some(CredentialKind::SshKey(username_from_url.unwrap_or("git")));
+        }
+        if allowed_types.contains(CredentialType::USER_PASS_PLAINTEXT) && has_credentials && !self.tried_userpass {
+            self.tried_userpass = true;
+            return Some(CredentialKind::UserPass);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch directory removed at the end of the test.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("gitcloner-test-{}", Uuid::new_v4()));
+            fs::create_dir_all(&path).unwrap();
+            TempDir(fs::canonicalize(path).unwrap())
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn manager(base_path: &Path) -> GitManager {
+        GitManager {
+            base_path: base_path.to_path_buf(),
+            operations: Arc::new(Mutex::new(HashMap::new())),
+            locks: Arc::new(Mutex::new(HashMap::new())),
+            lock_mode: LockMode::Reject,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            max_in_flight: 1,
+            op_timeout: None,
+            retry: RetryPolicy { attempts: 1, initial_backoff: Duration::ZERO },
+            min_free_space: 0,
+            default_rate_limit_kbps: None,
+            default_max_size_mb: None,
+            gc_after_syncs: None,
+            follow_repo_moves: false,
+            tools: Arc::new(GitTools { binary: "git".to_string(), ..GitTools::default() }),
+        }
+    }
+
+    fn repository(url: &str, local_path: &Path) -> RepoModel {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "url": url,
+            "name": "upstream",
+            "local_path": local_path,
+            "created_at": "2024-01-01T00:00:00Z",
+            "status": "synced",
+        }))
+        .unwrap()
+    }
+
+    fn commit_file(repo: &Repository, name: &str, contents: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, name, &tree, &parents).unwrap()
+    }
+
+    fn net_error(code: git2::ErrorCode) -> git2::Error {
+        git2::Error::new(code, git2::ErrorClass::Net, "connection failed")
+    }
+
+    fn retry(attempts: u32) -> RetryPolicy {
+        RetryPolicy { attempts, initial_backoff: Duration::from_millis(1) }
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut calls = 0;
+        let result = retry(3).run("fetch", "url", None, || {
+            calls += 1;
+            if calls < 3 { Err(net_error(git2::ErrorCode::GenericError)) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy { attempts: 10, initial_backoff: Duration::from_secs(5) };
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.backoff_delay(attempt)).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60].map(Duration::from_secs));
+        assert_eq!(policy.backoff_delay(100), MAX_RETRY_BACKOFF);
+        assert_eq!(retry(3).backoff_delay(3), Duration::from_millis(4));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let permanent = [
+            net_error(git2::ErrorCode::Auth),
+            net_error(git2::ErrorCode::NotFound),
+            git2::Error::new(git2::ErrorCode::GenericError, git2::ErrorClass::Http, "unexpected http status code: 404"),
+        ];
+        for error in permanent {
+            let mut calls = 0;
+            let message = error.message().to_string();
+            let mut error = Some(error);
+            let result: std::result::Result<(), _> = retry(3).run("fetch", "url", None, || {
+                calls += 1;
+                Err(error.take().unwrap_or_else(|| git2::Error::from_str("called again")))
+            });
+            assert_eq!(result.unwrap_err().message(), message);
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn retries_stop_after_the_attempts() {
+        let mut calls = 0;
+        let result: std::result::Result<(), _> = retry(4).run("fetch", "url", None, || {
+            calls += 1;
+            Err(net_error(git2::ErrorCode::GenericError))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+
+    #[test]
+    fn retries_never_sleep_past_the_deadline() {
+        let policy = RetryPolicy { attempts: 3, initial_backoff: Duration::from_secs(60) };
+        let deadline = std::time::Instant::now() + Duration::from_secs(1);
+        let started = std::time::Instant::now();
+        let mut calls = 0;
+        let result: std::result::Result<(), _> = policy.run("fetch", "url", Some(deadline), || {
+            calls += 1;
+            Err(net_error(git2::ErrorCode::GenericError))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn slots_run_out_at_the_ceiling() {
+        let dir = TempDir::new();
+        let manager = GitManager { max_in_flight: 2, ..manager(&dir.0) };
+        let first = manager.try_acquire_slot().unwrap();
+        let _second = manager.try_acquire_slot().unwrap();
+        assert!(manager.try_acquire_slot().is_none());
+        assert_eq!(manager.load().0, 2);
+
+        drop(first);
+        assert_eq!(manager.load().0, 1);
+        assert!(manager.try_acquire_slot().is_some());
+    }
+
+    #[tokio::test]
+    async fn waiting_operations_run_one_after_the_other() {
+        let dir = TempDir::new();
+        let manager = manager(&dir.0);
+        let url = "https://github.com/org/repo.git";
+        let first = manager.begin_operation(url, &dir.0, Operation::Sync, LockMode::Wait).await.unwrap();
+
+        let started = Arc::new(AtomicBool::new(false));
+        let second = tokio::spawn({
+            let (manager, path, started) = (manager.clone(), dir.0.clone(), started.clone());
+            async move {
+                let _guard = manager.begin_operation(url, &path, Operation::Sync, LockMode::Wait).await.unwrap();
+                started.store(true, Ordering::SeqCst);
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!started.load(Ordering::SeqCst));
+
+        drop(first);
+        second.await.unwrap();
+        assert!(started.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn repository_shows_the_running_operation() {
+        let dir = TempDir::new();
+        let manager = manager(&dir.0);
+        let mut repo = repository("https://github.com/org/repo.git", &dir.0.join("local"));
+        let sync = manager.begin_operation(&repo.url, &dir.0.join("local"), Operation::Sync, LockMode::Wait).await.unwrap();
+
+        crate::handlers::set_operation_state(&mut repo, &manager);
+        assert!(repo.in_progress);
+        assert_eq!(repo.operation, Some(Operation::Sync));
+
+        drop(sync);
+        crate::handlers::set_operation_state(&mut repo, &manager);
+        assert!(!repo.in_progress);
+        assert_eq!(repo.operation, None);
+    }
+
+    #[tokio::test]
+    async fn rejecting_operations_fail_while_another_runs() {
+        let dir = TempDir::new();
+        let manager = manager(&dir.0);
+        let url = "https://github.com/org/repo.git";
+        let first = manager.begin_operation(url, &dir.0, Operation::Sync, LockMode::Wait).await.unwrap();
+        let second = manager.begin_operation(url, &dir.0, Operation::Sync, LockMode::Reject).await;
+        assert!(matches!(second, Err(GitError::Busy)));
+
+        drop(first);
+        assert!(manager.begin_operation(url, &dir.0, Operation::Sync, LockMode::Reject).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn repository_is_not_trashed_during_a_sync() {
+        let dir = TempDir::new();
+        let local_path = dir.0.join("local");
+        commit_file(&Repository::init(&local_path).unwrap(), "README", "first");
+        let repo = repository("https://github.com/org/repo.git", &local_path);
+        let manager = manager(&dir.0);
+        let sync = manager.begin_operation(&repo.url, &local_path, Operation::Sync, LockMode::Wait).await.unwrap();
+
+        let error = manager.move_to_trash(&repo).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<GitError>(), Some(GitError::Busy)), "{}", error);
+        assert!(local_path.join("README").exists());
+
+        drop(sync);
+        manager.move_to_trash(&repo).await.unwrap();
+        assert!(!local_path.exists());
+    }
+
+    #[tokio::test]
+    async fn bundle_fast_forwards_the_clone() {
+        let dir = TempDir::new();
+        let upstream_path = dir.0.join("upstream");
+        let upstream = Repository::init_opts(&upstream_path, git2::RepositoryInitOptions::new().initial_head("main")).unwrap();
+        commit_file(&upstream, "README", "first");
+        let local_path = dir.0.join("local");
+        Repository::clone(upstream_path.to_str().unwrap(), &local_path).unwrap();
+
+        let head = commit_file(&upstream, "CHANGES", "second");
+        let bundle_path = dir.0.join("update.bundle");
+        let bundle = Command::new("git")
+            .current_dir(&upstream_path)
+            .args(["bundle", "create"])
+            .arg(&bundle_path)
+            .arg("main")
+            .output()
+            .unwrap();
+        assert!(bundle.status.success(), "{}", String::from_utf8_lossy(&bundle.stderr));
+
+        let repo = repository(upstream_path.to_str().unwrap(), &local_path);
+        manager(&dir.0).apply_bundle(&repo, &bundle_path).await.unwrap();
+
+        let local = Repository::open(&local_path).unwrap();
+        assert_eq!(local.head().unwrap().target(), Some(head));
+        assert_eq!(fs::read_to_string(local_path.join("CHANGES")).unwrap(), "second");
+    }
+
+    #[tokio::test]
+    async fn sync_prunes_branches_deleted_upstream() {
+        let dir = TempDir::new();
+        let upstream_path = dir.0.join("upstream");
+        let upstream = Repository::init_opts(&upstream_path, git2::RepositoryInitOptions::new().initial_head("main")).unwrap();
+        let head = commit_file(&upstream, "README", "first");
+        upstream.branch("feature", &upstream.find_commit(head).unwrap(), false).unwrap();
+        let local_path = dir.0.join("local");
+        let local = Repository::clone(upstream_path.to_str().unwrap(), &local_path).unwrap();
+        assert!(local.find_reference("refs/remotes/origin/feature").is_ok());
+
+        upstream.find_branch("feature", BranchType::Local).unwrap().delete().unwrap();
+        let repo = repository(upstream_path.to_str().unwrap(), &local_path);
+        manager(&dir.0).sync_repository(&repo, &SyncOptions::default()).await.unwrap();
+
+        let local = Repository::open(&local_path).unwrap();
+        assert!(local.find_reference("refs/remotes/origin/feature").is_err());
+        assert!(local.find_reference("refs/remotes/origin/main").is_ok());
+    }
+
+    #[tokio::test]
+    async fn invalid_bundle_is_rejected() {
+        let dir = TempDir::new();
+        let local_path = dir.0.join("local");
+        let local = Repository::init(&local_path).unwrap();
+        commit_file(&local, "README", "first");
+        let bundle_path = dir.0.join("garbage.bundle");
+        fs::write(&bundle_path, "not a bundle").unwrap();
+
+        let repo = repository("https://example.com/org/repo.git", &local_path);
+        let error = manager(&dir.0).apply_bundle(&repo, &bundle_path).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<GitError>(), Some(GitError::InvalidBundle(_))), "{}", error);
+    }
+
+    // A clone of a repository tracked under an old URL that its host moved elsewhere
+    async fn moved_repository(dir: &TempDir, old_url: &str) -> (crate::database::Database, RepoModel) {
+        let upstream_path = dir.0.join("upstream");
+        commit_file(&Repository::init(&upstream_path).unwrap(), "README", "first");
+        let local_path = dir.0.join("local");
+        Repository::clone(upstream_path.to_str().unwrap(), &local_path).unwrap().remote_set_url("origin", old_url).unwrap();
+        let db = crate::database::Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        db.add_repository(old_url, "github.com/org/repo", local_path.to_str().unwrap(), &CloneOptions::default(), RepoStatus::Pending).await.unwrap();
+        let repo = db.get_repository_by_url(old_url).await.unwrap().unwrap();
+        (db, repo)
+    }
+
+    fn origin_url(local_path: &str) -> Option<String> {
+        Repository::open(local_path).unwrap().find_remote("origin").unwrap().url().map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn moved_repository_is_tracked_under_its_new_url() {
+        let dir = TempDir::new();
+        let old_url = "https://github.com/org/repo.git";
+        let new_url = "https://github.com/new-org/repo.git";
+        let (db, repo) = moved_repository(&dir, old_url).await;
+
+        crate::sync::record_move(&db, &manager(&dir.0), crate::sync::SYNC_ACTOR, &repo, new_url).await.unwrap();
+
+        assert_eq!(origin_url(&repo.local_path).as_deref(), Some(new_url));
+        assert_eq!(db.get_repository_by_url(new_url).await.unwrap().map(|moved| moved.id), Some(repo.id));
+        assert!(db.get_repository_by_url(old_url).await.unwrap().is_none());
+        let (entries, _) = db.get_audit_log(1, 10).await.unwrap();
+        let moves: Vec<_> = entries.iter().map(|entry| (entry.action.as_str(), entry.target.as_str(), entry.detail.as_deref())).collect();
+        assert_eq!(moves, [("repository.move", old_url, Some(new_url))]);
+    }
+
+    #[tokio::test]
+    async fn moves_onto_tracked_repositories_are_not_followed() {
+        let dir = TempDir::new();
+        let old_url = "https://github.com/org/repo.git";
+        let new_url = "https://github.com/new-org/repo.git";
+        let (db, repo) = moved_repository(&dir, old_url).await;
+        db.add_repository(new_url, "github.com/new-org/repo", dir.0.join("other").to_str().unwrap(), &CloneOptions::default(), RepoStatus::Pending).await.unwrap();
+
+        let error = crate::sync::record_move(&db, &manager(&dir.0), crate::sync::SYNC_ACTOR, &repo, new_url).await.unwrap_err();
+
+        assert!(error.to_string().contains("already tracked"), "{}", error);
+        assert_eq!(origin_url(&repo.local_path).as_deref(), Some(old_url));
+        assert_eq!(db.get_repository_by_url(old_url).await.unwrap().map(|unmoved| unmoved.id), Some(repo.id));
+        assert!(db.get_audit_log(1, 10).await.unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn moves_to_internal_hosts_are_not_followed() {
+        assert!(check_move_target("https://github.com/new-org/repo.git").is_ok());
+        assert!(check_move_target("http://127.0.0.1/org/repo.git").is_err());
+    }
+
+    #[test]
+    fn only_redirected_upload_pack_requests_are_moves() {
+        let current = "https://github.com/org/repo.git";
+        let moved = "https://github.com/new-org/repo.git/info/refs?service=git-upload-pack";
+        assert_eq!(moved_location(current, moved).as_deref(), Some("https://github.com/new-org/repo.git"));
+        assert_eq!(moved_location(current, "https://github.com/login?return_to=%2Forg%2Frepo"), None);
+        assert_eq!(moved_location(current, "https://github.com/org/repo.git/info/refs?service=git-upload-pack"), None);
+    }
+
+    #[tokio::test]
+    async fn interrupted_clones_are_reconciled_on_startup() {
+        let dir = TempDir::new();
+        let finished_path = dir.0.join("finished");
+        commit_file(&Repository::init(&finished_path).unwrap(), "README", "first");
+        let db = crate::database::Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        let finished = "https://github.com/org/finished.git";
+        let interrupted = "https://github.com/org/interrupted.git";
+        let options = CloneOptions::default();
+        db.add_repository(finished, "github.com/org/finished", finished_path.to_str().unwrap(), &options, RepoStatus::Cloning).await.unwrap();
+        db.add_repository(interrupted, "github.com/org/interrupted", dir.0.join("interrupted").to_str().unwrap(), &options, RepoStatus::Cloning)
+            .await
+            .unwrap();
+
+        crate::sync::reconcile_on_startup(&db, &manager(&dir.0)).await.unwrap();
+
+        assert_eq!(db.get_repository_by_url(finished).await.unwrap().map(|repo| repo.status), Some(RepoStatus::Pending));
+        assert!(db.get_repository_matching_url(interrupted).await.unwrap().is_none());
+    }
+
+    // Serves scp-like `git@host:path` remotes from `git upload-pack` on `path` under the temporary directory,
+    // so that they can be cloned without an SSH server
+    struct LocalSsh(Mutex<Option<UploadPack>>);
+
+    #[derive(Clone)]
+    struct UploadPack(Arc<Mutex<std::process::Child>>);
+
+    impl std::io::Read for UploadPack {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().stdout.as_mut().unwrap().read(buf)
+        }
+    }
+
+    impl std::io::Write for UploadPack {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().stdin.as_mut().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.lock().unwrap().stdin.as_mut().unwrap().flush()
+        }
+    }
+
+    impl git2::transport::SmartSubtransport for LocalSsh {
+        fn action(&self, url: &str, action: git2::transport::Service) -> std::result::Result<Box<dyn git2::transport::SmartSubtransportStream>, git2::Error> {
+            use git2::transport::Service;
+            let mut running = self.0.lock().unwrap();
+            match action {
+                Service::UploadPackLs => {
+                    let (_, path) = url.split_once(':').ok_or_else(|| git2::Error::from_str("not an scp-like URL"))?;
+                    let child = Command::new("git")
+                        .arg("upload-pack")
+                        .arg(std::env::temp_dir().join(path))
+                        .stdin(std::process::Stdio::piped())
+                        .stdout(std::process::Stdio::piped())
+                        .spawn()
+                        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+                    *running = Some(UploadPack(Arc::new(Mutex::new(child))));
+                }
+                Service::UploadPack => {}
+                _ => return Err(git2::Error::from_str("pushing is not supported")),
+            }
+            let stream = running.clone().ok_or_else(|| git2::Error::from_str("no upload-pack running"))?;
+            Ok(Box::new(stream))
+        }
+
+        fn close(&self) -> std::result::Result<(), git2::Error> {
+            if let Some(UploadPack(child)) = self.0.lock().unwrap().take() {
+                let _ = child.lock().unwrap().wait();
+            }
+            Ok(())
+        }
+    }
+
+    fn serve_ssh_locally() {
+        static REGISTER: std::sync::Once = std::sync::Once::new();
+        REGISTER.call_once(|| unsafe {
+            git2::transport::register("ssh", |remote| {
+                git2::transport::Transport::smart(remote, false, LocalSsh(Mutex::new(None)))
+            })
+            .unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn ssh_clone_is_named_after_its_directory() {
+        serve_ssh_locally();
+        let dir = TempDir::new();
+        let upstream = Repository::init_opts(dir.0.join("repo.git"), git2::RepositoryInitOptions::new().initial_head("main")).unwrap();
+        commit_file(&upstream, "README", "first");
+        let group = dir.0.file_name().unwrap().to_str().unwrap();
+        let url = format!("git@git.example.com:{}/repo.git", group);
+        let base = dir.0.join("repos");
+        fs::create_dir_all(&base).unwrap();
+
+        let outcome = manager(&base).clone_repository(&url, &CloneOptions::default()).await.unwrap();
+
+        assert_eq!(outcome.name, format!("git.example.com/{}/repo", group));
+        assert_eq!(PathBuf::from(&outcome.local_path), base.join(&outcome.name));
+        assert!(Repository::open(&outcome.local_path).unwrap().find_reference("refs/remotes/origin/main").is_ok());
+    }
+
+    #[test]
+    fn ssh_key_is_offered_once_for_the_url_user() {
+        let mut choice = CredentialChoice::default();
+        assert_eq!(choice.next(CredentialType::SSH_KEY, Some("deploy"), true, false), Some(CredentialKind::SshKey("deploy")));
+        assert_eq!(choice.next(CredentialType::SSH_KEY, Some("deploy"), true, false), None);
+    }
+
+    #[test]
+    fn ssh_key_user_defaults_to_git() {
+        let mut choice = CredentialChoice::default();
+        assert_eq!(choice.next(CredentialType::SSH_KEY, None, true, false), Some(CredentialKind::SshKey("git")));
+    }
+
+    #[test]
+    fn credentials_answer_what_the_remote_allows() {
+        let both = CredentialType::SSH_KEY | CredentialType::USER_PASS_PLAINTEXT;
+        let mut choice = CredentialChoice::default();
+        assert_eq!(choice.next(both, Some("git"), true, true), Some(CredentialKind::SshKey("git")));
+        assert_eq!(choice.next(both, Some("git"), true, true), Some(CredentialKind::UserPass));
+        assert_eq!(choice.next(both, Some("git"), true, true), None);
+
+        let mut https = CredentialChoice::default();
+        assert_eq!(https.next(CredentialType::USER_PASS_PLAINTEXT, None, true, true), Some(CredentialKind::UserPass));
+        let mut no_key = CredentialChoice::default();
+        assert_eq!(no_key.next(CredentialType::SSH_KEY, Some("git"), false, true), None);
+    }
+
+    #[test]
+    fn throttle_pauses_only_transfers_faster_than_the_limit() {
+        let throttle = Throttle { bytes_per_sec: 1000 };
+        assert_eq!(throttle.pause(500, Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(throttle.pause(1000, Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(throttle.pause(1500, Duration::from_secs(1)), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn throttle_pause_is_capped() {
+        let throttle = Throttle { bytes_per_sec: 1000 };
+        assert_eq!(throttle.pause(500, Duration::ZERO), Duration::from_millis(500));
+        assert_eq!(throttle.pause(5000, Duration::ZERO), MAX_THROTTLE_PAUSE);
+        assert_eq!(throttle.pause(0, Duration::ZERO), Duration::ZERO);
+    }
+
+    #[test]
+    fn size_limit_counts_the_existing_clone() {
+        let limit = SizeLimit { max_bytes: 1000, existing: 400 };
+        assert!(!limit.exceeded(599));
+        assert!(!limit.exceeded(600));
+        assert!(limit.exceeded(601));
+
+        let empty = SizeLimit { max_bytes: 1000, existing: 0 };
+        assert!(!empty.exceeded(1000));
+        assert!(empty.exceeded(1001));
+        assert!(SizeLimit { max_bytes: 1000, existing: u64::MAX }.exceeded(1));
+    }
+
+    #[test]
+    fn clones_cannot_nest_inside_another_clone() {
+        let dir = TempDir::new();
+        let base = dir.0.join("repos");
+        Repository::init(base.join("gitlab.com/group/sub")).unwrap();
+        let project = base.join("gitlab.com/group/sub/project");
+        assert_eq!(enclosing_clone(&base, &project), Some(base.join("gitlab.com/group/sub")));
+        assert_eq!(enclosing_clone(&base, &base.join("gitlab.com/group/other")), None);
+    }
+
+    #[test]
+    fn empty_group_directories_are_removed() {
+        let dir = TempDir::new();
+        let base = dir.0.join("repos");
+        fs::create_dir_all(base.join("gitlab.com/group/sub")).unwrap();
+        fs::create_dir_all(base.join("gitlab.com/other")).unwrap();
+        remove_empty_parents(&base, &base.join("gitlab.com/group/sub/project"));
+        assert!(!base.join("gitlab.com/group").exists());
+        assert!(base.join("gitlab.com/other").exists());
+        assert!(base.exists());
     }
-    
-    // Replace any invalid filesystem characters
-    let safe_name = parsed_url.replace(":", "_").replace("@", "_");
-    
-    Ok(safe_name)
 }
@@ -1,30 +1,184 @@
 use warp::{Filter, Reply, Rejection};
+use warp::hyper::body::Buf;
+use futures::{Stream, StreamExt};
 use serde_json::json;
-use crate::auth::AuthManager;
+use tracing::Instrument;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use crate::auth::{AuthManager, AuthUser, TokenError};
+use crate::config::Config;
+use crate::ratelimit::{LoginLimiter, RequestClass, RequestLimiter};
 use crate::database::Database;
-use crate::git_manager::GitManager;
-use crate::models::{LoginRequest, AddRepositoryRequest, ApiResponse, PaginationQuery, PaginatedResponse};
+use crate::git_manager::{failure_status, CloneOptions, GitError, GitManager};
+use crate::url_utils::{check_host, strip_credentials, RepoUrl};
+use crate::webhooks::verify_github_signature;
+use crate::models::{LoginRequest, AddRepositoryRequest, AddWorktreeRequest, ApiResponse, ArchiveFormat, ArchiveQuery, BrowseQuery, DiffQuery, ChangeUrlRequest, ErrorCode, ErrorResponse, ExportedRepository, FetchRateLimitRequest, GithubPushEvent, MaxSizeRequest, Operation, PaginationQuery, PaginatedResponse, RepoStatus, Repository, RepositoryCredentials, RepositoryDetail, RepositoryExport, RepositoryFilter, ServiceHealth, SessionQuery, SyncPriority, SyncQuery, TagRequest, TemplateRequest, User, VerifyQuery};
+
+// Suggested wait when all operation slots are taken
+const OVERLOAD_RETRY_AFTER_SECS: u64 = 10;
+// GitHub caps webhook payloads at 25 MB
+const MAX_WEBHOOK_PAYLOAD_BYTES: u64 = 25 * 1024 * 1024;
 
 lazy_static::lazy_static! {
-    static ref AUTH_MANAGER: AuthManager = AuthManager::new();
+    pub static ref AUTH_MANAGER: AuthManager = AuthManager::new();
+    pub static ref LOGIN_LIMITER: LoginLimiter = LoginLimiter::new(Config::get());
+    pub static ref REQUEST_LIMITER: RequestLimiter = RequestLimiter::new(Config::get());
 }
 
 pub fn auth_routes(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     login(db.clone())
         .or(logout())
+        .or(refresh(db.clone()))
+        .or(current_user())
+        .or(list_sessions())
+        .or(revoke_session(db.clone()))
+        .or(revoke_user_sessions(db))
+}
+
+/// Unauthenticated so load balancers can probe it without a token.
+pub fn health_routes(db: Database, started_at: std::time::Instant) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("health")
+        .and(warp::get())
+        .and(with_db(db))
+        .and(warp::any().map(move || started_at))
+        .and_then(handle_health)
+}
+
+/// Open to scrapers unless `METRICS_TOKEN` is set, in which case it must be sent as a bearer token.
+pub fn metrics_routes(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_db(db))
+        .and_then(handle_metrics)
+}
+
+pub fn stats_routes(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("stats")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_get_stats)
+}
+
+/// Authenticated by the payload signature instead of a session, so that GitHub can call it.
+pub fn webhook_routes(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("webhooks" / "github")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("x-hub-signature-256"))
+        .and(warp::header::optional::<String>("x-github-event"))
+        .and(warp::body::content_length_limit(MAX_WEBHOOK_PAYLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_github_webhook)
+}
+
+/// Admin only.
+pub fn admin_routes(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("admin" / "sync" / "pause")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db.clone()))
+        .and_then(|user, db| handle_set_sync_paused(true, user, db))
+        .or(warp::path!("admin" / "sync" / "resume")
+            .and(warp::post())
+            .and(with_auth())
+            .and(with_db(db))
+            .and_then(|user, db| handle_set_sync_paused(false, user, db)))
+}
+
+/// Admin only.
+pub fn audit_log_routes(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("audit")
+        .and(warp::get())
+        .and(warp::query::<PaginationQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_get_audit_log)
+}
+
+pub fn template_routes(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("templates")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db.clone()))
+        .and_then(handle_get_templates)
+        .or(warp::path!("templates" / String)
+            .and(warp::get())
+            .and(with_auth())
+            .and(with_db(db.clone()))
+            .and_then(handle_get_template))
+        .or(warp::path!("templates")
+            .and(warp::post())
+            .and(warp::body::json())
+            .and(with_auth())
+            .and(with_db(db.clone()))
+            .and_then(handle_add_template))
+        .or(warp::path!("templates" / String)
+            .and(warp::put())
+            .and(warp::body::json())
+            .and(with_auth())
+            .and(with_db(db.clone()))
+            .and_then(handle_update_template))
+        .or(warp::path!("templates" / String)
+            .and(warp::delete())
+            .and(with_auth())
+            .and(with_db(db))
+            .and_then(handle_remove_template))
 }
 
 pub fn repo_routes(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    get_repositories(db.clone())
+    get_repositories(db.clone(), git_manager.clone())
+        // Before `get_repository`, which would take "export" for a repository URL
+        .or(export_repositories(db.clone()))
+        .or(import_repositories(db.clone(), git_manager.clone()))
+        .or(get_repository(db.clone(), git_manager.clone()))
         .or(add_repository(db.clone(), git_manager.clone()))
-        .or(remove_repository(db.clone()))
-        .or(sync_repository(db, git_manager))
+        .or(remove_repository(db.clone(), git_manager.clone()))
+        .or(restore_repository(db.clone(), git_manager.clone()))
+        .or(sync_repository(db.clone(), git_manager.clone()))
+        .or(get_sync_history(db.clone()))
+        .or(get_progress(git_manager.clone()))
+        .or(cancel_operation(db.clone(), git_manager.clone()))
+        .or(audit_repository(db.clone(), git_manager.clone()))
+        .or(verify_repository(db.clone(), git_manager.clone()))
+        .or(gc_repository(db.clone(), git_manager.clone()))
+        .or(browse_routes(db.clone(), git_manager.clone()))
+        .or(preview_sync(db.clone(), git_manager.clone()))
+        .or(settings_routes(db.clone(), git_manager.clone()))
+        .or(upload_bundle(db.clone(), git_manager.clone()))
+        .or(get_worktrees(db.clone()))
+        .or(add_worktree(db.clone(), git_manager.clone()))
+        .or(remove_worktree(db, git_manager))
+}
+
+// Read-only views of a clone's contents, kept apart to bound the nesting of the route types
+fn browse_routes(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    archive_repository(db.clone(), git_manager.clone())
+        .or(repository_tree(db.clone(), git_manager.clone()))
+        .or(repository_blob(db.clone(), git_manager.clone()))
+        .or(diff_repository(db, git_manager))
+}
+
+// Changes to a repository's stored settings, grouped like `browse_routes`
+fn settings_routes(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    add_tag(db.clone())
+        .or(remove_tag(db.clone()))
+        .or(update_credentials(db.clone()))
+        .or(change_url(db.clone(), git_manager))
+        .or(set_rate_limit(db.clone()))
+        .or(set_max_size(db.clone()))
+        .or(pause_repository(db.clone()))
+        .or(resume_repository(db))
 }
 
 fn login(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("auth" / "login")
         .and(warp::post())
         .and(warp::body::json())
+        .and(with_client_ip())
         .and(with_db(db))
         .and_then(handle_login)
 }
@@ -36,42 +190,336 @@ fn logout() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
         .and_then(handle_logout)
 }
 
-fn get_repositories(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+fn refresh(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("auth" / "refresh")
+        .and(warp::post())
+        .and(with_auth_token())
+        .and(with_db(db))
+        .and_then(handle_refresh)
+}
+
+fn current_user() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("auth" / "me")
+        .and(warp::get())
+        .and(with_auth())
+        .and_then(handle_current_user)
+}
+
+fn list_sessions() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("auth" / "sessions")
+        .and(warp::get())
+        .and(with_auth())
+        .and_then(handle_list_sessions)
+}
+
+fn revoke_session(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("auth" / "sessions" / String)
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_revoke_session)
+}
+
+fn revoke_user_sessions(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("auth" / "sessions")
+        .and(warp::delete())
+        .and(warp::query::<SessionQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_revoke_user_sessions)
+}
+
+fn get_repositories(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("repositories")
         .and(warp::get())
         .and(warp::query::<PaginationQuery>())
+        .and(warp::query::<RepositoryFilter>())
+        .and(warp::header::optional::<String>("if-none-match"))
         .and(with_auth())
         .and(with_db(db))
+        .and(with_git_manager(git_manager))
         .and_then(handle_get_repositories)
 }
 
+fn export_repositories(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / "export")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_export_repositories)
+}
+
+fn import_repositories(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / "import")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_rate_limit(RequestClass::GitOperation))
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_import_repositories)
+}
+
+fn get_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String)
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_get_repository)
+}
+
 fn add_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("repositories")
         .and(warp::post())
         .and(warp::body::json())
         .and(with_auth())
+        .and(with_rate_limit(RequestClass::GitOperation))
         .and(with_db(db))
         .and(with_git_manager(git_manager))
         .and_then(handle_add_repository)
 }
 
-fn remove_repository(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+fn remove_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("repositories" / String)
         .and(warp::delete())
         .and(with_auth())
         .and(with_db(db))
+        .and(with_git_manager(git_manager))
         .and_then(handle_remove_repository)
 }
 
+fn restore_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "restore")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_restore_repository)
+}
+
 fn sync_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
     warp::path!("repositories" / String / "sync")
         .and(warp::post())
+        .and(warp::query::<SyncQuery>())
         .and(with_auth())
+        .and(with_rate_limit(RequestClass::GitOperation))
         .and(with_db(db))
         .and(with_git_manager(git_manager))
         .and_then(handle_sync_repository)
 }
 
+fn get_sync_history(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "history")
+        .and(warp::get())
+        .and(warp::query::<PaginationQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_get_sync_history)
+}
+
+fn get_progress(git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "progress")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_git_manager(git_manager))
+        .and_then(handle_get_progress)
+}
+
+fn cancel_operation(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "cancel")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_cancel_operation)
+}
+
+fn audit_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "audit")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_audit_repository)
+}
+
+fn verify_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "verify")
+        .and(warp::post())
+        .and(warp::query::<VerifyQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_verify_repository)
+}
+
+fn gc_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "gc")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_gc_repository)
+}
+
+fn archive_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "archive")
+        .and(warp::get())
+        .and(warp::query::<ArchiveQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_archive_repository)
+}
+
+fn repository_tree(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "tree")
+        .and(warp::get())
+        .and(warp::query::<BrowseQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_repository_tree)
+}
+
+fn repository_blob(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "blob")
+        .and(warp::get())
+        .and(warp::query::<BrowseQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_repository_blob)
+}
+
+fn diff_repository(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "diff")
+        .and(warp::get())
+        .and(warp::query::<DiffQuery>())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_diff_repository)
+}
+
+fn preview_sync(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "sync" / "preview")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_preview_sync)
+}
+
+fn add_tag(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "tags")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_add_tag)
+}
+
+fn remove_tag(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "tags" / String)
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_remove_tag)
+}
+
+fn update_credentials(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "credentials")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_update_credentials)
+}
+
+fn change_url(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "url")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_change_url)
+}
+
+fn set_rate_limit(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "rate-limit")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_set_rate_limit)
+}
+
+fn set_max_size(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "size-limit")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_set_max_size)
+}
+
+fn pause_repository(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "pause")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(|url, user, db| handle_set_auto_sync(url, false, user, db))
+}
+
+fn resume_repository(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "resume")
+        .and(warp::post())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(|url, user, db| handle_set_auto_sync(url, true, user, db))
+}
+
+fn upload_bundle(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "bundle")
+        .and(warp::post())
+        .and(with_auth())
+        .and(warp::body::content_length_limit(Config::get().max_bundle_size_bytes))
+        .and(warp::body::stream())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_upload_bundle)
+}
+
+fn get_worktrees(db: Database) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "worktrees")
+        .and(warp::get())
+        .and(with_auth())
+        .and(with_db(db))
+        .and_then(handle_get_worktrees)
+}
+
+fn add_worktree(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "worktrees")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_add_worktree)
+}
+
+fn remove_worktree(db: Database, git_manager: GitManager) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    warp::path!("repositories" / String / "worktrees" / i64)
+        .and(warp::delete())
+        .and(with_auth())
+        .and(with_db(db))
+        .and(with_git_manager(git_manager))
+        .and_then(handle_remove_worktree)
+}
+
 fn with_db(db: Database) -> impl Filter<Extract = (Database,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || db.clone())
 }
@@ -80,76 +528,129 @@ fn with_git_manager(git_manager: GitManager) -> impl Filter<Extract = (GitManage
     warp::any().map(move || git_manager.clone())
 }
 
-fn with_auth() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
-    warp::header::optional::<String>("authorization")
-        .and_then(|auth_header: Option<String>| async move {
-            match auth_header {
-                Some(header) if header.starts_with("Bearer ") => {
-                    let token = &header[7..];
-                    if let Some(username) = AUTH_MANAGER.validate_session(token).await {
-                        Ok(username)
-                    } else {
-                        Err(warp::reject::custom(Unauthorized))
-                    }
-                }
-                _ => Err(warp::reject::custom(Unauthorized)),
-            }
+/// Rejects the request with `RateLimited` once its client has used up its allowance for `class`.
+pub fn with_rate_limit(class: RequestClass) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_client_ip()
+        .and_then(move |client_ip: Option<std::net::IpAddr>| async move {
+            let Some(client_ip) = client_ip else {
+                return Ok(());
+            };
+            REQUEST_LIMITER.acquire(client_ip, class).map_err(|wait| warp::reject::custom(RateLimited(wait)))
         })
+        .untuple_one()
+}
+
+/// The client's address, taken from `X-Forwarded-For` when the request came through a trusted proxy.
+pub fn with_client_ip() -> impl Filter<Extract = (Option<std::net::IpAddr>,), Error = std::convert::Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(|remote, headers| crate::client_ip::client_ip(remote, &headers))
 }
 
-fn with_auth_token() -> impl Filter<Extract = (String, String), Error = Rejection> + Clone {
+fn with_auth() -> impl Filter<Extract = (AuthUser,), Error = Rejection> + Clone {
+    with_auth_token().map(|user, _token| user)
+}
+
+fn with_auth_token() -> impl Filter<Extract = (AuthUser, String), Error = Rejection> + Clone {
     warp::header::optional::<String>("authorization")
         .and_then(|auth_header: Option<String>| async move {
-            match auth_header {
-                Some(header) if header.starts_with("Bearer ") => {
-                    let token = &header[7..];
-                    if let Some(username) = AUTH_MANAGER.validate_session(token).await {
-                        Ok((username, token.to_string()))
-                    } else {
-                        Err(warp::reject::custom(Unauthorized))
-                    }
-                }
-                _ => Err(warp::reject::custom(Unauthorized)),
+            let Some(token) = auth_header.as_deref().and_then(|header| header.strip_prefix("Bearer ")) else {
+                return Err(warp::reject::custom(MissingToken));
+            };
+            match AUTH_MANAGER.validate_session(token).await {
+                Ok(user) => Ok((user, token.to_string())),
+                Err(TokenError::Invalid) => Err(warp::reject::custom(InvalidToken)),
+                Err(TokenError::Expired) => Err(warp::reject::custom(ExpiredToken)),
             }
         })
         .untuple_one()
 }
 
-async fn handle_login(request: LoginRequest, db: Database) -> Result<Box<dyn Reply>, Rejection> {
-    match db.get_user_by_username(&request.username).await {
-        Ok(Some(user)) => {
-            if crate::auth::AuthManager::verify_password(&request.password, &user.password_hash).unwrap_or(false) {
-                let token = AUTH_MANAGER.create_session(&user.username).await;
-                let response = ApiResponse {
-                    success: true,
-                    data: Some(json!({
-                        "token": token,
-                        "username": user.username
-                    })),
-                    message: None,
-                };
-                Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK)))
-            } else {
-                let response = ApiResponse::<()> {
-                    success: false,
-                    data: None,
-                    message: Some("Invalid credentials".to_string()),
-                };
-                Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::UNAUTHORIZED)))
-            }
+async fn handle_login(request: LoginRequest, client_ip: Option<std::net::IpAddr>, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    // Failures count against both the username and the client address
+    let mut limiter_keys = vec![format!("user:{}", request.username)];
+    if let Some(client_ip) = client_ip {
+        limiter_keys.push(format!("ip:{}", client_ip));
+    }
+    if let Some(wait) = LOGIN_LIMITER.check(&limiter_keys) {
+        let retry_after = (wait.as_secs_f64().ceil() as u64).max(1);
+        return Ok(Box::new(warp::reply::with_header(
+            error_reply(
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                format!("Too many failed login attempts, try again in {} seconds", retry_after),
+            ),
+            "Retry-After",
+            retry_after.to_string(),
+        )));
+    }
+    
+    // A database failure says nothing about the password, so it isn't counted as a failed attempt
+    let user = match db.get_user_by_username(&request.username).await {
+        Ok(user) => user,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to look up user: {}", e),
+            ));
         }
-        _ => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some("Invalid credentials".to_string()),
+    };
+    // bcrypt is slow on purpose, so it runs off the async workers
+    let password = request.password.clone();
+    let hash = user.as_ref().map(|user| user.password_hash.clone());
+    let password_ok = tokio::task::spawn_blocking(move || AUTH_MANAGER.verify_login(&password, hash.as_deref()))
+        .await
+        .unwrap_or(false);
+    match user {
+        Some(user) if password_ok => {
+            LOGIN_LIMITER.reset(&limiter_keys);
+            upgrade_password_hash(&db, &user, &request.password).await;
+            let token = AUTH_MANAGER
+                .create_session(AuthUser {
+                    username: user.username.clone(),
+                    role: user.role,
+                })
+                .await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({
+                    "token": token,
+                    "expires_at": AUTH_MANAGER.expires_at(),
+                    "username": user.username,
+                    "role": user.role
+                })),
+                message: None,
             };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::UNAUTHORIZED)))
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::OK)))
+        }
+        _ => {
+            LOGIN_LIMITER.record_failure(&limiter_keys);
+            Ok(error_reply(warp::http::StatusCode::UNAUTHORIZED, ErrorCode::InvalidCredentials, "Invalid credentials".to_string()))
         }
     }
 }
 
-async fn handle_logout(_username: String, token: String) -> Result<Box<dyn Reply>, Rejection> {
+// The password is only known at login, so hashes made at an older, lower cost are upgraded then
+async fn upgrade_password_hash(db: &Database, user: &User, password: &str) {
+    if !AuthManager::needs_rehash(&user.password_hash) {
+        return;
+    }
+    let password = password.to_string();
+    let hashed = tokio::task::spawn_blocking(move || AuthManager::hash_password(&password))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|hashed| hashed);
+    let rehashed = match hashed {
+        Ok(hash) => db.update_user_password(&user.username, &hash).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = rehashed {
+        tracing::warn!("Failed to rehash the password of {}: {}", user.username, e);
+    }
+}
+
+async fn handle_logout(_user: AuthUser, token: String) -> Result<Box<dyn Reply>, Rejection> {
     // Remove the session from the auth manager
     AUTH_MANAGER.remove_session(&token).await;
     
@@ -161,14 +662,367 @@ async fn handle_logout(_username: String, token: String) -> Result<Box<dyn Reply
     Ok(Box::new(warp::reply::json(&response)))
 }
 
-async fn handle_get_repositories(pagination: PaginationQuery, _username: String, db: Database) -> Result<Box<dyn Reply>, Rejection> {
-    // Set default values for pagination
-    let page = pagination.page.unwrap_or(1).max(1);
-    let limit = pagination.limit.unwrap_or(10).min(100); // Cap at 100 items per page
-    
-    match db.get_repositories_paginated(page, limit).await {
-        Ok((repositories, total)) => {
-            let total_pages = ((total as f64) / (limit as f64)).ceil() as u32;
+async fn handle_refresh(user: AuthUser, token: String, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    // Picks up role changes, and users removed since they logged in can't keep their session alive
+    let user = match db.get_user_by_username(&user.username).await {
+        Ok(Some(user)) => AuthUser {
+            username: user.username,
+            role: user.role,
+        },
+        Ok(None) => {
+            AUTH_MANAGER.remove_session(&token).await;
+            return Ok(token_error_reply(Some(TokenError::Invalid)));
+        }
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to look up user: {}", e),
+            ));
+        }
+    };
+    
+    let token = match AUTH_MANAGER.refresh_session(&token, user.clone()).await {
+        Ok(token) => token,
+        // Refreshed or expired by a concurrent request
+        Err(e) => return Ok(token_error_reply(Some(e))),
+    };
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({
+            "token": token,
+            "expires_at": AUTH_MANAGER.expires_at(),
+            "username": user.username,
+            "role": user.role
+        })),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+// The role is the one granted at login or the last refresh, which is what requests are checked against
+async fn handle_current_user(user: AuthUser) -> Result<Box<dyn Reply>, Rejection> {
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({
+            "username": user.username,
+            "role": user.role
+        })),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+fn sessions_not_tracked_reply() -> Box<dyn Reply> {
+    error_reply(
+        warp::http::StatusCode::BAD_REQUEST,
+        ErrorCode::NotConfigured,
+        "Sessions are only tracked with AUTH_MODE=session, JWTs can't be listed or revoked".to_string(),
+    )
+}
+
+async fn handle_list_sessions(user: AuthUser) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    if !AUTH_MANAGER.tracks_sessions() {
+        return Ok(sessions_not_tracked_reply());
+    }
+    
+    let response = ApiResponse {
+        success: true,
+        data: Some(AUTH_MANAGER.list_sessions().await),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_revoke_session(id: String, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    if !AUTH_MANAGER.tracks_sessions() {
+        return Ok(sessions_not_tracked_reply());
+    }
+    
+    let Some(owner) = AUTH_MANAGER.remove_session_by_id(&id).await else {
+        return Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::SessionNotFound, "Session not found".to_string()));
+    };
+    record_audit(&db, &user, "session.revoke", &owner.username, Some(&id)).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"message": "Session revoked"})),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+// Anyone may log themselves out everywhere, only admins other users
+async fn handle_revoke_user_sessions(query: SessionQuery, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if query.username != user.username {
+        if let Err(reply) = require_admin(&user) {
+            return Ok(reply);
+        }
+    }
+    if !AUTH_MANAGER.tracks_sessions() {
+        return Ok(sessions_not_tracked_reply());
+    }
+    
+    let revoked = AUTH_MANAGER.remove_user_sessions(&query.username).await;
+    record_audit(&db, &user, "session.revoke_all", &query.username, Some(&revoked.to_string())).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"revoked": revoked})),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_health(db: Database, started_at: std::time::Instant) -> Result<Box<dyn Reply>, Rejection> {
+    let db_ok = match db.ping().await {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::error!("Health check could not reach the database: {}", e);
+            false
+        }
+    };
+    let repos_count = if db_ok { db.count_repositories().await.ok() } else { None };
+    let sync_paused = if db_ok { db.sync_paused().await.ok() } else { None };
+    
+    let health = ServiceHealth {
+        status: if db_ok { "ok" } else { "unavailable" },
+        db_ok,
+        repos_count,
+        sync_paused,
+        uptime_secs: started_at.elapsed().as_secs(),
+    };
+    let status = if db_ok {
+        warp::http::StatusCode::OK
+    } else {
+        warp::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&health), status)))
+}
+
+async fn handle_set_sync_paused(paused: bool, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    if let Err(e) = db.set_sync_paused(paused).await {
+        return Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save sync settings: {}", e),
+        ));
+    }
+    
+    let action = if paused { "sync.pause" } else { "sync.resume" };
+    record_audit(&db, &user, action, "*", None).await;
+    tracing::info!("Automatic syncing {} by {}", if paused { "paused" } else { "resumed" }, user.username);
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"sync_paused": paused})),
+        message: Some(if paused { "Automatic syncing paused" } else { "Automatic syncing resumed" }.to_string()),
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_metrics(authorization: Option<String>, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Some(token) = &Config::get().metrics_token {
+        let supplied = authorization.as_deref().and_then(|value| value.strip_prefix("Bearer "));
+        if supplied != Some(token.as_str()) {
+            return Ok(error_reply(warp::http::StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Invalid metrics token".to_string()));
+        }
+    }
+    
+    let by_status = match db.count_repositories_by_status().await {
+        Ok(by_status) => by_status,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to count repositories: {}", e),
+            ));
+        }
+    };
+    Ok(Box::new(warp::reply::with_header(
+        crate::metrics::METRICS.render(&by_status),
+        "content-type",
+        "text/plain; version=0.0.4",
+    )))
+}
+
+async fn handle_github_webhook(
+    signature: Option<String>,
+    event: Option<String>,
+    body: warp::hyper::body::Bytes,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let Some(secret) = &Config::get().github_webhook_secret else {
+        return Ok(error_reply(
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::NotConfigured,
+            "GitHub webhooks are not configured, set GITHUB_WEBHOOK_SECRET".to_string(),
+        ));
+    };
+    if !signature.is_some_and(|signature| verify_github_signature(secret.as_bytes(), &body, &signature)) {
+        return Ok(error_reply(warp::http::StatusCode::UNAUTHORIZED, ErrorCode::InvalidSignature, "Invalid webhook signature".to_string()));
+    }
+    
+    // GitHub sends a ping when the webhook is created; only pushes change the repository
+    match event.as_deref() {
+        None | Some("push") => {}
+        Some(event) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": format!("Ignored {} event", event)})),
+                message: None,
+            };
+            return Ok(Box::new(warp::reply::json(&response)));
+        }
+    }
+    
+    let payload: GithubPushEvent = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, format!("Invalid push event: {}", e))),
+    };
+    let repository = payload.repository;
+    let mut repo = None;
+    for url in [repository.clone_url, repository.ssh_url, repository.html_url].into_iter().flatten() {
+        match db.get_repository_matching_url(&url).await {
+            Ok(Some(found)) if found.deleted_at.is_none() => {
+                repo = Some(found);
+                break;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    format!("Failed to look up repository: {}", e),
+                ));
+            }
+        }
+    }
+    let Some(repo) = repo else {
+        return Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::RepoNotFound, "Repository is not tracked".to_string()));
+    };
+    let paused = match db.sync_paused().await {
+        Ok(paused) => paused,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to read sync settings: {}", e),
+            ));
+        }
+    };
+    if paused || !repo.auto_sync_enabled {
+        let scope = if paused { "all repositories" } else { "this repository" };
+        let response = ApiResponse {
+            success: true,
+            data: Some(json!({"message": format!("Automatic syncing is paused for {}", scope)})),
+            message: None,
+        };
+        return Ok(Box::new(warp::reply::json(&response)));
+    }
+    
+    // A burst of pushes only needs one sync, plus one after an operation that was already running
+    let message = if git_manager.current_operation(&repo.url).is_some() {
+        if crate::sync::resync_after_current_operation(db, git_manager, &repo) {
+            "Another operation is running, the repository is synced once it finishes"
+        } else {
+            "A sync after the running operation is already queued"
+        }
+    } else {
+        let Some(slot) = git_manager.try_acquire_slot() else {
+            return Ok(overloaded_reply());
+        };
+        // Keeps the request's span, so that the sync's logs carry the id of the webhook delivery
+        tokio::spawn(
+            async move {
+                let _slot = slot;
+                if let Err(e) = crate::sync::sync_one_repository(&db, &git_manager, &repo).await {
+                    tracing::error!("Failed to record sync result for {}: {}", repo.url, e);
+                }
+            }
+            .in_current_span(),
+        );
+        "Sync started"
+    };
+    
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"message": message})),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::ACCEPTED)))
+}
+
+async fn handle_get_stats(_user: AuthUser, db: Database, git_manager: GitManager) -> Result<Box<dyn Reply>, Rejection> {
+    let repositories = match db.count_repositories().await {
+        Ok(count) => count,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to get repositories: {}", e),
+            ));
+        }
+    };
+    let total_size_bytes = match db.total_repository_size().await {
+        Ok(total) => total,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to get repository sizes: {}", e),
+            ));
+        }
+    };
+    let (in_flight, max_in_flight, running) = git_manager.load();
+    
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({
+            "repositories": repositories,
+            "total_size_bytes": total_size_bytes,
+            "in_flight_operations": in_flight,
+            "max_concurrent_operations": max_in_flight,
+            "running_operations": running,
+        })),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_get_repositories(
+    pagination: PaginationQuery,
+    filter: RepositoryFilter,
+    if_none_match: Option<String>,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Some(status) = filter.status.as_deref().filter(|status| !status.is_empty()) {
+        if let Err(e) = status.parse::<RepoStatus>() {
+            return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, e));
+        }
+    }
+
+    // Set default values for pagination
+    let page = pagination.page.unwrap_or(1).max(1);
+    let limit = pagination.limit.unwrap_or(20).clamp(1, 100); // Cap at 100 items per page
+    
+    match db.search_repositories(&filter, page, limit).await {
+        Ok((mut repositories, total)) => {
+            for repo in repositories.iter_mut() {
+                set_operation_state(repo, &git_manager);
+                repo.health = Some(crate::health::assess(repo));
+            }
+            let total_pages = (total as u64).div_ceil(limit as u64) as u32;
             
             let paginated_response = PaginatedResponse {
                 items: repositories,
@@ -180,258 +1034,2284 @@ async fn handle_get_repositories(pagination: PaginationQuery, _username: String,
             
             let response = ApiResponse {
                 success: true,
-                data: Some(paginated_response),
+                data: Some(paginated_response),
+                message: None,
+            };
+            Ok(conditional_json_reply(&response, if_none_match.as_deref()))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to fetch repositories: {}", e),
+        )),
+    }
+}
+
+async fn handle_get_repository(
+    url: String,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let mut repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    set_operation_state(&mut repo, &git_manager);
+    repo.health = Some(crate::health::assess(&repo));
+    
+    let current_branch = git_manager.current_branch(&repo.local_path).await;
+    let stats = git_manager.repo_stats(&repo.local_path).await;
+    let last_audit = match db.get_last_audit(repo.id).await {
+        Ok(last_audit) => last_audit,
+        Err(e) => {
+            tracing::warn!("Failed to load last audit of {}: {}", repo.url, e);
+            None
+        }
+    };
+    let response = ApiResponse {
+        success: true,
+        data: Some(RepositoryDetail {
+            repository: repo,
+            current_branch,
+            stats,
+            audit_ok: last_audit.map(|(ok, _)| ok),
+            last_audited: last_audit.map(|(_, audited_at)| audited_at),
+        }),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_add_repository(
+    request: AddRepositoryRequest,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    match add_repository_entry(&db, &git_manager, request).await {
+        Ok(added) => {
+            let adopted = added["adopted"] == true;
+            record_audit(&db, &user, "repository.add", added["url"].as_str().unwrap_or_default(), adopted.then_some("adopted")).await;
+            let message = if added["status"] == RepoStatus::LfsPointersOnly.as_str() {
+                "Repository cloned, but it uses Git LFS and only pointer files were checked out"
+            } else if adopted {
+                "Existing clone adopted"
+            } else {
+                "Repository cloned successfully"
+            };
+            let response = ApiResponse {
+                success: true,
+                data: Some(added),
+                message: Some(message.to_string()),
+            };
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::CREATED)))
+        }
+        Err(failure) => Ok(error_reply(failure.status, failure.code, failure.message)),
+    }
+}
+
+/// Why adding a repository failed.
+#[derive(Debug)]
+pub struct AddFailure {
+    pub status: warp::http::StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl AddFailure {
+    fn new(status: warp::http::StatusCode, code: ErrorCode, message: String) -> Self {
+        AddFailure { status, code, message }
+    }
+}
+
+/// Validates, clones and stores one repository; shared by single adds, imports and `gitc repo add`.
+/// Callers are expected to hold an operation slot.
+pub async fn add_repository_entry(
+    db: &Database,
+    git_manager: &GitManager,
+    mut request: AddRepositoryRequest,
+) -> Result<serde_json::Value, AddFailure> {
+    // Credentials in the URL would be stored and shown in plain text, they are kept encrypted like any others
+    let (url, embedded) = strip_credentials(&request.url);
+    request.url = url;
+    if request.credentials.is_none() {
+        request.credentials = embedded;
+    }
+    
+    // Reject URLs no name can be derived from before anything is cloned; the stored name is the clone's own
+    let repo_url = RepoUrl::parse(&request.url)
+        .map_err(|e| AddFailure::new(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidUrl, format!("Invalid repository URL: {}", e)))?;
+    check_host(&repo_url).map_err(|e| AddFailure::new(warp::http::StatusCode::FORBIDDEN, ErrorCode::HostNotAllowed, e.to_string()))?;
+    
+    // However the remote is spelled, e.g. over SSH instead of HTTPS
+    match db.get_repository_for_remote(&repo_url).await {
+        Ok(None) => {}
+        // The URL stays taken while a removed repository waits in the trash
+        Ok(Some(existing)) if existing.deleted_at.is_some() => {
+            return Err(AddFailure::new(
+                warp::http::StatusCode::CONFLICT,
+                ErrorCode::RepoInTrash,
+                format!("{} was removed and is still in the trash, restore it instead", existing.url),
+            ));
+        }
+        Ok(Some(existing)) => {
+            return Err(AddFailure::new(
+                warp::http::StatusCode::CONFLICT,
+                ErrorCode::RepoAlreadyExists,
+                format!("This repository is already tracked as {}", existing.url),
+            ));
+        }
+        Err(e) => {
+            return Err(AddFailure::new(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to check for existing repositories: {}", e),
+            ));
+        }
+    }
+    
+    if let Some(template) = request.template.clone() {
+        apply_template(db, &template, &mut request).await?;
+    }
+    
+    let branch = request.branch.as_deref().map(str::trim).filter(|b| !b.is_empty());
+    let mirror = request.mirror.unwrap_or(false);
+    let recurse_submodules = request.recurse_submodules.unwrap_or(false);
+    let auto_sync_enabled = request.auto_sync_enabled.unwrap_or(true);
+    
+    if request.credentials.is_some() && !crate::crypto::is_configured() {
+        return Err(AddFailure::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::NotConfigured,
+            "Credentials were supplied but CREDENTIALS_KEY is not configured on the server".to_string(),
+        ));
+    }
+    
+    let ssh_key_path = request.ssh_key_path.as_deref().map(str::trim).filter(|p| !p.is_empty());
+    
+    // A mirror always copies every ref, so there is no single branch to track
+    if mirror && branch.is_some() {
+        return Err(AddFailure::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Mirror clones copy every branch and can't track a single one".to_string(),
+        ));
+    }
+    if mirror && recurse_submodules {
+        return Err(AddFailure::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Mirror clones have no working tree to check out submodules in".to_string(),
+        ));
+    }
+    if request.adopt && request.overwrite {
+        return Err(AddFailure::new(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "An existing clone can either be adopted or overwritten, not both".to_string(),
+        ));
+    }
+    
+    let tags = request
+        .tags
+        .iter()
+        .map(|tag| validate_tag(tag))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|message| AddFailure::new(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, message))?;
+    
+    let options = CloneOptions {
+        branch: branch.map(|b| b.to_string()),
+        credentials: request.credentials.clone(),
+        ssh_key_path: ssh_key_path.map(|p| p.to_string()),
+        mirror,
+        recurse_submodules,
+        overwrite: request.overwrite,
+        rate_limit_kbps: request.fetch_rate_limit_kbps,
+        max_size_mb: request.max_size_mb,
+    };
+    
+    let adopted = if request.adopt {
+        git_manager.adopt_repository(&request.url, &options).await.map_err(|e| {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                _ => (warp::http::StatusCode::CONFLICT, ErrorCode::CloneFailed),
+            };
+            AddFailure::new(status, code, format!("Failed to adopt existing clone: {}", e))
+        })?
+    } else {
+        None
+    };
+    
+    let save_failure = |e: anyhow::Error| {
+        AddFailure::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to save repository: {}", e))
+    };
+    let (cloned, repository_id) = match adopted {
+        Some(adopted) => {
+            let repository_id = db
+                .add_repository(&request.url, &adopted.name, &adopted.local_path, &options, RepoStatus::Pending)
+                .await
+                .map_err(save_failure)?;
+            (adopted, repository_id)
+        }
+        None => {
+            // Stored before cloning so the repository shows up as `cloning` while the clone runs
+            let (name, local_path) = git_manager
+                .clone_location(&request.url)
+                .map_err(|e| AddFailure::new(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidUrl, format!("Invalid repository URL: {}", e)))?;
+            let repository_id = db
+                .add_repository(&request.url, &name, &local_path.to_string_lossy(), &options, RepoStatus::Cloning)
+                .await
+                .map_err(save_failure)?;
+            match git_manager.clone_repository(&request.url, &options).await {
+                Ok(cloned) => (cloned, repository_id),
+                Err(e) => {
+                    if let Err(purge_err) = db.purge_repository(repository_id).await {
+                        // The startup scan drops it, there is no clone to keep
+                        tracing::error!("Failed to remove {} after its clone failed: {}", request.url, purge_err);
+                    }
+                    let (status, code) = match e.downcast_ref::<GitError>() {
+                        Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                        Some(GitError::InsufficientSpace { .. }) => (warp::http::StatusCode::INSUFFICIENT_STORAGE, ErrorCode::InsufficientStorage),
+                        Some(GitError::TooLarge { .. }) => (warp::http::StatusCode::INSUFFICIENT_STORAGE, ErrorCode::RepoTooLarge),
+                        Some(GitError::Cancelled) => (warp::http::StatusCode::CONFLICT, ErrorCode::Cancelled),
+                        Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                        _ => (warp::http::StatusCode::BAD_REQUEST, ErrorCode::CloneFailed),
+                    };
+                    return Err(AddFailure::new(status, code, format!("Failed to clone repository: {}", e)));
+                }
+            }
+        }
+    };
+    
+    crate::sync::refresh_local_details(db, git_manager, &request.url, &cloned.local_path).await;
+    
+    if let Some(credentials) = &request.credentials {
+        db.set_credentials(repository_id, credentials).await.map_err(|e| {
+            AddFailure::new(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to save repository credentials: {}", e),
+            )
+        })?;
+    }
+    
+    db.add_tags(repository_id, &tags).await.map_err(|e| {
+        AddFailure::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to save repository tags: {}", e))
+    })?;
+    
+    if !auto_sync_enabled {
+        db.set_auto_sync(repository_id, false).await.map_err(|e| {
+            AddFailure::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to pause repository: {}", e))
+        })?;
+    }
+    
+    // An adopted clone is as fresh as it was when the backup was taken; the next sync brings it up to date
+    let status = if cloned.lfs_pointers_only {
+        RepoStatus::LfsPointersOnly
+    } else if cloned.adopted {
+        RepoStatus::Synced
+    } else {
+        RepoStatus::Pending
+    };
+    db.update_repository_status(&request.url, status).await.map_err(|e| {
+        AddFailure::new(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to save repository status: {}", e))
+    })?;
+    
+    Ok(json!({
+        "url": request.url,
+        "name": cloned.name,
+        "local_path": cloned.local_path,
+        "status": status,
+        "branch": branch,
+        "mirror": mirror,
+        "recurse_submodules": recurse_submodules,
+        "auto_sync_enabled": auto_sync_enabled,
+        "adopted": cloned.adopted,
+        "tags": tags
+    }))
+}
+
+// Copies a template's settings onto an add request, keeping anything the request sets itself.
+// The repository gets its own copy, so later template edits don't affect it.
+async fn apply_template(db: &Database, name: &str, request: &mut AddRepositoryRequest) -> Result<(), AddFailure> {
+    let template = match db.get_template(name).await {
+        Ok(Some(template)) => template,
+        Ok(None) => {
+            return Err(AddFailure::new(warp::http::StatusCode::BAD_REQUEST, ErrorCode::TemplateNotFound, format!("Unknown template '{}'", name)));
+        }
+        Err(e) => {
+            return Err(AddFailure::new(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to load template: {}", e),
+            ));
+        }
+    };
+    
+    if request.branch.is_none() {
+        request.branch = template.branch;
+    }
+    if request.ssh_key_path.is_none() {
+        request.ssh_key_path = template.ssh_key_path;
+    }
+    if request.tags.is_empty() {
+        request.tags = template.tags;
+    }
+    request.auto_sync_enabled = request.auto_sync_enabled.or(template.auto_sync_enabled);
+    request.mirror = request.mirror.or(template.mirror);
+    request.recurse_submodules = request.recurse_submodules.or(template.recurse_submodules);
+    request.fetch_rate_limit_kbps = request.fetch_rate_limit_kbps.or(template.fetch_rate_limit_kbps.map(|kbps| kbps as u64));
+    request.max_size_mb = request.max_size_mb.or(template.max_size_mb.map(|mb| mb as u64));
+    if request.credentials.is_none() && template.has_credentials {
+        match db.get_template_credentials(name).await {
+            Ok(credentials) => request.credentials = credentials,
+            Err(e) => {
+                return Err(AddFailure::new(
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    format!("Failed to load template credentials: {}", e),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_export_repositories(_user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    match db.get_all_repositories().await {
+        Ok(repositories) => {
+            let export = RepositoryExport {
+                repositories: repositories
+                    .into_iter()
+                    .map(|repo| ExportedRepository {
+                        url: repo.url,
+                        name: Some(repo.name),
+                        branch: repo.branch,
+                        ssh_key_path: repo.ssh_key_path,
+                        tags: repo.tags,
+                        mirror: repo.mirror,
+                        recurse_submodules: repo.recurse_submodules,
+                        fetch_rate_limit_kbps: repo.fetch_rate_limit_kbps.map(|kbps| kbps.max(0) as u64),
+                        max_size_mb: repo.max_size_mb.map(|mb| mb.max(0) as u64),
+                        auto_sync_enabled: repo.auto_sync_enabled,
+                    })
+                    .collect(),
+            };
+            let response = ApiResponse {
+                success: true,
+                data: Some(export),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to get repositories: {}", e),
+        )),
+    }
+}
+
+async fn handle_import_repositories(
+    import: RepositoryExport,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    // Entries are cloned one after another, so the whole import takes a single slot
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    let mut added = Vec::new();
+    let mut skipped = Vec::new();
+    let mut failed = Vec::new();
+    for entry in import.repositories {
+        let request = AddRepositoryRequest {
+            url: entry.url.clone(),
+            branch: entry.branch,
+            credentials: None,
+            ssh_key_path: entry.ssh_key_path,
+            template: None,
+            tags: entry.tags,
+            mirror: Some(entry.mirror),
+            recurse_submodules: Some(entry.recurse_submodules),
+            overwrite: false,
+            adopt: false,
+            fetch_rate_limit_kbps: entry.fetch_rate_limit_kbps,
+            max_size_mb: entry.max_size_mb,
+            auto_sync_enabled: Some(entry.auto_sync_enabled),
+        };
+        match add_repository_entry(&db, &git_manager, request).await {
+            Ok(_) => {
+                record_audit(&db, &user, "repository.add", &entry.url, Some("import")).await;
+                added.push(entry.url);
+            }
+            // Already tracked (or in the trash): importing again is a no-op
+            Err(failure) if matches!(failure.code, ErrorCode::RepoAlreadyExists | ErrorCode::RepoInTrash) => skipped.push(entry.url),
+            Err(failure) => {
+                tracing::warn!("Failed to import {}: {}", entry.url, failure.message);
+                failed.push(json!({"url": entry.url, "error": failure.message, "code": failure.code}));
+            }
+        }
+    }
+    
+    let response = ApiResponse {
+        success: failed.is_empty(),
+        data: Some(json!({
+            "added": added,
+            "skipped": skipped,
+            "failed": failed,
+        })),
+        message: None,
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_remove_repository(
+    url: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    // Keep the files around until the trash is purged so a mistaken delete can be undone. The
+    // row is only removed once the move succeeded, so a failure never leaves untracked files
+    if let Err(e) = git_manager.move_to_trash(&repo).await {
+        let (status, code) = match e.downcast_ref::<GitError>() {
+            Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+            _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError),
+        };
+        return Ok(error_reply(status, code, format!("Failed to move repository files to the trash: {}", e)));
+    }
+    
+    match db.remove_repository_by_id(repo.id).await {
+        Ok(_) => {
+            record_audit(&db, &user, "repository.remove", &repo.url, None).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Repository moved to the trash, it can be restored until it is purged"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to remove repository from database: {}", e),
+        )),
+    }
+}
+
+async fn handle_restore_repository(
+    url: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let decoded_url = urlencoding::decode(&url).map(|u| u.into_owned()).unwrap_or(url);
+    let repo = match db.get_deleted_repository_by_url(&decoded_url).await {
+        Ok(Some(repo)) => repo,
+        Ok(None) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::NOT_FOUND,
+                ErrorCode::RepoNotFound,
+                "No removed repository with this URL is in the trash".to_string(),
+            ));
+        }
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to get repository info: {}", e),
+            ));
+        }
+    };
+    
+    if let Err(e) = git_manager.restore_from_trash(&repo).await {
+        let code = match e.downcast_ref::<GitError>() {
+            Some(GitError::Busy) => ErrorCode::OperationInProgress,
+            _ => ErrorCode::RestoreFailed,
+        };
+        return Ok(error_reply(warp::http::StatusCode::CONFLICT, code, format!("Failed to restore repository files: {}", e)));
+    }
+    
+    match db.restore_repository(repo.id).await {
+        Ok(_) => {
+            record_audit(&db, &user, "repository.restore", &repo.url, None).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Repository restored"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to restore repository: {}", e),
+        )),
+    }
+}
+
+async fn handle_sync_repository(
+    url: String,
+    query: SyncQuery,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    if query.respect_pause.unwrap_or(false) {
+        match db.sync_paused().await {
+            Ok(false) => {}
+            Ok(true) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::SERVICE_UNAVAILABLE,
+                    ErrorCode::SyncPaused,
+                    "Automatic syncing is paused, retry once it is resumed or leave out respect_pause".to_string(),
+                ));
+            }
+            Err(e) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    format!("Failed to read sync settings: {}", e),
+                ));
+            }
+        }
+    }
+    // Only reorders the queue, the daily sync's running syncs carry on
+    if query.priority == Some(SyncPriority::High) && crate::sync::SYNC_QUEUE.prioritize(&repo.url) {
+        record_audit(&db, &user, "repository.sync", &repo.url, Some("prioritized")).await;
+        let response = ApiResponse {
+            success: true,
+            data: Some(json!({"message": "Sync moved to the front of the queue, it starts next"})),
+            message: None,
+        };
+        return Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::ACCEPTED)));
+    }
+    if repo.status == RepoStatus::Cloning {
+        return Ok(error_reply(
+            warp::http::StatusCode::CONFLICT,
+            ErrorCode::OperationInProgress,
+            "The repository is still being cloned".to_string(),
+        ));
+    }
+    let force = query.force.unwrap_or(false);
+    if let Some(retry_after) = manual_sync_cooldown(&repo, force, Config::get().manual_sync_min_interval, chrono::Utc::now()) {
+        return Ok(Box::new(warp::reply::with_header(
+            error_reply(
+                warp::http::StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                format!("Repository was synced recently, retry in {} seconds or pass force=true", retry_after),
+            ),
+            "Retry-After",
+            retry_after.to_string(),
+        )));
+    }
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    let mut options = match db.get_sync_options(&repo).await {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to load repository settings: {}", e),
+            ));
+        }
+    };
+    options.reset = query.reset.unwrap_or(false) && !repo.mirror;
+    if options.reset {
+        tracing::warn!("{} requested a hard reset of {} to upstream", user.username, repo.url);
+    }
+    let started_at = chrono::Utc::now();
+    if let Err(e) = crate::sync::mark_sync_started(&db, &repo.url).await {
+        tracing::warn!("Failed to mark {} as syncing: {}", repo.url, e);
+    }
+    let mut result = git_manager.sync_repository(&repo, &options).await;
+    let mut url = repo.url.clone();
+    if let Some(new_url) = crate::sync::moved_to(&result) {
+        match crate::sync::record_move(&db, &git_manager, &user.username, &repo, &new_url).await {
+            Ok(()) => url = new_url,
+            Err(e) => {
+                tracing::error!("Failed to follow moved repository {}: {:#}", repo.url, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    crate::sync::record_sync_history(&db, repo.id, started_at, &result).await;
+    match result {
+        Ok(outcome) => {
+            if let Err(e) = record_sync_outcome(&db, &url, outcome.status(), None).await {
+                tracing::error!("Failed to record the sync of {}: {}", url, e);
+            }
+            crate::sync::refresh_local_details(&db, &git_manager, &url, &repo.local_path).await;
+            let detail = if options.reset { format!("{}, reset", outcome.status()) } else { outcome.status().to_string() };
+            record_audit(&db, &user, "repository.sync", &url, Some(&detail)).await;
+            
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({
+                    "message": "Repository synced successfully",
+                    "status": outcome.status(),
+                    "moved_to": outcome.moved_to
+                })),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) if matches!(e.downcast_ref::<GitError>(), Some(GitError::Busy)) => {
+            if let Err(e) = crate::sync::restore_skipped_status(&db, &git_manager, &repo).await {
+                tracing::error!("Failed to restore the status of {}: {}", repo.url, e);
+            }
+            Ok(error_reply(warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress, format!("Failed to sync repository: {}", e)))
+        }
+        Err(e) => {
+            if let Err(record_err) = record_sync_outcome(&db, &url, failure_status(&e), Some(&format!("{:#}", e))).await {
+                tracing::error!("Failed to record the failed sync of {}: {}", url, record_err);
+            }
+            record_audit(&db, &user, "repository.sync", &url, Some(&format!("failed: {:#}", e))).await;
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                Some(GitError::Cancelled) => (warp::http::StatusCode::CONFLICT, ErrorCode::Cancelled),
+                Some(GitError::TooLarge { .. }) => (warp::http::StatusCode::INSUFFICIENT_STORAGE, ErrorCode::RepoTooLarge),
+                _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::SyncFailed),
+            };
+            Ok(error_reply(status, code, format!("Failed to sync repository: {:#}", e)))
+        }
+    }
+}
+
+// Stores how a sync ended; `last_synced` only moves on success, when there is no error
+async fn record_sync_outcome(db: &Database, url: &str, status: RepoStatus, error: Option<&str>) -> anyhow::Result<()> {
+    db.update_repository_status(url, status).await?;
+    db.update_last_error(url, error).await?;
+    if error.is_none() {
+        db.update_last_synced(url).await?;
+    }
+    Ok(())
+}
+
+async fn handle_get_sync_history(url: String, pagination: PaginationQuery, _user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let page = pagination.page.unwrap_or(1).max(1);
+    let limit = pagination.limit.unwrap_or(20).clamp(1, 100);
+    
+    match db.get_sync_history(repo.id, page, limit).await {
+        Ok((entries, total)) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(PaginatedResponse {
+                    items: entries,
+                    total,
+                    page,
+                    limit,
+                    total_pages: (total as u64).div_ceil(limit as u64) as u32,
+                }),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to fetch the sync history: {}", e),
+        )),
+    }
+}
+
+async fn handle_get_audit_log(pagination: PaginationQuery, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let page = pagination.page.unwrap_or(1).max(1);
+    let limit = pagination.limit.unwrap_or(50).clamp(1, 100);
+    
+    match db.get_audit_log(page, limit).await {
+        Ok((entries, total)) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(PaginatedResponse {
+                    items: entries,
+                    total,
+                    page,
+                    limit,
+                    total_pages: (total as u64).div_ceil(limit as u64) as u32,
+                }),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to fetch the audit log: {}", e),
+        )),
+    }
+}
+
+async fn handle_get_progress(url: String, _user: AuthUser, git_manager: GitManager) -> Result<Box<dyn Reply>, Rejection> {
+    // No database lookup: a repository being cloned has no row yet
+    let decoded_url = urlencoding::decode(&url).map(|u| u.into_owned()).unwrap_or(url);
+    let Some(receiver) = git_manager.subscribe_progress(&decoded_url) else {
+        return Ok(error_reply(
+            warp::http::StatusCode::NOT_FOUND,
+            ErrorCode::NoOperationRunning,
+            "No operation in progress for this repository".to_string(),
+        ));
+    };
+    
+    // Emit the current progress first, then every update, and a final `done` event once the
+    // operation ends and the channel closes
+    let events = futures::stream::unfold(Some((receiver, true)), |state| async move {
+        let (mut receiver, first) = state?;
+        if !first && receiver.changed().await.is_err() {
+            return Some((warp::sse::Event::default().event("done").data("{}"), None));
+        }
+        let progress = receiver.borrow_and_update().clone();
+        let event = warp::sse::Event::default()
+            .event("progress")
+            .json_data(&progress)
+            .unwrap_or_else(|_| warp::sse::Event::default().event("progress"));
+        Some((event, Some((receiver, false))))
+    })
+    .map(Ok::<_, std::convert::Infallible>);
+    
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events))))
+}
+
+async fn handle_cancel_operation(
+    segment: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    // A repository being cloned has no row yet, so fall back to the URL as given
+    let url = match lookup_repository(&db, &segment).await {
+        Ok(repo) => repo.url,
+        Err(_) => urlencoding::decode(&segment).map(|u| u.into_owned()).unwrap_or(segment),
+    };
+    match git_manager.cancel_operation(&url) {
+        Some(Operation::Clone | Operation::Sync) => {
+            record_audit(&db, &user, "repository.cancel", &url, None).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Cancellation requested, the operation stops shortly"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::ACCEPTED)))
+        }
+        Some(_) => Ok(error_reply(
+            warp::http::StatusCode::CONFLICT,
+            ErrorCode::OperationInProgress,
+            "Only clones and syncs can be cancelled".to_string(),
+        )),
+        None => Ok(error_reply(
+            warp::http::StatusCode::NOT_FOUND,
+            ErrorCode::NoOperationRunning,
+            "No operation in progress for this repository".to_string(),
+        )),
+    }
+}
+
+async fn handle_audit_repository(
+    url: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let options = match db.get_sync_options(&repo).await {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to load repository settings: {}", e),
+            ));
+        }
+    };
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    match git_manager.audit_repository(&repo, &options).await {
+        Ok(report) => {
+            if let Err(e) = db.save_audit_report(repo.id, &report).await {
+                tracing::error!("Failed to record audit of {}: {}", repo.url, e);
+            }
+            let response = ApiResponse {
+                success: true,
+                data: Some(report),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                _ => (warp::http::StatusCode::BAD_GATEWAY, ErrorCode::AuditFailed),
+            };
+            Ok(error_reply(status, code, format!("Failed to audit repository: {}", e)))
+        }
+    }
+}
+
+async fn handle_verify_repository(
+    url: String,
+    query: VerifyQuery,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    match git_manager.verify_repository(&repo, query.fsck.unwrap_or(false)).await {
+        Ok(report) => {
+            if !report.ok {
+                tracing::warn!("Verification of {} found {} problems", repo.url, report.problems.len());
+                let _ = db.update_repository_status(&repo.url, RepoStatus::Corrupt).await;
+                let _ = db.update_last_error(&repo.url, Some(&report.problems.join("; "))).await;
+            } else if repo.status == RepoStatus::Corrupt {
+                // Repaired by hand since the last verification
+                let status = if repo.last_synced.is_some() { RepoStatus::Synced } else { RepoStatus::Pending };
+                let _ = db.update_repository_status(&repo.url, status).await;
+                let _ = db.update_last_error(&repo.url, None).await;
+            }
+            let detail = if report.ok { "ok".to_string() } else { format!("{} problems", report.problems.len()) };
+            record_audit(&db, &user, "repository.verify", &repo.url, Some(&detail)).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(report),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::VerifyFailed),
+            };
+            Ok(error_reply(status, code, format!("Failed to verify repository: {}", e)))
+        }
+    }
+}
+
+async fn handle_gc_repository(
+    url: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    match crate::sync::collect_garbage(&db, &git_manager, &repo).await {
+        Ok(collected) => {
+            let detail = format!("{} bytes reclaimed", collected.reclaimed_bytes);
+            record_audit(&db, &user, "repository.gc", &repo.url, Some(&detail)).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(collected),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::GcFailed),
+            };
+            Ok(error_reply(status, code, format!("Failed to collect garbage: {}", e)))
+        }
+    }
+}
+
+async fn handle_archive_repository(
+    url: String,
+    query: ArchiveQuery,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let format = query.format.unwrap_or_default();
+    let reference = query.reference.as_deref().map(str::trim).filter(|r| !r.is_empty()).unwrap_or("HEAD");
+    
+    // Held until the download finishes, not just until it starts
+    let Some(slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    let archive = match git_manager.archive(&repo, reference, format).await {
+        Ok(archive) => archive,
+        Err(e) => {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::RefNotFound(_)) => (warp::http::StatusCode::NOT_FOUND, ErrorCode::RefNotFound),
+                _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::ArchiveFailed),
+            };
+            return Ok(error_reply(status, code, format!("Failed to archive repository: {}", e)));
+        }
+    };
+    let body = warp::hyper::Body::wrap_stream(archive.map(move |chunk| {
+        let _slot = &slot;
+        chunk
+    }));
+    
+    let mut response = warp::http::Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(warp::http::header::CONTENT_TYPE, warp::http::HeaderValue::from_static(format.content_type()));
+    if let Ok(disposition) = warp::http::HeaderValue::from_str(&format!("attachment; filename=\"{}\"", archive_file_name(&repo.name, reference, format))) {
+        headers.insert(warp::http::header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(Box::new(response))
+}
+
+async fn handle_repository_tree(
+    url: String,
+    query: BrowseQuery,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let reference = query.reference.as_deref().map(str::trim).filter(|r| !r.is_empty()).unwrap_or("HEAD");
+
+    match git_manager.list_tree(&repo, reference, query.path.as_deref().unwrap_or_default()).await {
+        Ok(listing) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(listing),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(browse_error_reply(e)),
+    }
+}
+
+async fn handle_repository_blob(
+    url: String,
+    query: BrowseQuery,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let reference = query.reference.as_deref().map(str::trim).filter(|r| !r.is_empty()).unwrap_or("HEAD");
+
+    match git_manager.read_blob(&repo, reference, query.path.as_deref().unwrap_or_default()).await {
+        Ok(blob) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(blob),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(browse_error_reply(e)),
+    }
+}
+
+async fn handle_diff_repository(
+    url: String,
+    query: DiffQuery,
+    _user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let from = query.from.trim();
+    if from.is_empty() {
+        return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "The from ref is required".to_string()));
+    }
+    let to = query.to.as_deref().map(str::trim).filter(|r| !r.is_empty()).unwrap_or("HEAD");
+
+    match git_manager.diff(&repo, from, to, query.patch.unwrap_or(false)).await {
+        Ok(diff) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(diff),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(browse_error_reply(e)),
+    }
+}
+
+fn browse_error_reply(e: anyhow::Error) -> Box<dyn Reply> {
+    let (status, code) = match e.downcast_ref::<GitError>() {
+        Some(GitError::RefNotFound(_)) => (warp::http::StatusCode::NOT_FOUND, ErrorCode::RefNotFound),
+        Some(GitError::PathNotFound(_)) => (warp::http::StatusCode::NOT_FOUND, ErrorCode::PathNotFound),
+        Some(GitError::InvalidPath(_)) | Some(GitError::WrongEntryType { .. }) => (warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest),
+        Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+        _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError),
+    };
+    error_reply(status, code, format!("Failed to read repository: {}", e))
+}
+
+// `demo-v1.2.tar.gz` for the `v1.2` tag of `gitlab.com/group/demo`
+fn archive_file_name(name: &str, reference: &str, format: ArchiveFormat) -> String {
+    let base = name.rsplit('/').next().unwrap_or(name);
+    let reference: String = reference
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '_' { c } else { '-' })
+        .collect();
+    format!("{}-{}.{}", base, reference, format.extension())
+}
+
+async fn handle_preview_sync(
+    url: String,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    if repo.mirror {
+        return Ok(error_reply(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Mirrors can't be previewed, audit them instead".to_string(),
+        ));
+    }
+    let options = match db.get_sync_options(&repo).await {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to load repository settings: {}", e),
+            ));
+        }
+    };
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    match git_manager.preview_sync(&repo, &options).await {
+        Ok(preview) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(preview),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => {
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                _ => (warp::http::StatusCode::BAD_GATEWAY, ErrorCode::SyncFailed),
+            };
+            Ok(error_reply(status, code, format!("Failed to preview sync: {}", e)))
+        }
+    }
+}
+
+async fn handle_update_credentials(
+    url: String,
+    credentials: RepositoryCredentials,
+    user: AuthUser,
+    db: Database,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    if !crate::crypto::is_configured() {
+        return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::NotConfigured, "CREDENTIALS_KEY is not configured on the server".to_string()));
+    }
+    
+    match db.set_credentials(repo.id, &credentials).await {
+        Ok(_) => {
+            // Only that they changed, never the secret
+            record_audit(&db, &user, "repository.credentials", &repo.url, None).await;
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Repository credentials updated successfully"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository credentials: {}", e),
+        )),
+    }
+}
+
+async fn handle_change_url(
+    url: String,
+    request: ChangeUrlRequest,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let new_url = request.url.trim();
+    let repo_url = match RepoUrl::parse(new_url) {
+        Ok(repo_url) => repo_url,
+        Err(e) => return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidUrl, format!("Invalid repository URL: {}", e))),
+    };
+    if let Err(e) = check_host(&repo_url) {
+        return Ok(error_reply(warp::http::StatusCode::FORBIDDEN, ErrorCode::HostNotAllowed, e.to_string()));
+    }
+    let name = repo_url.dir_name();
+    
+    match db.get_repository_matching_url(new_url).await {
+        Ok(Some(existing)) if existing.id != repo.id => {
+            let (code, message) = match existing.deleted_at {
+                Some(_) => (ErrorCode::RepoInTrash, format!("{} was removed and is still in the trash", existing.url)),
+                None => (ErrorCode::RepoAlreadyExists, format!("{} is already tracked as another repository", existing.url)),
+            };
+            return Ok(error_reply(warp::http::StatusCode::CONFLICT, code, message));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to check for existing repositories: {}", e),
+            ));
+        }
+    }
+    
+    if request.verify {
+        let credentials = match db.get_credentials(repo.id).await {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    format!("Failed to load repository credentials: {}", e),
+                ));
+            }
+        };
+        let Some(_slot) = git_manager.try_acquire_slot() else {
+            return Ok(overloaded_reply());
+        };
+        if let Err(e) = git_manager.check_remote(new_url, credentials, repo.ssh_key_path.as_deref()).await {
+            return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidUrl, format!("Failed to reach {}: {}", new_url, e)));
+        }
+    }
+    
+    if let Err(e) = git_manager.set_remote_url(&repo, new_url).await {
+        let (status, code) = match e.downcast_ref::<GitError>() {
+            Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+            _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError),
+        };
+        return Ok(error_reply(status, code, format!("Failed to update the clone's remote: {}", e)));
+    }
+    
+    if let Err(e) = db.change_repository_url(repo.id, new_url, &name).await {
+        // Keep the clone pointing at the URL that is still recorded
+        if let Err(revert_err) = git_manager.set_remote_url(&repo, &repo.url).await {
+            tracing::error!("Failed to point {} back at {}: {}", repo.local_path, repo.url, revert_err);
+        }
+        return Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository URL: {}", e),
+        ));
+    }
+    
+    record_audit(&db, &user, "repository.change_url", &repo.url, Some(new_url)).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"url": new_url, "name": name})),
+        message: Some("Repository URL updated".to_string()),
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_set_rate_limit(
+    url: String,
+    request: FetchRateLimitRequest,
+    user: AuthUser,
+    db: Database,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    if let Err(e) = db.set_fetch_rate_limit(repo.id, request.kbps).await {
+        return Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository rate limit: {}", e),
+        ));
+    }
+    
+    let detail = request.kbps.map_or_else(|| "default".to_string(), |kbps| format!("{} KiB/s", kbps));
+    record_audit(&db, &user, "repository.rate_limit", &repo.url, Some(&detail)).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"fetch_rate_limit_kbps": request.kbps})),
+        message: Some("Repository rate limit updated".to_string()),
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_set_max_size(
+    url: String,
+    request: MaxSizeRequest,
+    user: AuthUser,
+    db: Database,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    if let Err(e) = db.set_max_size(repo.id, request.mb).await {
+        return Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository size limit: {}", e),
+        ));
+    }
+    
+    let detail = request.mb.map_or_else(|| "default".to_string(), |mb| format!("{} MiB", mb));
+    record_audit(&db, &user, "repository.size_limit", &repo.url, Some(&detail)).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"max_size_mb": request.mb})),
+        message: Some("Repository size limit updated".to_string()),
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_set_auto_sync(url: String, enabled: bool, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    if let Err(e) = db.set_auto_sync(repo.id, enabled).await {
+        return Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository: {}", e),
+        ));
+    }
+    
+    let action = if enabled { "repository.resume" } else { "repository.pause" };
+    record_audit(&db, &user, action, &repo.url, None).await;
+    let response = ApiResponse {
+        success: true,
+        data: Some(json!({"auto_sync_enabled": enabled})),
+        message: Some(if enabled { "Automatic syncing resumed" } else { "Automatic syncing paused" }.to_string()),
+    };
+    Ok(Box::new(warp::reply::json(&response)))
+}
+
+async fn handle_add_tag(url: String, request: TagRequest, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let tag = match validate_tag(&request.tag) {
+        Ok(tag) => tag,
+        Err(message) => return Ok(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, message)),
+    };
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    match db.add_tags(repo.id, std::slice::from_ref(&tag)).await {
+        Ok(_) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": format!("Tag '{}' added", tag)})),
                 message: None,
             };
             Ok(Box::new(warp::reply::json(&response)))
         }
-        Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Failed to fetch repositories: {}", e)),
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save repository tag: {}", e),
+        )),
+    }
+}
+
+async fn handle_remove_tag(url: String, tag: String, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    let tag = urlencoding::decode(&tag).map(|t| t.into_owned()).unwrap_or(tag);
+    
+    match db.remove_tag(repo.id, &tag).await {
+        Ok(true) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": format!("Tag '{}' removed", tag)})),
+                message: None,
             };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+            Ok(Box::new(warp::reply::json(&response)))
         }
+        Ok(false) => Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::TagNotFound, "Repository doesn't have this tag".to_string())),
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to remove repository tag: {}", e),
+        )),
     }
 }
 
-async fn handle_add_repository(
-    request: AddRepositoryRequest,
-    _username: String,
+// Tags are short labels like `team-a`; commas are excluded because the database joins them with one
+fn validate_tag(tag: &str) -> Result<String, String> {
+    let tag = tag.trim();
+    if tag.is_empty() || tag.len() > 64 {
+        return Err("Tags must be between 1 and 64 characters long".to_string());
+    }
+    if !tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(format!("Invalid tag '{}': only letters, digits, '-', '_' and '.' are allowed", tag));
+    }
+    Ok(tag.to_string())
+}
+
+async fn handle_upload_bundle(
+    url: String,
+    user: AuthUser,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
     db: Database,
     git_manager: GitManager,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    // Extract repository name from URL
-    let repo_name = match extract_repo_name(&request.url) {
-        Ok(name) => name,
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let Some(_slot) = git_manager.try_acquire_slot() else {
+        return Ok(overloaded_reply());
+    };
+    
+    let bundle_path = match git_manager.bundle_upload_path().await {
+        Ok(path) => path,
         Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Invalid repository URL: {}", e)),
-            };
-            return Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::BAD_REQUEST)));
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to prepare bundle upload: {}", e),
+            ));
         }
     };
     
-    match git_manager.clone_repository(&request.url).await {
-        Ok(local_path) => {
-            if let Err(e) = db.add_repository(&request.url, &repo_name, &local_path).await {
-                let response = ApiResponse::<()> {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Failed to save repository: {}", e)),
-                };
-                return Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::INTERNAL_SERVER_ERROR)));
+    // Stream the upload to disk instead of buffering it in memory
+    if let Err(e) = write_stream_to_file(body, &bundle_path).await {
+        let _ = tokio::fs::remove_file(&bundle_path).await;
+        return Ok(error_reply(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            format!("Failed to receive bundle: {}", e),
+        ));
+    }
+    
+    let result = git_manager.apply_bundle(&repo, &bundle_path).await;
+    if let Err(e) = tokio::fs::remove_file(&bundle_path).await {
+        tracing::warn!("Failed to remove uploaded bundle {}: {}", bundle_path.display(), e);
+    }
+    
+    match result {
+        Ok(_) => {
+            if let Err(e) = record_bundle_applied(&db, &repo.url).await {
+                tracing::error!("Bundle applied to {} but its sync state could not be saved: {}", repo.url, e);
             }
-            
+            crate::sync::refresh_local_details(&db, &git_manager, &repo.url, &repo.local_path).await;
             let response = ApiResponse {
                 success: true,
-                data: Some(json!({
-                    "url": request.url,
-                    "name": repo_name,
-                    "local_path": local_path
-                })),
-                message: Some("Repository cloned successfully".to_string()),
+                data: Some(json!({"message": "Bundle applied successfully"})),
+                message: None,
             };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::CREATED)))
+            Ok(Box::new(warp::reply::json(&response)))
         }
         Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Failed to clone repository: {}", e)),
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                Some(GitError::InvalidBundle(_)) => (warp::http::StatusCode::BAD_REQUEST, ErrorCode::BundleFailed),
+                Some(GitError::Timeout(_)) => (warp::http::StatusCode::GATEWAY_TIMEOUT, ErrorCode::Timeout),
+                Some(GitError::InsufficientSpace { .. }) => (warp::http::StatusCode::INSUFFICIENT_STORAGE, ErrorCode::InsufficientStorage),
+                // Bundles aren't cancellable
+                _ => (warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::BundleFailed),
             };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::BAD_REQUEST)))
+            Ok(error_reply(status, code, format!("Failed to apply bundle: {}", e)))
         }
     }
 }
 
-async fn handle_remove_repository(
-    url: String,
-    _username: String,
-    db: Database,
-) -> Result<Box<dyn Reply>, Rejection> {
-    let url_clone = url.clone();
-    let decoded_url = urlencoding::decode(&url).unwrap_or_else(|_| url_clone.into());
-    
-    // First, get the repository info to obtain the local path
-    let repo_info = match db.get_repository_by_url(&decoded_url).await {
-        Ok(Some(repo)) => repo,
-        Ok(None) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some("Repository not found".to_string()),
+// An applied bundle leaves the repository as a successful sync would
+async fn record_bundle_applied(db: &Database, url: &str) -> anyhow::Result<()> {
+    db.update_repository_status(url, RepoStatus::Synced).await?;
+    db.update_last_error(url, None).await?;
+    db.update_last_synced(url).await
+}
+
+async fn write_stream_to_file(
+    mut body: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin,
+    path: &std::path::Path,
+) -> anyhow::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk?;
+        while chunk.has_remaining() {
+            let len = chunk.chunk().len();
+            file.write_all(chunk.chunk()).await?;
+            chunk.advance(len);
+        }
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+async fn handle_get_templates(_user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    match db.get_templates().await {
+        Ok(templates) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(templates),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to get templates: {}", e),
+        )),
+    }
+}
+
+async fn handle_get_template(name: String, _user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    let name = urlencoding::decode(&name).map(|n| n.into_owned()).unwrap_or(name);
+    match db.get_template(&name).await {
+        Ok(Some(template)) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(template),
+                message: None,
             };
-            return Ok(Box::new(warp::reply::with_status(
-                warp::reply::json(&response), 
-                warp::http::StatusCode::NOT_FOUND
-            )));
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Ok(None) => Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::TemplateNotFound, "Template not found".to_string())),
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to get template: {}", e),
+        )),
+    }
+}
+
+async fn handle_add_template(request: TemplateRequest, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let request = match validate_template(request) {
+        Ok(request) => request,
+        Err(reply) => return Ok(reply),
+    };
+    
+    match db.get_template(&request.name).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::CONFLICT,
+                ErrorCode::TemplateAlreadyExists,
+                format!("Template '{}' already exists", request.name),
+            ));
         }
         Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Failed to get repository info: {}", e)),
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to check existing templates: {}", e),
+            ));
+        }
+    }
+    
+    match db.add_template(&request).await {
+        Ok(id) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"id": id, "name": request.name})),
+                message: Some("Template created".to_string()),
             };
-            return Ok(Box::new(warp::reply::with_status(
-                warp::reply::json(&response), 
-                warp::http::StatusCode::INTERNAL_SERVER_ERROR
-            )));
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::CREATED)))
         }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save template: {}", e),
+        )),
+    }
+}
+
+async fn handle_update_template(
+    name: String,
+    request: TemplateRequest,
+    user: AuthUser,
+    db: Database,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let name = urlencoding::decode(&name).map(|n| n.into_owned()).unwrap_or(name);
+    let request = match validate_template(request) {
+        Ok(request) => request,
+        Err(reply) => return Ok(reply),
     };
+    
+    // Renaming onto another template would hit the unique constraint
+    if request.name != name {
+        match db.get_template(&request.name).await {
+            Ok(None) => {}
+            Ok(Some(_)) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::CONFLICT,
+                    ErrorCode::TemplateAlreadyExists,
+                    format!("Template '{}' already exists", request.name),
+                ));
+            }
+            Err(e) => {
+                return Ok(error_reply(
+                    warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    ErrorCode::InternalError,
+                    format!("Failed to check existing templates: {}", e),
+                ));
+            }
+        }
+    }
+    
+    match db.update_template(&name, &request).await {
+        Ok(true) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"name": request.name})),
+                message: Some("Template updated".to_string()),
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Ok(false) => Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::TemplateNotFound, "Template not found".to_string())),
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to update template: {}", e),
+        )),
+    }
+}
 
-    // Remove the local directory if it exists
-    if std::path::Path::new(&repo_info.local_path).exists() {
-        if let Err(e) = std::fs::remove_dir_all(&repo_info.local_path) {
-            tracing::warn!("Failed to remove directory {}: {}", repo_info.local_path, e);
-            // Continue with database removal even if directory removal fails
-        } else {
-            tracing::info!("Removed directory: {}", repo_info.local_path);
+async fn handle_remove_template(name: String, user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let name = urlencoding::decode(&name).map(|n| n.into_owned()).unwrap_or(name);
+    match db.remove_template(&name).await {
+        Ok(true) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Template removed"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
         }
+        Ok(false) => Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::TemplateNotFound, "Template not found".to_string())),
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to remove template: {}", e),
+        )),
     }
+}
+
+// Trims the template fields, treating blank optional values as unset
+fn validate_template(mut request: TemplateRequest) -> Result<TemplateRequest, Box<dyn Reply>> {
+    request.name = request.name.trim().to_string();
+    if request.name.is_empty() {
+        return Err(error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, "Template name is required".to_string()));
+    }
+    if request.credentials.is_some() && !crate::crypto::is_configured() {
+        return Err(error_reply(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::NotConfigured,
+            "Credentials were supplied but CREDENTIALS_KEY is not configured on the server".to_string(),
+        ));
+    }
+    request.branch = request.branch.map(|b| b.trim().to_string()).filter(|b| !b.is_empty());
+    request.ssh_key_path = request.ssh_key_path.map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+    request.tags = request
+        .tags
+        .iter()
+        .map(|tag| validate_tag(tag))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|message| error_reply(warp::http::StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, message))?;
+    if request.mirror == Some(true) && (request.branch.is_some() || request.recurse_submodules == Some(true)) {
+        return Err(error_reply(
+            warp::http::StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Mirror clones copy every branch and have no working tree, so they can't track a branch or check out submodules".to_string(),
+        ));
+    }
+    Ok(request)
+}
+
+async fn handle_get_worktrees(url: String, _user: AuthUser, db: Database) -> Result<Box<dyn Reply>, Rejection> {
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
     
-    // Remove from database
-    match db.remove_repository(&decoded_url).await {
-        Ok(_) => {
+    match db.get_worktrees(repo.id).await {
+        Ok(worktrees) => {
             let response = ApiResponse {
                 success: true,
-                data: Some(json!({"message": "Repository and local files removed successfully"})),
+                data: Some(worktrees),
                 message: None,
             };
             Ok(Box::new(warp::reply::json(&response)))
         }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to fetch worktrees: {}", e),
+        )),
+    }
+}
+
+async fn handle_add_worktree(
+    url: String,
+    request: AddWorktreeRequest,
+    user: AuthUser,
+    db: Database,
+    git_manager: GitManager,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
+    
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let credentials = match db.get_credentials(repo.id).await {
+        Ok(credentials) => credentials,
+        Err(e) => {
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to load repository credentials: {}", e),
+            ));
+        }
+    };
+    
+    let path = match git_manager.add_worktree(&repo, &request.branch, &request.path, credentials.as_ref()).await {
+        Ok(path) => path,
         Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Failed to remove repository from database: {}", e)),
+            let (status, code) = match e.downcast_ref::<GitError>() {
+                Some(GitError::Busy) => (warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress),
+                _ => (warp::http::StatusCode::BAD_REQUEST, ErrorCode::WorktreeFailed),
+            };
+            return Ok(error_reply(status, code, format!("Failed to add worktree: {}", e)));
+        }
+    };
+    
+    match db.add_worktree(repo.id, &request.branch, &path).await {
+        Ok(id) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({
+                    "id": id,
+                    "branch": request.branch,
+                    "path": path
+                })),
+                message: Some("Worktree added successfully".to_string()),
             };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
+            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::CREATED)))
         }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to save worktree: {}", e),
+        )),
     }
 }
 
-async fn handle_sync_repository(
+async fn handle_remove_worktree(
     url: String,
-    _username: String,
+    worktree_id: i64,
+    user: AuthUser,
     db: Database,
     git_manager: GitManager,
 ) -> Result<Box<dyn Reply>, Rejection> {
-    let url_clone = url.clone();
-    let decoded_url = urlencoding::decode(&url).unwrap_or_else(|_| url_clone.into());
+    if let Err(reply) = require_admin(&user) {
+        return Ok(reply);
+    }
     
-    match db.get_all_repositories().await {
-        Ok(repositories) => {
-            if let Some(repo) = repositories.iter().find(|r| r.url == decoded_url.as_ref()) {
-                match git_manager.sync_repository(repo).await {
-                    Ok(_) => {
-                        let _ = db.update_repository_status(&repo.url, "synced").await;
-                        let _ = db.update_last_synced(&repo.url).await;
-                        
-                        let response = ApiResponse {
-                            success: true,
-                            data: Some(json!({"message": "Repository synced successfully"})),
-                            message: None,
-                        };
-                        Ok(Box::new(warp::reply::json(&response)))
-                    }
-                    Err(e) => {
-                        let _ = db.update_repository_status(&repo.url, "error").await;
-                        let response = ApiResponse::<()> {
-                            success: false,
-                            data: None,
-                            message: Some(format!("Failed to sync repository: {}", e)),
-                        };
-                        Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
-                    }
-                }
-            } else {
-                let response = ApiResponse::<()> {
-                    success: false,
-                    data: None,
-                    message: Some("Repository not found".to_string()),
-                };
-                Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::NOT_FOUND)))
-            }
-        }
+    let repo = match lookup_repository(&db, &url).await {
+        Ok(repo) => repo,
+        Err(reply) => return Ok(reply),
+    };
+    
+    let worktree = match db.get_worktree(repo.id, worktree_id).await {
+        Ok(Some(worktree)) => worktree,
+        Ok(None) => return Ok(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::WorktreeNotFound, "Worktree not found".to_string())),
         Err(e) => {
-            let response = ApiResponse::<()> {
-                success: false,
-                data: None,
-                message: Some(format!("Failed to fetch repositories: {}", e)),
-            };
-            Ok(Box::new(warp::reply::with_status(warp::reply::json(&response), warp::http::StatusCode::INTERNAL_SERVER_ERROR)))
-        }
-    }
-}
-
-fn extract_repo_name(url: &str) -> anyhow::Result<String> {
-    let url = url.trim_end_matches('/');
-    
-    // Parse the URL to extract host, org, and repo name
-    let parsed_url = if url.starts_with("http://") || url.starts_with("https://") {
-        // HTTP/HTTPS URL
-        let without_protocol = url.split("://").nth(1)
-            .ok_or_else(|| anyhow::anyhow!("Invalid URL format"))?;
-        
-        let parts: Vec<&str> = without_protocol.split('/').collect();
-        if parts.len() < 3 {
-            return Err(anyhow::anyhow!("Invalid repository URL format"));
-        }
-        
-        let host = parts[0];
-        let org = parts[1];
-        let repo = parts[2].trim_end_matches(".git");
-        
-        // Create fully qualified name: host/org/repo
-        format!("{}/{}/{}", host, org, repo)
-    } else if url.contains("@") {
-        // SSH URL like git@github.com:user/repo.git
-        let parts: Vec<&str> = url.split('@').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid SSH URL format"));
-        }
-        
-        let host_and_path = parts[1];
-        let colon_split: Vec<&str> = host_and_path.split(':').collect();
-        if colon_split.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid SSH URL format"));
-        }
-        
-        let host = colon_split[0];
-        let path = colon_split[1];
-        let path_parts: Vec<&str> = path.split('/').collect();
-        
-        if path_parts.len() == 2 {
-            // org/repo format
-            let org = path_parts[0];
-            let repo = path_parts[1].trim_end_matches(".git");
-            format!("{}/{}/{}", host, org, repo)
-        } else if path_parts.len() == 1 {
-            // just repo format
-            let repo = path_parts[0].trim_end_matches(".git");
-            format!("{}/{}", host, repo)
-        } else {
-            return Err(anyhow::anyhow!("Invalid repository path format"));
+            return Ok(error_reply(
+                warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorCode::InternalError,
+                format!("Failed to get worktree info: {}", e),
+            ));
         }
-    } else {
-        return Err(anyhow::anyhow!("Unsupported URL format"));
     };
     
-    if parsed_url.is_empty() {
-        return Err(anyhow::anyhow!("Could not extract repository name from URL"));
+    match git_manager.remove_worktree(&repo, &worktree).await {
+        Ok(()) => {}
+        Err(e) if matches!(e.downcast_ref::<GitError>(), Some(GitError::Busy)) => {
+            return Ok(error_reply(warp::http::StatusCode::CONFLICT, ErrorCode::OperationInProgress, format!("Failed to remove worktree: {}", e)));
+        }
+        // Continue with database removal even if the checkout could not be cleaned up
+        Err(e) => tracing::warn!("Failed to remove worktree {}: {}", worktree.path, e),
+    }
+    
+    match db.remove_worktree(worktree.id).await {
+        Ok(_) => {
+            let response = ApiResponse {
+                success: true,
+                data: Some(json!({"message": "Worktree removed successfully"})),
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)))
+        }
+        Err(e) => Ok(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to remove worktree from database: {}", e),
+        )),
+    }
+}
+
+// Seconds left before another manual sync is allowed, based on MANUAL_SYNC_MIN_INTERVAL_SECONDS; `force` skips the wait
+fn manual_sync_cooldown(
+    repo: &Repository,
+    force: bool,
+    min_interval: Option<std::time::Duration>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<i64> {
+    if force {
+        return None;
     }
+    let min_interval = min_interval?.as_secs() as i64;
     
-    // Replace any invalid filesystem characters
-    let safe_name = parsed_url.replace(":", "_").replace("@", "_");
+    let last = match (repo.last_synced, repo.last_sync_attempt) {
+        (Some(synced), Some(attempt)) => synced.max(attempt),
+        (synced, attempt) => synced.or(attempt)?,
+    };
+    let elapsed = (now - last).num_seconds();
+    (elapsed < min_interval).then(|| min_interval - elapsed)
+}
+
+/// Shows whether an operation is running for the repository, and which one.
+pub fn set_operation_state(repo: &mut Repository, git_manager: &GitManager) {
+    repo.operation = git_manager.current_operation(&repo.url);
+    repo.in_progress = repo.operation.is_some();
+}
+
+fn overloaded_reply() -> Box<dyn Reply> {
+    Box::new(warp::reply::with_header(
+        error_reply(
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Overloaded,
+            "Server is busy with other git operations, try again later".to_string(),
+        ),
+        "Retry-After",
+        OVERLOAD_RETRY_AFTER_SECS.to_string(),
+    ))
+}
+
+// JSON reply with a weak ETag of its body, or an empty 304 when it matches `If-None-Match`. Hashing
+// the body rather than the rows also catches changes of the operation state and health, which
+// aren't stored.
+fn conditional_json_reply<T: serde::Serialize>(value: &T, if_none_match: Option<&str>) -> Box<dyn Reply> {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => return error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to encode response: {}", e)),
+    };
+    let etag = format!("W/\"{}\"", hex::encode(&Sha256::digest(&body)[..16]));
+    let unchanged = if_none_match.is_some_and(|tags| {
+        tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag.trim_start_matches("W/"))
+    });
+
+    let response = if unchanged {
+        warp::http::Response::builder()
+            .status(warp::http::StatusCode::NOT_MODIFIED)
+            .header(warp::http::header::ETAG, &etag)
+            .header(warp::http::header::CACHE_CONTROL, "no-cache")
+            .body(Vec::new())
+    } else {
+        // `no-cache` lets browsers keep the body but revalidate it on every request
+        warp::http::Response::builder()
+            .header(warp::http::header::CONTENT_TYPE, "application/json")
+            .header(warp::http::header::ETAG, &etag)
+            .header(warp::http::header::CACHE_CONTROL, "no-cache")
+            .body(body)
+    };
+    match response {
+        Ok(response) => Box::new(response),
+        Err(e) => error_reply(warp::http::StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, format!("Failed to build response: {}", e)),
+    }
+}
+
+// Authenticated users who aren't admins can only read
+fn require_admin(user: &AuthUser) -> Result<(), Box<dyn Reply>> {
+    if user.is_admin() {
+        Ok(())
+    } else {
+        Err(error_reply(warp::http::StatusCode::FORBIDDEN, ErrorCode::Forbidden, "This action requires the admin role".to_string()))
+    }
+}
+
+// The action already happened, so a failure to log it is reported but doesn't fail the request
+async fn record_audit(db: &Database, user: &AuthUser, action: &str, target: &str, detail: Option<&str>) {
+    if let Err(e) = db.record_audit(&user.username, action, target, detail).await {
+        tracing::error!("Failed to record {} of {} in the audit log: {}", action, target, e);
+    }
+}
+
+fn error_reply(status: warp::http::StatusCode, code: ErrorCode, message: String) -> Box<dyn Reply> {
+    let response = ErrorResponse {
+        success: false,
+        data: None,
+        message,
+        code,
+    };
+    Box::new(warp::reply::with_status(warp::reply::json(&response), status))
+}
+
+// Resolves a repository path segment, its id or URL-encoded URL, or the error reply to send back.
+// Like when adding, a trailing slash or `.git` suffix of the URL doesn't matter.
+async fn lookup_repository(db: &Database, segment: &str) -> Result<Repository, Box<dyn Reply>> {
+    let decoded = urlencoding::decode(segment).map(|u| u.into_owned()).unwrap_or_else(|_| segment.to_string());
     
-    Ok(safe_name)
+    // No repository URL is a plain number, so there is no ambiguity
+    let found = match decoded.parse::<i64>() {
+        Ok(id) => db.get_repository_by_id(id).await,
+        Err(_) => db.get_repository_by_url(&decoded).await,
+    };
+    match found {
+        Ok(Some(repo)) => Ok(repo),
+        Ok(None) => Err(error_reply(warp::http::StatusCode::NOT_FOUND, ErrorCode::RepoNotFound, "Repository not found".to_string())),
+        Err(e) => Err(error_reply(
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::InternalError,
+            format!("Failed to get repository info: {}", e),
+        )),
+    }
 }
 
 #[derive(Debug)]
-struct Unauthorized;
+struct RateLimited(std::time::Duration);
+
+impl warp::reject::Reject for RateLimited {}
+
+#[derive(Debug)]
+struct MissingToken;
+
+impl warp::reject::Reject for MissingToken {}
+
+#[derive(Debug)]
+struct InvalidToken;
+
+impl warp::reject::Reject for InvalidToken {}
+
+#[derive(Debug)]
+struct ExpiredToken;
+
+impl warp::reject::Reject for ExpiredToken {}
+
+// 401 for a missing (`None`) or refused token, with a Bearer challenge as described in RFC 6750
+fn token_error_reply(error: Option<TokenError>) -> Box<dyn Reply> {
+    let (code, message, challenge) = match error {
+        None => (
+            ErrorCode::MissingToken,
+            "Log in and send the token as 'Authorization: Bearer <token>'",
+            r#"Bearer realm="gitcloner""#,
+        ),
+        Some(TokenError::Invalid) => (
+            ErrorCode::InvalidToken,
+            "The token is invalid or was revoked, log in again",
+            r#"Bearer realm="gitcloner", error="invalid_token", error_description="The token is invalid or was revoked""#,
+        ),
+        Some(TokenError::Expired) => (
+            ErrorCode::TokenExpired,
+            "The token expired, log in again",
+            r#"Bearer realm="gitcloner", error="invalid_token", error_description="The token expired""#,
+        ),
+    };
+    let reply = error_reply(warp::http::StatusCode::UNAUTHORIZED, code, message.to_string());
+    Box::new(warp::reply::with_header(reply, "WWW-Authenticate", challenge))
+}
+
+/// Renders requests no route accepted as the same JSON error body the handlers use.
+pub async fn handle_rejection(err: Rejection) -> Result<Box<dyn Reply>, std::convert::Infallible> {
+    use warp::http::StatusCode;
+    
+    // Checked before the rest because a request rejected by every route carries all their reasons,
+    // e.g. a missing token for one route and the wrong method for another
+    let (status, code, message) = if err.is_not_found() {
+        (StatusCode::NOT_FOUND, ErrorCode::NotFound, "Not found".to_string())
+    } else if err.find::<MissingToken>().is_some() {
+        return Ok(token_error_reply(None));
+    } else if err.find::<InvalidToken>().is_some() {
+        return Ok(token_error_reply(Some(TokenError::Invalid)));
+    } else if err.find::<ExpiredToken>().is_some() {
+        return Ok(token_error_reply(Some(TokenError::Expired)));
+    } else if let Some(RateLimited(wait)) = err.find() {
+        let retry_after = (wait.as_secs_f64().ceil() as u64).max(1);
+        return Ok(Box::new(warp::reply::with_header(
+            error_reply(
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                format!("Too many requests, try again in {} seconds", retry_after),
+            ),
+            "Retry-After",
+            retry_after.to_string(),
+        )));
+    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, ErrorCode::InvalidRequest, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, ErrorCode::InvalidRequest, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::InvalidRequest, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, ErrorCode::MethodNotAllowed, e.to_string())
+    } else {
+        tracing::error!("Unhandled rejection: {:?}", err);
+        (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::InternalError, "Internal server error".to_string())
+    };
+    Ok(error_reply(status, code, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::time::Duration;
+
+    fn repository(last_synced: Option<DateTime<Utc>>, last_sync_attempt: Option<DateTime<Utc>>) -> Repository {
+        let mut repo: Repository = serde_json::from_value(json!({
+            "id": 1,
+            "url": "https://github.com/rust-lang/rust.git",
+            "name": "github.com/rust-lang/rust",
+            "local_path": "/tmp/repos/github.com/rust-lang/rust",
+            "created_at": "2024-01-01T00:00:00Z",
+            "status": "synced",
+        }))
+        .unwrap();
+        repo.last_synced = last_synced;
+        repo.last_sync_attempt = last_sync_attempt;
+        repo
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap()
+    }
+
+    const INTERVAL: Option<Duration> = Some(Duration::from_secs(60));
+
+    #[tokio::test]
+    async fn cheap_password_hash_is_upgraded_at_login() {
+        let db = Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        let cheap = bcrypt::hash("correct horse battery", 4).unwrap();
+        db.save_user("alice", &cheap, None).await.unwrap();
+        let user = db.get_user_by_username("alice").await.unwrap().unwrap();
+        assert!(AuthManager::needs_rehash(&user.password_hash));
+
+        upgrade_password_hash(&db, &user, "correct horse battery").await;
 
-impl warp::reject::Reject for Unauthorized {}
+        let upgraded = db.get_user_by_username("alice").await.unwrap().unwrap().password_hash;
+        assert_ne!(upgraded, cheap);
+        assert!(!AuthManager::needs_rehash(&upgraded));
+        assert!(AuthManager::verify_password("correct horse battery", &upgraded).unwrap());
+    }
+
+    async fn rejected(request: warp::test::RequestBuilder) -> (warp::http::StatusCode, serde_json::Value) {
+        let db = Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        let routes = auth_routes(db.clone()).or(audit_log_routes(db)).recover(handle_rejection);
+        let response = request.reply(&routes).await;
+        (response.status(), serde_json::from_slice(response.body()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn request_without_token_is_unauthorized() {
+        let (status, body) = rejected(warp::test::request().method("GET").path("/audit")).await;
+        assert_eq!(status, warp::http::StatusCode::UNAUTHORIZED);
+        assert_eq!(body["success"], false);
+        assert_eq!(body["data"], serde_json::Value::Null);
+        assert_eq!(body["code"], "MISSING_TOKEN");
+        assert!(body["message"].as_str().is_some_and(|message| !message.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_body_is_a_bad_request() {
+        let request = warp::test::request()
+            .method("POST")
+            .path("/auth/login")
+            .header("content-type", "application/json")
+            .body(r#"{"username": "alice","#);
+        let (status, body) = rejected(request).await;
+        assert_eq!(status, warp::http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["success"], false);
+        assert_eq!(body["data"], serde_json::Value::Null);
+        assert_eq!(body["code"], "INVALID_REQUEST");
+        assert!(body["message"].as_str().is_some_and(|message| !message.is_empty()));
+    }
+
+    fn template(branch: &str) -> TemplateRequest {
+        TemplateRequest {
+            name: "team".to_string(),
+            branch: Some(branch.to_string()),
+            credentials: None,
+            ssh_key_path: Some("/keys/team".to_string()),
+            tags: vec!["team".to_string()],
+            auto_sync_enabled: Some(false),
+            mirror: None,
+            recurse_submodules: Some(true),
+            fetch_rate_limit_kbps: Some(512),
+            max_size_mb: Some(2048),
+        }
+    }
+
+    fn add_request(settings: serde_json::Value) -> AddRepositoryRequest {
+        let mut request = json!({"url": "https://github.com/org/repo.git", "template": "team"});
+        request.as_object_mut().unwrap().extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn template_fills_only_unset_settings() {
+        let db = Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        db.add_template(&template("develop")).await.unwrap();
+
+        let mut unset = add_request(json!({}));
+        apply_template(&db, "team", &mut unset).await.unwrap();
+        assert_eq!((unset.branch.as_deref(), unset.ssh_key_path.as_deref()), (Some("develop"), Some("/keys/team")));
+        assert_eq!(unset.tags, ["team"]);
+        assert_eq!((unset.auto_sync_enabled, unset.mirror, unset.recurse_submodules), (Some(false), None, Some(true)));
+        assert_eq!((unset.fetch_rate_limit_kbps, unset.max_size_mb), (Some(512), Some(2048)));
+
+        let mut given = add_request(json!({
+            "branch": "main",
+            "ssh_key_path": "/keys/own",
+            "tags": ["own"],
+            "auto_sync_enabled": true,
+            "recurse_submodules": false,
+            "fetch_rate_limit_kbps": 0,
+            "max_size_mb": 100
+        }));
+        apply_template(&db, "team", &mut given).await.unwrap();
+        assert_eq!((given.branch.as_deref(), given.ssh_key_path.as_deref()), (Some("main"), Some("/keys/own")));
+        assert_eq!(given.tags, ["own"]);
+        assert_eq!((given.auto_sync_enabled, given.recurse_submodules), (Some(true), Some(false)));
+        assert_eq!((given.fetch_rate_limit_kbps, given.max_size_mb), (Some(0), Some(100)));
+
+        let mut partly = add_request(json!({"branch": "main", "max_size_mb": 100}));
+        apply_template(&db, "team", &mut partly).await.unwrap();
+        assert_eq!((partly.branch.as_deref(), partly.ssh_key_path.as_deref()), (Some("main"), Some("/keys/team")));
+        assert_eq!((partly.fetch_rate_limit_kbps, partly.max_size_mb), (Some(512), Some(100)));
+    }
+
+    #[tokio::test]
+    async fn template_edits_leave_existing_repositories_alone() {
+        let db = Database::new("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        db.add_template(&template("develop")).await.unwrap();
+        let mut request = add_request(json!({}));
+        apply_template(&db, "team", &mut request).await.unwrap();
+        let options = CloneOptions { branch: request.branch, ssh_key_path: request.ssh_key_path, ..CloneOptions::default() };
+        db.add_repository(&request.url, "github.com/org/repo", "/tmp/repos/github.com/org/repo", &options, RepoStatus::Pending).await.unwrap();
+
+        let edited = TemplateRequest { ssh_key_path: Some("/keys/rotated".to_string()), ..template("release") };
+        assert!(db.update_template("team", &edited).await.unwrap());
+
+        let repo = db.get_repository_by_url(&request.url).await.unwrap().unwrap();
+        assert_eq!((repo.branch.as_deref(), repo.ssh_key_path.as_deref()), (Some("develop"), Some("/keys/team")));
+    }
+
+    #[test]
+    fn overloaded_reply_asks_to_retry_later() {
+        let response = overloaded_reply().into_response();
+        assert_eq!(response.status(), warp::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["Retry-After"], OVERLOAD_RETRY_AFTER_SECS.to_string().as_str());
+    }
+
+    #[test]
+    fn cooldown_counts_down_to_the_interval() {
+        let repo = repository(Some(at(0)), None);
+        assert_eq!(manual_sync_cooldown(&repo, false, INTERVAL, at(0)), Some(60));
+        assert_eq!(manual_sync_cooldown(&repo, false, INTERVAL, at(59)), Some(1));
+        assert_eq!(manual_sync_cooldown(&repo, false, INTERVAL, at(60)), None);
+        assert_eq!(manual_sync_cooldown(&repo, false, INTERVAL, at(61)), None);
+    }
+
+    #[test]
+    fn cooldown_starts_at_the_latest_attempt() {
+        let repo = repository(Some(at(0)), Some(at(30)));
+        assert_eq!(manual_sync_cooldown(&repo, false, INTERVAL, at(60)), Some(30));
+        let failed_only = repository(None, Some(at(30)));
+        assert_eq!(manual_sync_cooldown(&failed_only, false, INTERVAL, at(60)), Some(30));
+    }
+
+    #[test]
+    fn force_never_waits() {
+        let repo = repository(Some(at(0)), None);
+        assert_eq!(manual_sync_cooldown(&repo, true, INTERVAL, at(1)), None);
+    }
+
+    #[test]
+    fn no_cooldown_without_interval_or_previous_sync() {
+        assert_eq!(manual_sync_cooldown(&repository(Some(at(0)), None), false, None, at(1)), None);
+        assert_eq!(manual_sync_cooldown(&repository(None, None), false, INTERVAL, at(1)), None);
+    }
+}
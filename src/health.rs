@@ -0,0 +1,148 @@
+use chrono::{DateTime, Utc};
+use crate::config::Config;
+use crate::models::{HealthLevel, RepoStatus, Repository, RepositoryHealth};
+
+// Scoring formula (documented in the README):
+//
+//   recency = 100 if the last successful sync is younger than HEALTH_FRESH_HOURS,
+//             falling linearly to 0 at HEALTH_STALE_HOURS (0 if never synced)
+//   outcome = 0 when the status records a failure (error, timeout, corruption, size limit), 100 when synced,
+//             50 otherwise (pending, running, cancelled, LFS pointers only)
+//   score   = (recency * HEALTH_WEIGHT_RECENCY + outcome * HEALTH_WEIGHT_OUTCOME)
+//             / (HEALTH_WEIGHT_RECENCY + HEALTH_WEIGHT_OUTCOME)
+//
+// Scores of 80 and above are green, 50 and above yellow, anything lower red.
+
+struct HealthConfig {
+    fresh_hours: f64,
+    stale_hours: f64,
+    weight_recency: f64,
+    weight_outcome: f64,
+}
+
+lazy_static::lazy_static! {
+    static ref CONFIG: HealthConfig = {
+        let config = Config::get();
+        HealthConfig {
+            fresh_hours: config.health_fresh_hours,
+            stale_hours: config.health_stale_hours,
+            weight_recency: config.health_weight_recency,
+            weight_outcome: config.health_weight_outcome,
+        }
+    };
+}
+
+pub fn assess(repo: &Repository) -> RepositoryHealth {
+    score(repo, &CONFIG, Utc::now())
+}
+
+fn score(repo: &Repository, config: &HealthConfig, now: DateTime<Utc>) -> RepositoryHealth {
+    let recency = match repo.last_synced {
+        Some(last_synced) => {
+            let age_hours = (now - last_synced).num_seconds() as f64 / 3600.0;
+            if age_hours <= config.fresh_hours {
+                100.0
+            } else if age_hours >= config.stale_hours || config.stale_hours <= config.fresh_hours {
+                0.0
+            } else {
+                100.0 * (config.stale_hours - age_hours) / (config.stale_hours - config.fresh_hours)
+            }
+        }
+        None => 0.0,
+    };
+
+    // Failed syncs leave their status behind; a running or skipped one says nothing about the last outcome
+    let outcome = match repo.status {
+        RepoStatus::Error | RepoStatus::Timeout | RepoStatus::Corrupt | RepoStatus::TooLarge => 0.0,
+        RepoStatus::Synced => 100.0,
+        _ => 50.0,
+    };
+
+    let total_weight = config.weight_recency + config.weight_outcome;
+    let score = if total_weight > 0.0 {
+        (recency * config.weight_recency + outcome * config.weight_outcome) / total_weight
+    } else {
+        0.0
+    };
+    let score = score.round().clamp(0.0, 100.0) as u8;
+
+    let level = match score {
+        80.. => HealthLevel::Green,
+        50.. => HealthLevel::Yellow,
+        _ => HealthLevel::Red,
+    };
+    RepositoryHealth { score, level }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    const CONFIG: HealthConfig = HealthConfig {
+        fresh_hours: 26.0,
+        stale_hours: 72.0,
+        weight_recency: 60.0,
+        weight_outcome: 40.0,
+    };
+
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000, 0).unwrap()
+    }
+
+    fn repository(status: RepoStatus, last_synced: Option<DateTime<Utc>>, last_sync_attempt: Option<DateTime<Utc>>) -> Repository {
+        let mut repo: Repository = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "url": "https://github.com/rust-lang/rust.git",
+            "name": "github.com/rust-lang/rust",
+            "local_path": "/tmp/repos/github.com/rust-lang/rust",
+            "created_at": "2024-01-01T00:00:00Z",
+            "status": status,
+        }))
+        .unwrap();
+        repo.last_synced = last_synced;
+        repo.last_sync_attempt = last_sync_attempt;
+        repo
+    }
+
+    #[test]
+    fn fresh_sync_is_green() {
+        let synced = now() - Duration::hours(1);
+        let health = score(&repository(RepoStatus::Synced, Some(synced), Some(synced)), &CONFIG, now());
+        assert_eq!((health.score, health.level), (100, HealthLevel::Green));
+    }
+
+    #[test]
+    fn running_sync_stays_green() {
+        let synced = now() - Duration::hours(1);
+        let repo = repository(RepoStatus::Syncing, Some(synced), Some(now()));
+        assert_eq!(score(&repo, &CONFIG, now()).level, HealthLevel::Green);
+    }
+
+    #[test]
+    fn skipped_sync_does_not_count_as_failure() {
+        // A sync skipped because another operation held the repository restores the old status
+        let synced = now() - Duration::hours(1);
+        let repo = repository(RepoStatus::Synced, Some(synced), Some(now()));
+        assert_eq!(score(&repo, &CONFIG, now()).score, 100);
+    }
+
+    #[test]
+    fn failed_sync_scores_no_outcome() {
+        let synced = now() - Duration::hours(1);
+        for status in [RepoStatus::Error, RepoStatus::Timeout, RepoStatus::Corrupt, RepoStatus::TooLarge] {
+            let health = score(&repository(status, Some(synced), Some(now())), &CONFIG, now());
+            assert_eq!((health.score, health.level), (60, HealthLevel::Yellow), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn recency_falls_between_fresh_and_stale() {
+        let halfway = now() - Duration::hours(49);
+        assert_eq!(score(&repository(RepoStatus::Synced, Some(halfway), None), &CONFIG, now()).score, 70);
+        let stale = now() - Duration::hours(80);
+        let health = score(&repository(RepoStatus::Synced, Some(stale), None), &CONFIG, now());
+        assert_eq!((health.score, health.level), (40, HealthLevel::Red));
+        assert_eq!(score(&repository(RepoStatus::Pending, None, None), &CONFIG, now()).score, 20);
+    }
+}
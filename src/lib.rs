@@ -0,0 +1,19 @@
+//! Server components, shared by the `gitcloner` server and the `gitc` admin tool.
+
+pub mod auth;
+pub mod client_ip;
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod crypto;
+pub mod database;
+pub mod git_manager;
+pub mod handlers;
+pub mod health;
+pub mod metrics;
+pub mod models;
+pub mod ratelimit;
+pub mod request_log;
+pub mod sync;
+pub mod url_utils;
+pub mod webhooks;
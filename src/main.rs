@@ -1,43 +1,84 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::env;
+use std::time::Duration;
 use tokio_cron_scheduler::{Job, JobScheduler};
-use tracing::{info, error};
+use tracing::{info, warn, error};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::prelude::*;
 use warp::Filter;
 
-mod auth;
-mod database;
-mod git_manager;
-mod handlers;
-mod models;
-
-use database::Database;
-use git_manager::GitManager;
+use gitcloner::auth::{self, AuthManager};
+use gitcloner::config::Config;
+use gitcloner::database::Database;
+use gitcloner::git_manager::GitManager;
+use gitcloner::config::CorsOrigins;
+use gitcloner::ratelimit::RequestClass;
+use gitcloner::{compression, cors, handlers, request_log, sync};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    // warp's own events for every request would only repeat the access log
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(Targets::new().with_default(LevelFilter::INFO).with_target("warp::filters::trace", LevelFilter::OFF))
+        .init();
+    let started_at = std::time::Instant::now();
 
-    let database_url = env::var("DATABASE_URL")
-        .unwrap_or_else(|_| "sqlite:gitcloner.db".to_string());
+    let config = Config::load()?;
     
-    let db = Database::new(&database_url).await?;
+    let db = Database::new(&config.database_url).await?;
     db.migrate().await?;
+    bootstrap_admin(&db).await?;
 
-    let git_manager = GitManager::new("./repos".to_string()).await?;
+    let git_manager = GitManager::new(config).await?;
+    sync::reconcile_on_startup(&db, &git_manager).await?;
     
     // Setup cron scheduler for daily sync
-    let sched = JobScheduler::new().await?;
+    let mut sched = JobScheduler::new().await?;
     let db_clone = db.clone();
     let git_manager_clone = git_manager.clone();
+    let sync_concurrency = config.sync_concurrency;
+    info!("Daily sync scheduled at '{}'", config.sync_cron);
     
     sched.add(
-        Job::new_async("0 0 2 * * *", move |_uuid, _l| {
+        Job::new_async(config.sync_cron.as_str(), move |_uuid, _l| {
             let db = db_clone.clone();
             let git_manager = git_manager_clone.clone();
             Box::pin(async move {
                 info!("Starting daily repository sync");
-                if let Err(e) = sync_all_repositories(&db, &git_manager).await {
-                    error!("Daily sync failed: {}", e);
+                match sync::sync_all_repositories(&db, &git_manager, &sync::SYNC_QUEUE, sync_concurrency).await {
+                    Ok(results) => {
+                        for (repo, result) in results {
+                            if let Err(e) = result {
+                                error!("Failed to record sync result for {}: {}", repo.url, e);
+                            }
+                        }
+                    }
+                    Err(e) => error!("Daily sync failed: {}", e),
+                }
+            })
+        })?
+    ).await?;
+
+    // Permanently delete repositories that have been in the trash for too long, and old sync history
+    let db_clone = db.clone();
+    let git_manager_clone = git_manager.clone();
+    let trash_retention = config.trash_retention;
+    let sync_history_retention = config.sync_history_retention;
+    sched.add(
+        Job::new_async("0 0 3 * * *", move |_uuid, _l| {
+            let db = db_clone.clone();
+            let git_manager = git_manager_clone.clone();
+            Box::pin(async move {
+                if let Err(e) = purge_trash(&db, &git_manager, trash_retention).await {
+                    error!("Purging the trash failed: {}", e);
+                }
+                if let Some(retention) = sync_history_retention {
+                    match db.prune_sync_history(retention).await {
+                        Ok(0) => {}
+                        Ok(pruned) => info!("Pruned {} old sync history entries", pruned),
+                        Err(e) => error!("Pruning the sync history failed: {}", e),
+                    }
                 }
             })
         })?
@@ -45,20 +86,38 @@ async fn main() -> Result<()> {
 
     sched.start().await?;
 
+    info!("Using {} authentication", config.auth_mode);
+    // Periodically drop expired login sessions
+    handlers::AUTH_MANAGER.spawn_sweeper(Duration::from_secs(300));
+    handlers::LOGIN_LIMITER.spawn_sweeper(Duration::from_secs(300));
+    handlers::REQUEST_LIMITER.spawn_sweeper(Duration::from_secs(300));
+    git_manager.spawn_disk_monitor(Duration::from_secs(300));
+
     // Setup routes
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_headers(vec!["content-type", "authorization"])
-        .allow_methods(vec!["GET", "POST", "DELETE", "OPTIONS"]);
+    match &config.cors_origins {
+        CorsOrigins::Any => warn!("CORS_ORIGINS allows any origin to call the API, only use this for development"),
+        CorsOrigins::Only(origins) if !origins.is_empty() => info!("Cross-origin requests allowed from {}", origins.join(", ")),
+        CorsOrigins::Only(_) => {}
+    }
 
     let static_files = warp::path("static")
         .and(warp::fs::dir("static"));
 
     let api = warp::path("api")
+        .and(handlers::with_rate_limit(RequestClass::Api))
         .and(
-            handlers::auth_routes(db.clone())
+            handlers::health_routes(db.clone(), started_at)
+                .or(handlers::auth_routes(db.clone()))
                 .or(handlers::repo_routes(db.clone(), git_manager.clone()))
+                .or(handlers::metrics_routes(db.clone()))
+                .or(handlers::template_routes(db.clone()))
+                .or(handlers::audit_log_routes(db.clone()))
+                .or(handlers::admin_routes(db.clone()))
+                .or(handlers::stats_routes(db.clone(), git_manager.clone()))
+                .or(handlers::webhook_routes(db.clone(), git_manager.clone()))
         );
+    // Boxed to keep the type of the whole route tree within the compiler's limits
+    let api = compression::compress(api.boxed());
 
     let index = warp::path::end()
         .and(warp::fs::file("static/index.html"));
@@ -66,26 +125,126 @@ async fn main() -> Result<()> {
     let routes = static_files
         .or(api)
         .or(index)
-        .with(cors);
+        .recover(handlers::handle_rejection);
+    let routes = cors::wrap(routes, &config.cors_origins).with(request_log::access_log(config.log_static_requests));
+    let routes = request_log::with_request_id(routes).with(warp::trace(request_log::span));
 
-    info!("Server starting on http://localhost:3030");
-    warp::serve(routes)
-        .run(([0, 0, 0, 0], 3030))
-        .await;
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let shutdown = async {
+        shutdown_rx.await.ok();
+    };
+    let server = match &config.tls {
+        Some(tls) => {
+            let (_, server) = warp::serve(routes)
+                .tls()
+                .cert_path(&tls.cert_path)
+                .key_path(&tls.key_path)
+                .bind_with_graceful_shutdown(config.bind_addr, shutdown);
+            info!("Server starting with TLS on https://{}", config.bind_addr);
+            tokio::spawn(server)
+        }
+        None => {
+            let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(config.bind_addr, shutdown);
+            info!("Server starting without TLS on http://{}", config.bind_addr);
+            tokio::spawn(server)
+        }
+    };
+
+    shutdown_signal().await;
+    info!("Shutdown requested, no longer accepting connections");
+    let _ = shutdown_tx.send(());
+    if let Err(e) = sched.shutdown().await {
+        warn!("Failed to stop the scheduler: {}", e);
+    }
 
+    // Let running clones and syncs finish so they don't leave half-written repositories behind
+    let deadline = tokio::time::Instant::now() + config.shutdown_grace;
+    let (_, _, running) = git_manager.load();
+    if running > 0 {
+        info!("Waiting for {} running git operations to finish", running);
+    }
+    if tokio::time::timeout_at(deadline, server).await.is_err() {
+        warn!("HTTP connections still open after the shutdown grace period");
+    }
+    let remaining = git_manager.wait_idle(deadline).await;
+    info!("Drained {} git operations", running.saturating_sub(remaining));
+    if remaining > 0 {
+        warn!("Abandoning {} git operations still running after the shutdown grace period", remaining);
+        // The runtime would otherwise wait for the blocking tasks indefinitely
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+// Without any user nobody can log in: create the first admin from the environment when asked to,
+// otherwise explain how to
+async fn bootstrap_admin(db: &Database) -> Result<()> {
+    if db.count_users().await? > 0 {
+        return Ok(());
+    }
+    let username = env::var("BOOTSTRAP_ADMIN_USER").ok().filter(|value| !value.is_empty());
+    let password = env::var("BOOTSTRAP_ADMIN_PASSWORD").ok().filter(|value| !value.is_empty());
+    match (username, password) {
+        (Some(username), Some(password)) => {
+            if let Some(problem) = auth::password_weakness(&username, &password) {
+                bail!("BOOTSTRAP_ADMIN_PASSWORD is too weak: {}", problem);
+            }
+            let password_hash = AuthManager::hash_password(&password)?;
+            db.save_user(&username, &password_hash, Some("admin")).await?;
+            if let Err(e) = db.record_audit("bootstrap", "user.add", &username, Some("admin")).await {
+                warn!("Failed to record audit entry: {}", e);
+            }
+            info!("Created admin user '{}' from BOOTSTRAP_ADMIN_USER, unset BOOTSTRAP_ADMIN_PASSWORD now", username);
+        }
+        (None, None) => {
+            warn!("No users exist yet, nobody can log in");
+            warn!("Create an admin with: gitc add <username> --role admin");
+            warn!("or set BOOTSTRAP_ADMIN_USER and BOOTSTRAP_ADMIN_PASSWORD and restart");
+        }
+        _ => bail!("BOOTSTRAP_ADMIN_USER and BOOTSTRAP_ADMIN_PASSWORD must be set together"),
+    }
     Ok(())
 }
 
-async fn sync_all_repositories(db: &Database, git_manager: &GitManager) -> Result<()> {
-    let repos = db.get_all_repositories().await?;
-    for repo in repos {
-        if let Err(e) = git_manager.sync_repository(&repo).await {
-            error!("Failed to sync repository {}: {}", repo.url, e);
-            db.update_repository_status(&repo.url, "error").await?;
-        } else {
-            db.update_repository_status(&repo.url, "synced").await?;
-            db.update_last_synced(&repo.url).await?;
+async fn purge_trash(db: &Database, git_manager: &GitManager, retention: Duration) -> Result<()> {
+    for repo in db.get_repositories_deleted_before(retention).await? {
+        info!("Purging removed repository {} from the trash", repo.url);
+        if let Err(e) = git_manager.purge_trash(&repo).await {
+            // Keep the row so the next run tries again
+            error!("Failed to delete trashed files of {}: {}", repo.url, e);
+            continue;
         }
+        db.purge_repository(repo.id).await?;
     }
     Ok(())
 }
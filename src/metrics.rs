@@ -0,0 +1,68 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Process-wide counters exposed in the Prometheus text format at `/api/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    clones_total: AtomicU64,
+    clone_failures_total: AtomicU64,
+    syncs_total: AtomicU64,
+    sync_failures_total: AtomicU64,
+    last_sync_duration_ms: AtomicU64,
+}
+
+lazy_static::lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+impl Metrics {
+    pub fn record_clone(&self, succeeded: bool) {
+        self.clones_total.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.clone_failures_total.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_sync(&self, succeeded: bool, duration: Duration) {
+        self.syncs_total.fetch_add(1, Ordering::Relaxed);
+        if !succeeded {
+            self.sync_failures_total.fetch_add(1, Ordering::Relaxed);
+        }
+        self.last_sync_duration_ms.store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics, plus the repository count per status read from the database.
+    pub fn render(&self, repositories_by_status: &[(String, i64)]) -> String {
+        let mut out = String::new();
+        counter(&mut out, "gitcloner_clones_total", "Clones attempted", self.clones_total.load(Ordering::Relaxed));
+        counter(&mut out, "gitcloner_clone_failures_total", "Clones that failed", self.clone_failures_total.load(Ordering::Relaxed));
+        counter(&mut out, "gitcloner_syncs_total", "Syncs attempted", self.syncs_total.load(Ordering::Relaxed));
+        counter(&mut out, "gitcloner_sync_failures_total", "Syncs that failed", self.sync_failures_total.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP gitcloner_last_sync_duration_seconds Duration of the most recent sync");
+        let _ = writeln!(out, "# TYPE gitcloner_last_sync_duration_seconds gauge");
+        let _ = writeln!(
+            out,
+            "gitcloner_last_sync_duration_seconds {:.3}",
+            self.last_sync_duration_ms.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+
+        let _ = writeln!(out, "# HELP gitcloner_repositories Tracked repositories by status");
+        let _ = writeln!(out, "# TYPE gitcloner_repositories gauge");
+        for (status, count) in repositories_by_status {
+            let _ = writeln!(out, "gitcloner_repositories{{status=\"{}\"}} {}", escape_label(status), count);
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -6,18 +6,445 @@ pub struct User {
     pub id: i64,
     pub username: String,
     pub password_hash: String,
+    pub role: Role,
     pub created_at: DateTime<Utc>,
 }
 
+/// Admins may change repositories and settings, other users can only read them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+}
+
+impl Role {
+    /// Unknown values get the least privileges.
+    pub fn parse(value: &str) -> Role {
+        match value {
+            "admin" => Role::Admin,
+            _ => Role::User,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::User => "user",
+        }
+    }
+}
+
+/// State of a repository as stored in its `status` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepoStatus {
+    /// Being cloned; the row is removed again if the clone fails.
+    Cloning,
+    /// Cloned but not synced yet.
+    Pending,
+    /// A sync is running. Stays behind if the server stops before it finishes.
+    Syncing,
+    Synced,
+    /// The repository uses Git LFS but only pointer files were checked out.
+    LfsPointersOnly,
+    Error,
+    Timeout,
+    Cancelled,
+    /// A verification found missing or unreadable objects or refs.
+    Corrupt,
+    /// A sync was stopped because the repository outgrew its size limit.
+    TooLarge,
+}
+
+impl RepoStatus {
+    pub const ALL: [RepoStatus; 10] = [
+        RepoStatus::Cloning,
+        RepoStatus::Pending,
+        RepoStatus::Syncing,
+        RepoStatus::Synced,
+        RepoStatus::LfsPointersOnly,
+        RepoStatus::Error,
+        RepoStatus::Timeout,
+        RepoStatus::Cancelled,
+        RepoStatus::Corrupt,
+        RepoStatus::TooLarge,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepoStatus::Cloning => "cloning",
+            RepoStatus::Pending => "pending",
+            RepoStatus::Syncing => "syncing",
+            RepoStatus::Synced => "synced",
+            RepoStatus::LfsPointersOnly => "lfs_pointers_only",
+            RepoStatus::Error => "error",
+            RepoStatus::Timeout => "timeout",
+            RepoStatus::Cancelled => "cancelled",
+            RepoStatus::Corrupt => "corrupt",
+            RepoStatus::TooLarge => "too_large",
+        }
+    }
+}
+
+impl std::str::FromStr for RepoStatus {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        RepoStatus::ALL.into_iter().find(|status| status.as_str() == value).ok_or_else(|| {
+            let names: Vec<_> = RepoStatus::ALL.iter().map(RepoStatus::as_str).collect();
+            format!("unknown status '{}', expected one of: {}", value, names.join(", "))
+        })
+    }
+}
+
+impl std::fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Repository {
     pub id: i64,
     pub url: String,
     pub name: String,
     pub local_path: String,
+    pub branch: Option<String>,
+    pub ssh_key_path: Option<String>,
+    /// Bare `--mirror` clone kept identical to the remote, without a working tree.
+    #[serde(default)]
+    pub mirror: bool,
+    #[serde(default)]
+    pub recurse_submodules: bool,
+    /// Download cap of clones and syncs in KiB/s, `None` for the `FETCH_RATE_LIMIT_KBPS` default and 0 for none.
+    #[serde(default)]
+    pub fetch_rate_limit_kbps: Option<i64>,
+    /// Size cap of the clone in MiB, `None` for the `MAX_REPO_SIZE_MB` default and 0 for none.
+    #[serde(default)]
+    pub max_size_mb: Option<i64>,
+    /// Included in the daily sync and synced by push webhooks; manual syncs work either way.
+    #[serde(default = "auto_sync_default")]
+    pub auto_sync_enabled: bool,
     pub last_synced: Option<DateTime<Utc>>,
+    pub last_sync_attempt: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub status: RepoStatus,
+    /// Why the last sync failed, cleared by the next successful one.
+    pub last_error: Option<String>,
+    /// Set while the repository sits in the trash after being removed.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// On-disk size of the clone as measured after its last clone or sync.
+    pub size_bytes: Option<i64>,
+    /// Successful syncs since `git gc` last ran on the clone.
+    #[serde(default)]
+    pub syncs_since_gc: i64,
+    /// HEAD commit as of the last clone or sync, `None` for empty repositories.
+    pub last_commit: Option<CommitInfo>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub in_progress: bool,
+    pub operation: Option<Operation>,
+    #[serde(skip_deserializing)]
+    pub health: Option<RepositoryHealth>,
+}
+
+// Repositories are synced automatically unless paused
+fn auto_sync_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitInfo {
+    pub sha: String,
+    pub author: String,
+    /// First line of the commit message.
+    pub summary: String,
+    pub committed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepositoryHealth {
+    pub score: u8,
+    pub level: HealthLevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthLevel {
+    Green,
+    Yellow,
+    Red,
+}
+
+/// Liveness and readiness of the service itself, for load balancer probes.
+#[derive(Debug, Serialize)]
+pub struct ServiceHealth {
+    pub status: &'static str,
+    pub db_ok: bool,
+    pub repos_count: Option<i64>,
+    /// Automatic syncing is paused for all repositories, `None` when the database is unreachable.
+    pub sync_paused: Option<bool>,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RepositoryDetail {
+    #[serde(flatten)]
+    pub repository: Repository,
+    pub current_branch: Option<String>,
+    #[serde(flatten)]
+    pub stats: Option<RepositoryStats>,
+    /// Outcome of the most recent mirror audit, `None` if the repository was never audited.
+    pub audit_ok: Option<bool>,
+    pub last_audited: Option<DateTime<Utc>>,
+}
+
+/// Branch, tag and commit counts of a local clone.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepositoryStats {
+    pub branch_count: usize,
+    pub tag_count: usize,
+    /// Commits reachable from HEAD, counted up to a limit.
+    pub commit_count: usize,
+    /// Set when the repository has more commits than `commit_count`.
+    pub commit_count_capped: bool,
+    /// Upstream branches present in the clone.
+    pub branches: Vec<String>,
+}
+
+/// Differences between the upstream refs and the local mirror, as found by an audit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditReport {
+    pub ok: bool,
+    /// Upstream refs with no local counterpart.
+    pub missing: Vec<RefDifference>,
+    /// Local refs no longer present upstream.
+    pub extra: Vec<RefDifference>,
+    /// Refs present on both sides but pointing at different commits.
+    pub mismatched: Vec<RefDifference>,
+}
+
+/// Integrity problems found in a clone by a verification.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VerifyReport {
+    pub ok: bool,
+    /// What is wrong, e.g. a ref pointing at a missing commit, or the complaints of `git fsck`.
+    pub problems: Vec<String>,
+}
+
+/// Space reclaimed by running `git gc` on a clone.
+#[derive(Debug, Clone, Serialize)]
+pub struct GarbageCollection {
+    pub size_before: u64,
+    pub size_after: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// Kind of an entry in a repository tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeEntryKind {
+    Directory,
+    File,
+    Symlink,
+    Submodule,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: TreeEntryKind,
+    /// Size in bytes of files and symlinks.
+    pub size: Option<u64>,
+    /// Git file mode in octal, e.g. `100644`, or `100755` for an executable.
+    pub mode: String,
+}
+
+/// A directory of a repository at some revision.
+#[derive(Debug, Clone, Serialize)]
+pub struct TreeListing {
+    /// Commit the ref resolved to, which can be passed as the ref of follow-up requests.
+    pub revision: String,
+    /// Empty for the root directory.
+    pub path: String,
+    /// Directories first, then by name.
+    pub entries: Vec<TreeEntry>,
+}
+
+/// A file of a repository at some revision.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlobContent {
+    /// Commit the ref resolved to.
+    pub revision: String,
+    pub path: String,
+    pub size: u64,
+    /// The file isn't UTF-8 text. Unknown when the content was left out.
+    pub binary: Option<bool>,
+    /// Text as is, base64 for binary files. Left out for files too large to return inline.
+    pub content: Option<String>,
+}
+
+/// Changes between the trees of two revisions of a repository.
+#[derive(Debug, Clone, Serialize)]
+pub struct RepositoryDiff {
+    /// Commit the `from` ref resolved to.
+    pub from: String,
+    /// Commit the `to` ref resolved to.
+    pub to: String,
+    /// `from` is an ancestor of `to`, or the same commit, as after a fast-forward.
+    pub fast_forward: bool,
+    pub additions: usize,
+    pub deletions: usize,
+    pub files: Vec<FileChange>,
+    /// Unified diff of all files, only when asked for.
+    pub patch: Option<String>,
+    /// The patch was cut off at a file boundary because it grew too large.
+    pub patch_truncated: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    /// Where a renamed or copied file came from.
+    pub old_path: Option<String>,
+    pub status: FileChangeStatus,
+    pub additions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChangeStatus {
+    Added,
+    Deleted,
+    Modified,
+    Renamed,
+    Copied,
+    /// Turned from a file into a symlink or submodule, or the other way around.
+    TypeChanged,
+}
+
+/// What a sync would do, found by fetching into the remote-tracking refs without moving local branches.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncPreview {
+    /// Branch the sync would fast-forward: the tracked branch, or the checked-out one.
+    pub branch: Option<String>,
+    /// Local commits missing upstream.
+    pub ahead: usize,
+    /// Upstream commits missing locally.
+    pub behind: usize,
+    pub would_fast_forward: bool,
+    pub diverged: bool,
+    /// Uncommitted changes, which make the sync leave the branch alone.
+    pub local_changes: bool,
+    /// Upstream branches the fetch found for the first time.
+    pub new_branches: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefDifference {
+    /// Upstream ref name, e.g. `refs/heads/main` or `refs/tags/v1.0`.
+    pub name: String,
+    pub upstream: Option<String>,
+    pub local: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Clone,
+    Sync,
+    Bundle,
+    Audit,
+    ChangeUrl,
+    SyncPreview,
+    Verify,
+    Gc,
+    Remove,
+    Restore,
+    AddWorktree,
+    RemoveWorktree,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Clone => "clone",
+            Operation::Sync => "sync",
+            Operation::Bundle => "bundle",
+            Operation::Audit => "audit",
+            Operation::ChangeUrl => "change_url",
+            Operation::SyncPreview => "sync_preview",
+            Operation::Verify => "verify",
+            Operation::Gc => "gc",
+            Operation::Remove => "remove",
+            Operation::Restore => "restore",
+            Operation::AddWorktree => "add_worktree",
+            Operation::RemoveWorktree => "remove_worktree",
+        }
+    }
+}
+
+/// Transfer progress of a running clone or sync, as reported by libgit2.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferProgress {
+    pub operation: Operation,
+    pub received_objects: usize,
+    pub total_objects: usize,
+    pub indexed_objects: usize,
+    pub received_bytes: usize,
+}
+
+/// Who changed what, kept for compliance. `target` is the repository URL or username acted on.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub created_at: DateTime<Utc>,
+    pub username: String,
+    /// Dotted name of the action, e.g. `repository.add` or `user.remove`.
+    pub action: String,
+    pub target: String,
+    pub detail: Option<String>,
+}
+
+/// One finished sync of a repository, kept for `SYNC_HISTORY_RETENTION_DAYS`.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncHistoryEntry {
+    pub id: i64,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Repository status the sync ended with, e.g. `synced`, `error` or `timeout`.
+    pub status: RepoStatus,
+    pub error: Option<String>,
+    /// Upstream commits the branch gained, `None` when the sync failed.
+    pub commits_advanced: Option<i64>,
+}
+
+/// A logged-in session. The token itself is never shown, `id` identifies it instead.
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub username: String,
+    pub role: Role,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionQuery {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Worktree {
+    pub id: i64,
+    pub repository_id: i64,
+    pub branch: String,
+    pub path: String,
     pub created_at: DateTime<Utc>,
-    pub status: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,6 +456,156 @@ pub struct LoginRequest {
 #[derive(Debug, Deserialize)]
 pub struct AddRepositoryRequest {
     pub url: String,
+    pub branch: Option<String>,
+    pub credentials: Option<RepositoryCredentials>,
+    pub ssh_key_path: Option<String>,
+    /// Name of a template whose settings fill in anything not given in the request.
+    pub template: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Clone as a bare mirror of every ref instead of a checkout. Off unless set here or by the template.
+    pub mirror: Option<bool>,
+    /// Also check out submodules (recursively), on clone and on every sync. Off unless set here or by the template.
+    pub recurse_submodules: Option<bool>,
+    /// Replace whatever is left at the clone's location, e.g. by a clone that crashed halfway.
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Take over a clone of the URL that is already at the clone's location, e.g. after restoring the database from
+    /// a backup, instead of failing. Clones as usual when the location is empty.
+    #[serde(default)]
+    pub adopt: bool,
+    /// Download cap in KiB/s instead of the `FETCH_RATE_LIMIT_KBPS` default, 0 for none.
+    pub fetch_rate_limit_kbps: Option<u64>,
+    /// Size cap in MiB instead of the `MAX_REPO_SIZE_MB` default, 0 for none.
+    pub max_size_mb: Option<u64>,
+    /// `false` leaves the repository out of the daily sync and webhook syncs from the start. On unless set here or by
+    /// the template.
+    pub auto_sync_enabled: Option<bool>,
+}
+
+/// Portable list of tracked repositories, used to move them between instances.
+/// Credentials are never included.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepositoryExport {
+    pub repositories: Vec<ExportedRepository>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedRepository {
+    pub url: String,
+    /// Informational only, imports derive the name from the URL again.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub ssh_key_path: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub mirror: bool,
+    #[serde(default)]
+    pub recurse_submodules: bool,
+    #[serde(default)]
+    pub fetch_rate_limit_kbps: Option<u64>,
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    #[serde(default = "auto_sync_default")]
+    pub auto_sync_enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeUrlRequest {
+    pub url: String,
+    /// Check that the new remote can be reached before switching to it.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FetchRateLimitRequest {
+    /// KiB/s, 0 for no limit and `null` for the `FETCH_RATE_LIMIT_KBPS` default.
+    pub kbps: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MaxSizeRequest {
+    /// MiB, 0 for no limit and `null` for the `MAX_REPO_SIZE_MB` default.
+    pub mb: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagRequest {
+    pub tag: String,
+}
+
+/// The parts of a GitHub (or Gitea) push event used to find the repository that changed.
+#[derive(Debug, Deserialize)]
+pub struct GithubPushEvent {
+    pub repository: GithubRepository,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GithubRepository {
+    pub clone_url: Option<String>,
+    pub ssh_url: Option<String>,
+    pub html_url: Option<String>,
+}
+
+/// Stored settings copied onto repositories added with `"template": "<name>"`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Template {
+    pub id: i64,
+    pub name: String,
+    pub branch: Option<String>,
+    pub ssh_key_path: Option<String>,
+    /// Credentials themselves are never returned, only whether the template has some.
+    pub has_credentials: bool,
+    pub tags: Vec<String>,
+    pub auto_sync_enabled: Option<bool>,
+    pub mirror: Option<bool>,
+    pub recurse_submodules: Option<bool>,
+    pub fetch_rate_limit_kbps: Option<i64>,
+    pub max_size_mb: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TemplateRequest {
+    pub name: String,
+    pub branch: Option<String>,
+    pub credentials: Option<RepositoryCredentials>,
+    pub ssh_key_path: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub auto_sync_enabled: Option<bool>,
+    pub mirror: Option<bool>,
+    pub recurse_submodules: Option<bool>,
+    pub fetch_rate_limit_kbps: Option<u64>,
+    pub max_size_mb: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddWorktreeRequest {
+    pub branch: String,
+    pub path: String,
+}
+
+// Deliberately not `Serialize` so secrets can never end up in a response
+#[derive(Clone, Deserialize)]
+pub struct RepositoryCredentials {
+    pub username: String,
+    pub token: String,
+}
+
+impl std::fmt::Debug for RepositoryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RepositoryCredentials")
+            .field("username", &self.username)
+            .field("token", &"<redacted>")
+            .finish()
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -38,12 +615,176 @@ pub struct ApiResponse<T> {
     pub message: Option<String>,
 }
 
+/// Body of failed requests: the `ApiResponse` envelope plus a stable `code` clients can match on.
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    pub success: bool,
+    pub data: Option<()>,
+    pub message: String,
+    pub code: ErrorCode,
+}
+
+/// Machine-readable reason a request failed. The names are part of the API, only add new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Missing or invalid token for an endpoint with its own token, such as the metrics.
+    Unauthorized,
+    /// Wrong username or password at login.
+    InvalidCredentials,
+    /// No `Authorization: Bearer` header was sent.
+    MissingToken,
+    /// The token is unknown, malformed or was revoked.
+    InvalidToken,
+    /// The token was valid but its session ran out.
+    TokenExpired,
+    /// Authenticated, but the action requires the admin role.
+    Forbidden,
+    RateLimited,
+    /// The request body or parameters are invalid.
+    InvalidRequest,
+    InvalidUrl,
+    /// The URL's host is not one repositories may be cloned from.
+    HostNotAllowed,
+    InvalidSignature,
+    /// No endpoint matches the path.
+    NotFound,
+    MethodNotAllowed,
+    /// The feature needs server configuration that is missing.
+    NotConfigured,
+    RepoNotFound,
+    RepoAlreadyExists,
+    /// The repository was removed and still waits in the trash.
+    RepoInTrash,
+    TemplateNotFound,
+    TemplateAlreadyExists,
+    WorktreeNotFound,
+    TagNotFound,
+    /// The requested branch, tag or commit doesn't exist in the clone.
+    RefNotFound,
+    /// Nothing exists at the requested path of the repository tree.
+    PathNotFound,
+    SessionNotFound,
+    NoOperationRunning,
+    /// Another git operation is running for the repository.
+    OperationInProgress,
+    /// The clone or sync was cancelled through the API.
+    Cancelled,
+    CloneFailed,
+    SyncFailed,
+    AuditFailed,
+    VerifyFailed,
+    GcFailed,
+    ArchiveFailed,
+    BundleFailed,
+    WorktreeFailed,
+    RestoreFailed,
+    Timeout,
+    InsufficientStorage,
+    /// Automatic syncing is paused for all repositories.
+    SyncPaused,
+    /// The repository is larger than its size limit.
+    RepoTooLarge,
+    /// All git operation slots are taken.
+    Overloaded,
+    InternalError,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncQuery {
+    /// Skip the manual sync cooldown.
+    pub force: Option<bool>,
+    /// Hard reset the branch to upstream, discarding local commits and changes.
+    pub reset: Option<bool>,
+    /// Refuse the sync while automatic syncing is paused for all repositories.
+    pub respect_pause: Option<bool>,
+    /// `high` starts the repository next when the daily sync has it queued, instead of syncing it right away.
+    pub priority: Option<SyncPriority>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncPriority {
+    #[default]
+    Normal,
+    High,
+}
+
+/// Format of a repository archive download.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "tar.gz")]
+    TarGz,
+    #[serde(rename = "zip")]
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Also the format name `git archive` takes.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveQuery {
+    pub format: Option<ArchiveFormat>,
+    /// Branch, tag, commit or any other revision, `HEAD` by default.
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BrowseQuery {
+    /// Path inside the repository, the root directory by default.
+    pub path: Option<String>,
+    /// Branch, tag, commit or any other revision, `HEAD` by default.
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    pub from: String,
+    /// `HEAD` by default.
+    pub to: Option<String>,
+    /// Include the unified diff text.
+    pub patch: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyQuery {
+    /// Also run `git fsck`, which reads every object and can take a while.
+    pub fsck: Option<bool>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PaginationQuery {
     pub page: Option<u32>,
     pub limit: Option<u32>,
 }
 
+/// Optional filters for the repository list, combined with `PaginationQuery`.
+#[derive(Debug, Default, Deserialize)]
+pub struct RepositoryFilter {
+    /// Case-insensitive substring of the name or URL.
+    pub q: Option<String>,
+    /// One of the `RepoStatus` names.
+    pub status: Option<String>,
+    pub tag: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PaginatedResponse<T> {
     pub items: Vec<T>,
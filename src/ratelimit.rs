@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+struct Attempts {
+    failures: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts failed logins per key (username or client address) and locks a key out
+/// after too many failures within the window.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    attempts: Arc<Mutex<HashMap<String, Attempts>>>,
+    max_failures: u32,
+    window: Duration,
+    lockout: Duration,
+}
+
+impl LoginLimiter {
+    pub fn new(config: &Config) -> Self {
+        LoginLimiter {
+            attempts: Arc::new(Mutex::new(HashMap::new())),
+            max_failures: config.login_max_failures,
+            window: config.login_failure_window,
+            lockout: config.login_lockout,
+        }
+    }
+
+    /// Returns how long to wait if any of the keys is locked out.
+    pub fn check(&self, keys: &[String]) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let attempts = self.attempts.lock().unwrap();
+        keys.iter()
+            .filter_map(|key| attempts.get(key)?.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+            .max()
+    }
+
+    pub fn record_failure(&self, keys: &[String]) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            let entry = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                window_start: now,
+                locked_until: None,
+            });
+            if now.duration_since(entry.window_start) >= self.window {
+                entry.failures = 0;
+                entry.window_start = now;
+            }
+            entry.failures += 1;
+            if entry.failures >= self.max_failures {
+                warn!("Too many failed logins for {}, locking it out for {:?}", key, self.lockout);
+                entry.failures = 0;
+                entry.window_start = now;
+                entry.locked_until = Some(now + self.lockout);
+            }
+        }
+    }
+
+    pub fn reset(&self, keys: &[String]) {
+        let mut attempts = self.attempts.lock().unwrap();
+        for key in keys {
+            attempts.remove(key);
+        }
+    }
+
+    /// Forgets keys that are neither locked out nor have recent failures, returning how many were dropped.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut attempts = self.attempts.lock().unwrap();
+        let before = attempts.len();
+        attempts.retain(|_, entry| {
+            entry.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(entry.window_start) < self.window
+        });
+        before - attempts.len()
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let purged = limiter.purge_expired();
+                if purged > 0 {
+                    debug!("Purged {} stale login attempt counters", purged);
+                }
+            }
+        });
+    }
+}
+
+/// Which allowance of a client a request draws from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestClass {
+    /// Every API request.
+    Api,
+    /// Requests that clone or sync repositories, on top of `Api`.
+    GitOperation,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets per client address and request class. A bucket holds a minute's worth of requests,
+/// so a client may burst up to the limit and then continue at the steady rate.
+#[derive(Clone)]
+pub struct RequestLimiter {
+    buckets: Arc<Mutex<HashMap<(IpAddr, RequestClass), Bucket>>>,
+    api_per_minute: u64,
+    git_operations_per_minute: u64,
+}
+
+impl RequestLimiter {
+    pub fn new(config: &Config) -> Self {
+        RequestLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            api_per_minute: config.api_rate_limit_per_min,
+            git_operations_per_minute: config.git_op_rate_limit_per_min,
+        }
+    }
+
+    fn per_minute(&self, class: RequestClass) -> u64 {
+        match class {
+            RequestClass::Api => self.api_per_minute,
+            RequestClass::GitOperation => self.git_operations_per_minute,
+        }
+    }
+
+    /// Takes a request from the client's allowance, or returns how long until it has one again.
+    pub fn acquire(&self, ip: IpAddr, class: RequestClass) -> Result<(), Duration> {
+        let per_minute = self.per_minute(class);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let capacity = per_minute as f64;
+        let per_second = capacity / 60.0;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((ip, class)).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    /// Forgets buckets that have filled up again, returning how many were dropped.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let before = buckets.len();
+        buckets.retain(|(_, class), bucket| {
+            let capacity = self.per_minute(*class) as f64;
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * capacity / 60.0 < capacity
+        });
+        before - buckets.len()
+    }
+
+    pub fn spawn_sweeper(&self, interval: Duration) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let purged = limiter.purge_expired();
+                if purged > 0 {
+                    debug!("Purged {} idle request rate buckets", purged);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 9));
+
+    fn limiter(api_per_minute: u64, git_operations_per_minute: u64) -> RequestLimiter {
+        RequestLimiter {
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            api_per_minute,
+            git_operations_per_minute,
+        }
+    }
+
+    #[test]
+    fn bursts_up_to_the_limit_then_waits_for_a_token() {
+        let limiter = limiter(60, 0);
+        for _ in 0..60 {
+            assert!(limiter.acquire(CLIENT, RequestClass::Api).is_ok());
+        }
+        let wait = limiter.acquire(CLIENT, RequestClass::Api).unwrap_err();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+    }
+
+    #[test]
+    fn clients_and_classes_have_their_own_allowance() {
+        let limiter = limiter(1, 1);
+        assert!(limiter.acquire(CLIENT, RequestClass::Api).is_ok());
+        assert!(limiter.acquire(CLIENT, RequestClass::Api).is_err());
+        assert!(limiter.acquire(CLIENT, RequestClass::GitOperation).is_ok());
+        assert!(limiter.acquire(CLIENT, RequestClass::GitOperation).is_err());
+        assert!(limiter.acquire(IpAddr::from([198, 51, 100, 1]), RequestClass::Api).is_ok());
+    }
+
+    #[test]
+    fn zero_per_minute_disables_the_limit() {
+        let limiter = limiter(0, 1);
+        for _ in 0..1000 {
+            assert!(limiter.acquire(CLIENT, RequestClass::Api).is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refilled_buckets_are_purged() {
+        let limiter = limiter(60, 60);
+        limiter.acquire(CLIENT, RequestClass::Api).unwrap();
+        limiter.acquire(CLIENT, RequestClass::GitOperation).unwrap();
+        assert_eq!(limiter.purge_expired(), 0);
+
+        let a_minute_ago = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        limiter.buckets.lock().unwrap().get_mut(&(CLIENT, RequestClass::Api)).unwrap().updated = a_minute_ago;
+        assert_eq!(limiter.purge_expired(), 1);
+        assert!(limiter.buckets.lock().unwrap().contains_key(&(CLIENT, RequestClass::GitOperation)));
+    }
+}
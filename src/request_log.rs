@@ -0,0 +1,74 @@
+//! Request ids and access logging.
+//!
+//! Every request runs in a `request` span carrying its method, path, client address and id, so everything logged
+//! while handling it, including the spans of the git operations it starts, can be traced back to it.
+//! The id is taken from the client's `X-Request-Id` header when it sends a usable one and echoed
+//! back in the response.
+
+use tracing::field::{display, Empty};
+use tracing::{info, info_span, Span};
+use uuid::Uuid;
+use warp::http::HeaderValue;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Longer or unusual ids from clients are replaced rather than logged
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Span of a request, for `warp::trace`. The id is recorded by `with_request_id`.
+pub fn span(info: warp::trace::Info) -> Span {
+    let client = crate::client_ip::client_ip(info.remote_addr(), info.request_headers());
+    info_span!(
+        "request",
+        method = %info.method(),
+        path = %info.path(),
+        client = client.map(display),
+        request_id = Empty
+    )
+}
+
+/// Wraps `filter` so that each request gets an id, recorded in the current span and returned in the
+/// `X-Request-Id` response header.
+pub fn with_request_id<F, R>(filter: F) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    warp::header::optional::<String>(REQUEST_ID_HEADER)
+        .map(|id: Option<String>| {
+            let id = id.filter(|id| valid_request_id(id)).unwrap_or_else(|| Uuid::new_v4().to_string());
+            Span::current().record("request_id", display(&id));
+            id
+        })
+        .and(filter)
+        .map(|id: String, reply: R| {
+            let mut response = reply.into_response();
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            response
+        })
+}
+
+/// Logs the status and latency of every request, leaving out the web interface's files unless
+/// `log_static` is set.
+pub fn access_log(log_static: bool) -> warp::log::Log<impl Fn(warp::log::Info<'_>) + Clone> {
+    warp::log::custom(move |info| {
+        if !log_static && is_static(info.path()) {
+            return;
+        }
+        info!(status = info.status().as_u16(), elapsed_ms = info.elapsed().as_millis() as u64, "finished");
+    })
+}
+
+fn is_static(path: &str) -> bool {
+    path == "/" || path.starts_with("/static/")
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
@@ -0,0 +1,338 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use tracing::{error, info, warn, Instrument};
+
+use crate::database::Database;
+use crate::git_manager::{self, GitError, GitManager, SyncOutcome};
+use crate::models::{GarbageCollection, Operation, RepoStatus, Repository};
+
+/// Who the audit log names for changes made by syncs nobody requested through the API.
+pub const SYNC_ACTOR: &str = "sync";
+
+lazy_static::lazy_static! {
+    /// Repositories the daily sync has yet to start.
+    pub static ref SYNC_QUEUE: SyncQueue = SyncQueue::default();
+    /// Ids of repositories a push arrived for while another operation held them.
+    static ref PENDING_RESYNCS: Mutex<HashSet<i64>> = Mutex::new(HashSet::new());
+}
+
+/// Repositories waiting for a free slot in a sync of all repositories, started in order.
+#[derive(Default)]
+pub struct SyncQueue(Mutex<VecDeque<Repository>>);
+
+impl SyncQueue {
+    fn push(&self, repos: Vec<Repository>) {
+        self.0.lock().unwrap().extend(repos);
+    }
+
+    fn pop(&self) -> Option<Repository> {
+        self.0.lock().unwrap().pop_front()
+    }
+
+    /// Moves a queued repository to the front so it is started next. Returns false when it isn't queued,
+    /// syncs that already started are never interrupted.
+    pub fn prioritize(&self, url: &str) -> bool {
+        let mut queue = self.0.lock().unwrap();
+        let Some(repo) = queue.iter().position(|repo| repo.url == url).and_then(|index| queue.remove(index)) else {
+            return false;
+        };
+        queue.push_front(repo);
+        true
+    }
+}
+
+/// Syncs every tracked repository, `concurrency` at a time, like the daily sync does. The repositories
+/// wait in `queue` for a free slot. Returns what `sync_one_repository` reported for each of them, in
+/// no particular order.
+pub async fn sync_all_repositories(
+    db: &Database,
+    git_manager: &GitManager,
+    queue: &SyncQueue,
+    concurrency: usize,
+) -> Result<Vec<(Repository, Result<Result<SyncOutcome>>)>> {
+    if db.sync_paused().await? {
+        info!("Automatic syncing is paused, skipping the sync of all repositories");
+        return Ok(Vec::new());
+    }
+    let (repos, paused): (Vec<_>, Vec<_>) = db.get_all_repositories().await?.into_iter().partition(|repo| repo.auto_sync_enabled);
+    if !paused.is_empty() {
+        info!("Skipping {} repositories with automatic syncing paused", paused.len());
+    }
+    info!("Syncing {} repositories, {} at a time", repos.len(), concurrency);
+    queue.push(repos);
+    
+    // Taken from the queue only once a slot is free, so a repository prioritized meanwhile goes next
+    Ok(stream::iter(std::iter::from_fn(|| queue.pop()))
+        .map(|repo| async move {
+            let result = sync_one_repository(db, git_manager, &repo).await;
+            (repo, result)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await)
+}
+
+/// Syncs one repository and records the outcome in the database.
+/// The outer error means the database couldn't be updated, the inner one is the sync failure
+/// that was recorded as the repository's status.
+pub async fn sync_one_repository(db: &Database, git_manager: &GitManager, repo: &Repository) -> Result<Result<SyncOutcome>> {
+    // Nothing to sync until the clone is in place
+    if repo.status == RepoStatus::Cloning {
+        warn!("Skipped sync of {}: it is still being cloned", repo.url);
+        return Ok(Err(GitError::Busy.into()));
+    }
+    let started_at = Utc::now();
+    mark_sync_started(db, &repo.url).await?;
+    let mut result = match db.get_sync_options(repo).await {
+        Ok(options) => git_manager.sync_repository(repo, &options).await,
+        Err(e) => Err(e),
+    };
+    let mut url = repo.url.clone();
+    if let Some(new_url) = moved_to(&result) {
+        match record_move(db, git_manager, SYNC_ACTOR, repo, &new_url).await {
+            Ok(()) => url = new_url,
+            // Still tracked and fetched from the old URL, which only redirects, so the repository needs attention
+            Err(e) => {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+    }
+    match &result {
+        // Nothing was attempted, the status belongs to the operation that is running
+        Err(e) if matches!(e.downcast_ref::<GitError>(), Some(GitError::Busy)) => {
+            warn!("Skipped sync of {}: {}", repo.url, e);
+            restore_skipped_status(db, git_manager, repo).await?;
+        }
+        Err(e) => {
+            error!("Failed to sync repository {}: {:#}", url, e);
+            db.update_repository_status(&url, git_manager::failure_status(e)).await?;
+            db.update_last_error(&url, Some(&format!("{:#}", e))).await?;
+        }
+        Ok(outcome) => {
+            db.update_repository_status(&url, outcome.status()).await?;
+            db.update_last_error(&url, None).await?;
+            db.update_last_synced(&url).await?;
+            refresh_local_details(db, git_manager, &url, &repo.local_path).await;
+            if git_manager.gc_due(repo.syncs_since_gc + 1) {
+                // The row is keyed by the URL it was just moved to, if any
+                let repo = Repository { url, ..repo.clone() };
+                if let Err(e) = collect_garbage(db, git_manager, &repo).await {
+                    warn!("Garbage collection of {} failed: {}", repo.url, e);
+                }
+            }
+        }
+    }
+    record_sync_history(db, repo.id, started_at, &result).await;
+    Ok(result)
+}
+
+/// URL a sync found its repository moved to, whether or not the rest of the sync succeeded.
+pub fn moved_to(result: &Result<SyncOutcome>) -> Option<String> {
+    match result {
+        Ok(outcome) => outcome.moved_to.clone(),
+        Err(e) => git_manager::moved_to(e).map(str::to_string),
+    }
+}
+
+/// Tracks a repository under the URL its host moved it to, on behalf of `username`: points the clone's origin
+/// at it, updates the row and records the move in the audit log. Fails without changing anything when another
+/// repository is tracked under the new URL.
+pub async fn record_move(db: &Database, git_manager: &GitManager, username: &str, repo: &Repository, new_url: &str) -> Result<()> {
+    if let Some(existing) = db.get_repository_matching_url(new_url).await? {
+        if existing.id != repo.id {
+            return Err(anyhow!("{} moved to {}, which is already tracked as another repository", repo.url, new_url));
+        }
+    }
+    warn!("Repository {} moved, now tracking {}", repo.url, new_url);
+    git_manager.set_remote_url(repo, new_url).await?;
+    if let Err(e) = db.update_repository_url(&repo.url, new_url).await {
+        // Keep the clone pointing at the URL that is still recorded
+        if let Err(revert_err) = git_manager.set_remote_url(repo, &repo.url).await {
+            error!("Failed to point {} back at {}: {}", repo.local_path, repo.url, revert_err);
+        }
+        return Err(e);
+    }
+    // The URL already changed, so a failure to log it doesn't fail the sync
+    if let Err(e) = db.record_audit(username, "repository.move", &repo.url, Some(new_url)).await {
+        error!("Failed to record repository.move of {} in the audit log: {}", repo.url, e);
+    }
+    Ok(())
+}
+
+/// Runs `git gc` on a repository, then stores the clone's new size and restarts the count of syncs
+/// towards the next automatic one.
+pub async fn collect_garbage(db: &Database, git_manager: &GitManager, repo: &Repository) -> Result<GarbageCollection> {
+    let collected = git_manager.gc_repository(repo).await?;
+    db.reset_syncs_since_gc(&repo.url).await?;
+    db.update_repository_size(&repo.url, collected.size_after).await?;
+    Ok(collected)
+}
+
+/// Fixes up what a previous run left behind when it stopped in the middle of an operation: clones and syncs
+/// that never recorded their outcome, clones that went missing and temporary directories of unfinished clones.
+pub async fn reconcile_on_startup(db: &Database, git_manager: &GitManager) -> Result<()> {
+    for path in git_manager.remove_abandoned_clones().await? {
+        info!("Removed unfinished clone {}", path.display());
+    }
+
+    for repo in db.get_repositories_by_status(&[RepoStatus::Cloning, RepoStatus::Syncing, RepoStatus::Pending]).await? {
+        match (repo.status, git_manager.clone_is_intact(&repo.local_path).await) {
+            (RepoStatus::Cloning, true) => {
+                db.update_repository_status(&repo.url, RepoStatus::Pending).await?;
+                info!("Clone of {} was completed but not recorded, it is marked pending", repo.url);
+            }
+            // The add never finished, so there is nothing to keep
+            (RepoStatus::Cloning, false) => {
+                db.purge_repository(repo.id).await?;
+                info!("Clone of {} was interrupted, the repository is removed", repo.url);
+            }
+            (RepoStatus::Syncing, true) => {
+                db.update_repository_status(&repo.url, RepoStatus::Synced).await?;
+                info!("Sync of {} was interrupted, its clone is intact so it is marked synced", repo.url);
+            }
+            // A pending repository just hasn't been synced yet, its status is still right
+            (_, true) => {}
+            (_, false) => {
+                db.update_repository_status(&repo.url, RepoStatus::Error).await?;
+                db.update_last_error(&repo.url, Some("The clone is missing or damaged, remove the repository and add it again")).await?;
+                warn!("Clone of {} at {} is missing or damaged, marked as failed", repo.url, repo.local_path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Syncs a repository once the operation running for it ends, so that a push that arrived in the meantime
+/// isn't missed. Returns false when a follow-up sync is already waiting, which picks up this push as well.
+pub fn resync_after_current_operation(db: Database, git_manager: GitManager, repo: &Repository) -> bool {
+    if !PENDING_RESYNCS.lock().unwrap().insert(repo.id) {
+        return false;
+    }
+    let id = repo.id;
+    // `None` when the operation already ended
+    let running = git_manager.subscribe_progress(&repo.url);
+    tokio::spawn(
+        async move {
+            if let Some(mut progress) = running {
+                while progress.changed().await.is_ok() {}
+            }
+            // Pushes from here on need a sync of their own
+            PENDING_RESYNCS.lock().unwrap().remove(&id);
+            // The operation may have moved or removed the repository
+            let repo = match db.get_repository_by_id(id).await {
+                Ok(Some(repo)) => repo,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Failed to load repository {} for its follow-up sync: {}", id, e);
+                    return;
+                }
+            };
+            let Some(_slot) = git_manager.try_acquire_slot() else {
+                warn!("Skipped follow-up sync of {}: too many operations are running", repo.url);
+                return;
+            };
+            if let Err(e) = sync_one_repository(&db, &git_manager, &repo).await {
+                error!("Failed to record sync result for {}: {}", repo.url, e);
+            }
+        }
+        .in_current_span(),
+    );
+    true
+}
+
+/// Records the attempt and shows the repository as `syncing` until the sync's outcome is stored.
+pub async fn mark_sync_started(db: &Database, url: &str) -> Result<()> {
+    db.update_last_sync_attempt(url).await?;
+    db.update_repository_status(url, RepoStatus::Syncing).await
+}
+
+/// Puts back the status a sync skipped with `GitError::Busy` had replaced, unless the operation it
+/// ran into is a sync that will store its own.
+pub async fn restore_skipped_status(db: &Database, git_manager: &GitManager, repo: &Repository) -> Result<()> {
+    if git_manager.current_operation(&repo.url) == Some(Operation::Sync) {
+        return Ok(());
+    }
+    db.update_repository_status(&repo.url, repo.status).await
+}
+
+/// Appends a finished sync to the repository's history. Syncs skipped because another operation was running
+/// never started and aren't recorded.
+pub async fn record_sync_history(db: &Database, repository_id: i64, started_at: DateTime<Utc>, result: &Result<SyncOutcome>) {
+    let recorded = match result {
+        Err(e) if matches!(e.downcast_ref::<GitError>(), Some(GitError::Busy)) => return,
+        Err(e) => {
+            db.add_sync_history(repository_id, started_at, git_manager::failure_status(e), Some(&format!("{:#}", e)), None).await
+        }
+        Ok(outcome) => db.add_sync_history(repository_id, started_at, outcome.status(), None, Some(outcome.commits_advanced)).await,
+    };
+    if let Err(e) = recorded {
+        warn!("Failed to record sync history of repository {}: {}", repository_id, e);
+    }
+}
+
+/// Caches the on-disk size and HEAD commit of a clone after it changed, since reading them on every
+/// request would be too slow.
+pub async fn refresh_local_details(db: &Database, git_manager: &GitManager, url: &str, local_path: &str) {
+    if let Some(size) = git_manager.disk_usage(local_path).await {
+        if let Err(e) = db.update_repository_size(url, size).await {
+            warn!("Failed to record the size of {}: {}", url, e);
+        }
+    }
+    let commit = git_manager.head_commit_info(local_path).await;
+    if let Err(e) = db.update_last_commit(url, commit.as_ref()).await {
+        warn!("Failed to record the last commit of {}: {}", url, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(url: &str) -> Repository {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "url": url,
+            "name": "github.com/org/repo",
+            "local_path": "/tmp/repos/github.com/org/repo",
+            "created_at": "2024-01-01T00:00:00Z",
+            "status": "synced",
+        }))
+        .unwrap()
+    }
+
+    fn drain(queue: &SyncQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop()).map(|repo| repo.url).collect()
+    }
+
+    #[test]
+    fn queue_starts_repositories_in_order() {
+        let queue = SyncQueue::default();
+        queue.push(vec![repository("a"), repository("b"), repository("c")]);
+        assert_eq!(drain(&queue), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn prioritized_repository_is_started_next() {
+        let queue = SyncQueue::default();
+        queue.push(vec![repository("a"), repository("b"), repository("c"), repository("d")]);
+        assert_eq!(queue.pop().map(|repo| repo.url).as_deref(), Some("a"));
+
+        assert!(queue.prioritize("c"));
+        assert_eq!(drain(&queue), ["c", "b", "d"]);
+    }
+
+    #[test]
+    fn started_repositories_cannot_be_prioritized() {
+        let queue = SyncQueue::default();
+        queue.push(vec![repository("a"), repository("b")]);
+        queue.pop();
+        assert!(!queue.prioritize("a"));
+        assert!(!queue.prioritize("unknown"));
+        assert_eq!(drain(&queue), ["b"]);
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::config::Config;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    SyncSucceeded,
+    SyncFailed,
+    CloneSucceeded,
+    CloneFailed,
+}
+
+impl WebhookEvent {
+    const ALL: [WebhookEvent; 4] = [
+        WebhookEvent::SyncSucceeded,
+        WebhookEvent::SyncFailed,
+        WebhookEvent::CloneSucceeded,
+        WebhookEvent::CloneFailed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            WebhookEvent::SyncSucceeded => "sync_succeeded",
+            WebhookEvent::SyncFailed => "sync_failed",
+            WebhookEvent::CloneSucceeded => "clone_succeeded",
+            WebhookEvent::CloneFailed => "clone_failed",
+        }
+    }
+}
+
+/// The events a webhook subscribes to, all of them by default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct WebhookEvents(Vec<WebhookEvent>);
+
+impl Default for WebhookEvents {
+    fn default() -> Self {
+        WebhookEvents(WebhookEvent::ALL.to_vec())
+    }
+}
+
+impl FromStr for WebhookEvents {
+    type Err = String;
+
+    /// Comma-separated event names.
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                WebhookEvent::ALL.into_iter().find(|event| event.name() == name).ok_or_else(|| {
+                    let names: Vec<_> = WebhookEvent::ALL.iter().map(|event| event.name()).collect();
+                    format!("unknown event '{}', expected one of {}", name, names.join(", "))
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(WebhookEvents)
+    }
+}
+
+impl TryFrom<String> for WebhookEvents {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Serialize)]
+struct Payload {
+    repo: String,
+    event: WebhookEvent,
+    status: String,
+    timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Posts clone and sync results to `WEBHOOK_URL`, limited to the `WEBHOOK_EVENTS` it subscribed to.
+pub struct Webhooks {
+    url: Option<String>,
+    events: Vec<WebhookEvent>,
+    client: reqwest::Client,
+}
+
+lazy_static::lazy_static! {
+    pub static ref WEBHOOKS: Webhooks = Webhooks::new(Config::get());
+}
+
+impl Webhooks {
+    pub fn new(config: &Config) -> Self {
+        let url = config.webhook_url.clone();
+        let events = config.webhook_events.0.clone();
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Webhooks { url, events, client }
+    }
+
+    /// Delivers one event in the background. Failures are only logged, they must never affect or hold up
+    /// the operation that triggered it.
+    pub fn notify(&self, event: WebhookEvent, repo_url: &str, status: &str, error: Option<&anyhow::Error>) {
+        let Some(url) = self.url.clone() else {
+            return;
+        };
+        if !self.events.contains(&event) {
+            return;
+        }
+        let payload = Payload {
+            repo: repo_url.to_string(),
+            event,
+            status: status.to_string(),
+            timestamp: Utc::now(),
+            error: error.map(|e| e.to_string()),
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            match client.post(&url).json(&payload).send().await {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Delivered {} webhook for {}", event.name(), payload.repo);
+                }
+                Ok(response) => {
+                    warn!("Webhook {} for {} was rejected with {}", event.name(), payload.repo, response.status());
+                }
+                Err(e) => warn!("Failed to deliver {} webhook for {}: {}", event.name(), payload.repo, e),
+            }
+        });
+    }
+}
+
+/// Checks a GitHub `X-Hub-Signature-256` header (`sha256=<hex>`) against the HMAC of the raw request body.
+pub fn verify_github_signature(secret: &[u8], body: &[u8], header: &str) -> bool {
+    let Some(signature) = header.strip_prefix("sha256=").and_then(|digest| hex::decode(digest).ok()) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(body);
+    // Constant-time comparison
+    mac.verify_slice(&signature).is_ok()
+}
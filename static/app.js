@@ -11,6 +11,7 @@ class GitCloner {
         this.bindEvents();
         if (this.token) {
             this.showApp();
+            this.loadCurrentUser();
             this.loadRepositories();
         } else {
             this.showLogin();
@@ -66,6 +67,28 @@ class GitCloner {
         }
     }
 
+    async loadCurrentUser() {
+        try {
+            const response = await fetch('/api/auth/me', {
+                headers: {
+                    'Authorization': `Bearer ${this.token}`,
+                },
+            });
+
+            const data = await response.json();
+
+            if (data.success) {
+                this.username = data.data.username;
+                localStorage.setItem('username', this.username);
+                document.getElementById('userInfo').textContent = `Welcome, ${this.username}`;
+            } else if (response.status === 401) {
+                this.logout();
+            }
+        } catch (error) {
+            // The repository list reports network errors
+        }
+    }
+
     logout() {
         this.token = null;
         this.username = null;
@@ -76,6 +99,9 @@ class GitCloner {
 
     async addRepository() {
         const repoUrl = document.getElementById('repoUrl').value;
+        const repoBranch = document.getElementById('repoBranch').value.trim();
+        const repoMirror = document.getElementById('repoMirror').checked;
+        const repoSubmodules = document.getElementById('repoSubmodules').checked;
         const form = document.getElementById('addRepoForm');
         const submitBtn = form.querySelector('button[type="submit"]');
         
@@ -90,7 +116,7 @@ class GitCloner {
                     'Content-Type': 'application/json',
                     'Authorization': `Bearer ${this.token}`,
                 },
-                body: JSON.stringify({ url: repoUrl }),
+                body: JSON.stringify({ url: repoUrl, branch: repoBranch || null, mirror: repoMirror, recurse_submodules: repoSubmodules }),
             });
 
             const data = await response.json();
@@ -98,6 +124,9 @@ class GitCloner {
             if (data.success) {
                 this.showAlert('appAlert', data.message || 'Repository cloned successfully', 'success');
                 document.getElementById('repoUrl').value = '';
+                document.getElementById('repoBranch').value = '';
+                document.getElementById('repoMirror').checked = false;
+                document.getElementById('repoSubmodules').checked = false;
                 this.loadRepositories();
             } else {
                 this.showAlert('appAlert', data.message || 'Failed to clone repository', 'error');
@@ -127,6 +156,9 @@ class GitCloner {
 
             if (data.success) {
                 this.renderRepositories(data.data);
+            } else if (response.status === 401) {
+                // The session expired or the server restarted
+                this.logout();
             } else {
                 listContainer.innerHTML = '<div style="padding: 20px; text-align: center; color: red;">Failed to load repositories</div>';
             }
@@ -153,17 +185,22 @@ class GitCloner {
             <div class="repo-item" data-url="${encodeURIComponent(repo.url)}">
                 <div class="repo-info">
                     <div class="repo-name">${this.escapeHtml(repo.name)}</div>
-                    <div class="repo-url">${this.escapeHtml(repo.url)}</div>
+                    <div class="repo-url">${this.escapeHtml(repo.url)}${repo.branch ? ` (${this.escapeHtml(repo.branch)})` : ''}${repo.mirror ? ' (mirror)' : ''}${repo.auto_sync_enabled ? '' : ' (paused)'}</div>
+                    ${repo.last_commit ? `<div class="repo-commit"><code>${this.escapeHtml(repo.last_commit.sha.slice(0, 7))}</code> ${this.escapeHtml(repo.last_commit.summary)} — ${this.escapeHtml(repo.last_commit.author)}, ${new Date(repo.last_commit.committed_at).toLocaleString()}</div>` : ''}
+                    ${repo.last_error ? `<div class="repo-error">${this.escapeHtml(repo.last_error)}</div>` : ''}
                     <div class="repo-meta">
                         <span class="repo-status status-${repo.status}">${repo.status}</span>
+                        ${repo.in_progress ? `• <span class="spinner"></span>${this.operationLabel(repo.operation)}...` : ''}
                         ${repo.last_synced ? `• Last synced: ${new Date(repo.last_synced).toLocaleString()}` : '• Never synced'}
+                        ${repo.size_bytes != null ? `• ${this.formatSize(repo.size_bytes)}` : ''}
+                        ${(repo.tags || []).map(tag => `<span class="repo-tag">${this.escapeHtml(tag)}</span>`).join('')}
                     </div>
                 </div>
                 <div class="repo-actions">
-                    <button class="btn btn-success btn-small sync-btn" data-url="${encodeURIComponent(repo.url)}">
+                    <button class="btn btn-success btn-small sync-btn" data-id="${repo.id}" ${repo.in_progress ? 'disabled' : ''}>
                         Sync
                     </button>
-                    <button class="btn btn-danger btn-small remove-btn" data-url="${encodeURIComponent(repo.url)}">
+                    <button class="btn btn-danger btn-small remove-btn" data-id="${repo.id}">
                         Remove
                     </button>
                 </div>
@@ -217,17 +254,15 @@ class GitCloner {
         // Sync buttons
         document.querySelectorAll('.sync-btn').forEach(btn => {
             btn.addEventListener('click', (e) => {
-                const url = e.target.dataset.url;
-                this.syncRepository(url, e.target);
+                this.syncRepository(e.target.dataset.id, e.target);
             });
         });
 
         // Remove buttons
         document.querySelectorAll('.remove-btn').forEach(btn => {
             btn.addEventListener('click', (e) => {
-                const url = e.target.dataset.url;
                 if (confirm('Are you sure you want to remove this repository?')) {
-                    this.removeRepository(url);
+                    this.removeRepository(e.target.dataset.id);
                 }
             });
         });
@@ -243,13 +278,13 @@ class GitCloner {
         });
     }
 
-    async syncRepository(encodedUrl, button) {
+    async syncRepository(id, button) {
         const originalText = button.innerHTML;
         button.innerHTML = '<span class="spinner"></span>Syncing...';
         button.disabled = true;
 
         try {
-            const response = await fetch(`/api/repositories/${encodedUrl}/sync`, {
+            const response = await fetch(`/api/repositories/${id}/sync`, {
                 method: 'POST',
                 headers: {
                     'Authorization': `Bearer ${this.token}`,
@@ -272,9 +307,9 @@ class GitCloner {
         }
     }
 
-    async removeRepository(encodedUrl) {
+    async removeRepository(id) {
         try {
-            const response = await fetch(`/api/repositories/${encodedUrl}`, {
+            const response = await fetch(`/api/repositories/${id}`, {
                 method: 'DELETE',
                 headers: {
                     'Authorization': `Bearer ${this.token}`,
@@ -325,6 +360,22 @@ class GitCloner {
         document.getElementById(alertId).classList.add('hidden');
     }
 
+    operationLabel(operation) {
+        const labels = { clone: 'Cloning', sync: 'Syncing', bundle: 'Applying bundle', audit: 'Auditing', change_url: 'Changing URL', sync_preview: 'Previewing sync', verify: 'Verifying', gc: 'Collecting garbage', remove: 'Removing', restore: 'Restoring', add_worktree: 'Adding worktree', remove_worktree: 'Removing worktree' };
+        return labels[operation] || 'Working';
+    }
+
+    formatSize(bytes) {
+        const units = ['B', 'KB', 'MB', 'GB', 'TB'];
+        let size = bytes;
+        let unit = 0;
+        while (size >= 1024 && unit < units.length - 1) {
+            size /= 1024;
+            unit++;
+        }
+        return `${size.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+    }
+
     escapeHtml(text) {
         const div = document.createElement('div');
         div.textContent = text;